@@ -0,0 +1,485 @@
+use anyhow::{bail, Result};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+use tokio::{
+    io::{
+        split, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, DuplexStream,
+        WriteHalf,
+    },
+    select,
+    sync::{mpsc, watch},
+    time,
+};
+
+use crate::{
+    client::{
+        health::PoolHealth, lines_unwrap, parse, pool_url::PoolUrl,
+        upstream::is_ok, write_to_socket,
+    },
+    coin::Coin,
+    protocol::{
+        ethjson::{
+            EthClientRootObject, EthClientWorkerObject, EthServerRoot,
+            EthServerRootObject, EthServerRootObjectJsonRpc,
+        },
+        stratum::{parse_server_message, StraumServerMessage},
+        CLIENT_GETWORK, CLIENT_LOGIN, CLIENT_SUBHASHRATE,
+    },
+    util::config::Settings,
+};
+
+// 没有矿机的聚合连接保留的时间
+const IDLE_SECS: u64 = 300;
+// 断线后重连的间隔
+const RECONNECT_SECS: u64 = 5;
+// 矿机端与聚合连接之间的缓冲
+const PIPE_SIZE: usize = 64 * 1024;
+// 聚合连接提交份额的 id 从这里开始, 避开登录和取任务的固定 id
+const SUBMIT_ID_START: u64 = 100000;
+// 矿池超过这个时间没有回复的份额按拒绝回给矿机
+const SUBMIT_TIMEOUT_SECS: u64 = 60;
+// 矿池连续拒绝登录几次后关闭聚合连接, 让矿机重新登录
+const LOGIN_RETRIES: u32 = 3;
+
+// 矿机经由聚合连接发给矿池的请求, 都带上矿机名
+#[derive(Debug)]
+enum Request {
+    // (矿机端 id, 份额, 矿机名, 提交结果的回传通道)
+    Submit(u64, Vec<String>, String, mpsc::UnboundedSender<(u64, bool)>),
+    Hashrate(Vec<String>, String),
+}
+
+// 一个聚合连接, 由多台矿机共用
+#[derive(Debug, Clone)]
+struct Upstream {
+    tx: mpsc::Sender<Request>,
+    jobs: watch::Receiver<Vec<String>>,
+    miners: Arc<AtomicUsize>,
+}
+
+// 使用聚合连接期间持有, 断开时减少连接上的矿机数
+struct MinerGuard(Arc<AtomicUsize>);
+
+impl Drop for MinerGuard {
+    fn drop(&mut self) { self.0.fetch_sub(1, Ordering::SeqCst); }
+}
+
+// 聚合模式: 同一个钱包的矿机共用少量矿池连接, 任务分发给所有矿机,
+// 提交时用 EthClientWorkerObject.worker 区分矿机。
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    sessions: Mutex<HashMap<String, Vec<Upstream>>>,
+}
+
+// 连接数没到上限时返回 None 表示新建, 否则选矿机最少的连接
+fn pick_session(sessions: &[Upstream], limit: usize) -> Option<usize> {
+    if sessions.len() < limit {
+        return None;
+    }
+    sessions
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, s)| s.miners.load(Ordering::SeqCst))
+        .map(|(idx, _)| idx)
+}
+
+impl Aggregator {
+    // 返回交给 handle_stream 的矿池端。矿池协议固定为 ETHProxy
+    pub fn connect(
+        self: &Arc<Self>, config: &Settings, health: Arc<PoolHealth>,
+    ) -> DuplexStream {
+        let (pool_end, miner_end) = tokio::io::duplex(PIPE_SIZE);
+        let aggregator = self.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                aggregator.pipe(miner_end, config, health).await
+            {
+                debug!("聚合连接的矿机端断开 {}", e);
+            }
+        });
+        pool_end
+    }
+
+    fn session(
+        &self, config: &Settings, wallet: &str, login: Vec<String>,
+        health: &Arc<PoolHealth>,
+    ) -> Result<(Upstream, MinerGuard)> {
+        let mut sessions = match self.sessions.lock() {
+            Ok(sessions) => sessions,
+            Err(_) => bail!("聚合连接状态错误"),
+        };
+        let list = sessions.entry(wallet.to_string()).or_default();
+        list.retain(|s| !s.tx.is_closed());
+
+        let upstream = match pick_session(list, config.aggregate as usize) {
+            Some(idx) => list[idx].clone(),
+            None => {
+                let (tx, rx) = mpsc::channel(256);
+                let (jobs_tx, jobs) = watch::channel(Vec::new());
+                let upstream = Upstream {
+                    tx,
+                    jobs,
+                    miners: Arc::new(AtomicUsize::new(0)),
+                };
+                info!("钱包 {} 新建聚合连接 #{}", wallet, list.len() + 1);
+                tokio::spawn(run_session(
+                    config.clone(),
+                    login,
+                    health.clone(),
+                    rx,
+                    jobs_tx,
+                    upstream.miners.clone(),
+                ));
+                list.push(upstream.clone());
+                upstream
+            }
+        };
+
+        upstream.miners.fetch_add(1, Ordering::SeqCst);
+        let guard = MinerGuard(upstream.miners.clone());
+        Ok((upstream, guard))
+    }
+
+    // 在矿机端扮演 ETHProxy 矿池: 登录和取任务在本地应答, 份额转给聚合连接
+    async fn pipe(
+        &self, stream: DuplexStream, config: Settings, health: Arc<PoolHealth>,
+    ) -> Result<()> {
+        let (r, mut w) = split(stream);
+        let mut lines = BufReader::new(r).lines();
+        let (res_tx, mut res_rx) = mpsc::unbounded_channel();
+        let mut upstream: Option<(Upstream, MinerGuard)> = None;
+        let mut worker_name = String::new();
+
+        loop {
+            select! {
+                res = lines.next_line() => {
+                    let buffer = lines_unwrap(res,&worker_name,"矿机").await?;
+                    let rpc = match parse(buffer.as_bytes()) {
+                        Some(rpc) => rpc,
+                        None => continue,
+                    };
+                    let params = rpc.get_params();
+                    match rpc.get_method().as_str() {
+                        "eth_submitLogin" => {
+                            let wallet = params.first().cloned().unwrap_or_default();
+                            worker_name = format!("{}.{}", wallet, rpc.get_worker_name());
+                            let ok = match self.session(&config,&wallet,params,&health) {
+                                Ok(session) => {
+                                    upstream = Some(session);
+                                    true
+                                },
+                                Err(e) => {
+                                    tracing::warn!("矿工: {} 无法使用聚合连接 {}",worker_name,e);
+                                    false
+                                },
+                            };
+                            let login = EthServerRoot { id: rpc.get_id(), jsonrpc: "2.0".into(), result: ok };
+                            write_to_socket(&mut w,&login,&worker_name).await?;
+                        },
+                        "eth_getWork" => {
+                            if let Some((upstream, _)) = &upstream {
+                                let job = upstream.jobs.borrow().clone();
+                                if !job.is_empty() {
+                                    let job = EthServerRootObjectJsonRpc { id: rpc.get_id(), jsonrpc: "2.0".into(), result: job };
+                                    write_to_socket(&mut w,&job,&worker_name).await?;
+                                }
+                            }
+                        },
+                        "eth_submitWork" => {
+                            if let Some((upstream, _)) = &upstream {
+                                let submit = Request::Submit(rpc.get_id(), params, rpc.get_worker_name(), res_tx.clone());
+                                if upstream.tx.send(submit).await.is_err() {
+                                    bail!("聚合连接已关闭");
+                                }
+                            }
+                        },
+                        "eth_submitHashrate" => {
+                            if let Some((upstream, _)) = &upstream {
+                                let _ = upstream.tx.try_send(Request::Hashrate(params, rpc.get_worker_name()));
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+                Some((id, ok)) = res_rx.recv() => {
+                    let res = EthServerRoot { id, jsonrpc: "2.0".into(), result: ok };
+                    write_to_socket(&mut w,&res,&worker_name).await?;
+                },
+                res = async { upstream.as_mut().unwrap().0.jobs.changed().await }, if upstream.is_some() => {
+                    if res.is_err() {
+                        bail!("聚合连接已关闭");
+                    }
+                    let job = upstream.as_ref().unwrap().0.jobs.borrow().clone();
+                    let job = EthServerRootObjectJsonRpc { id: 0, jsonrpc: "2.0".into(), result: job };
+                    write_to_socket(&mut w,&job,&worker_name).await?;
+                },
+            }
+        }
+    }
+}
+
+// 矿池地址中设置了钱包、矿工名或密码时替换掉, 返回 (登录参数, 矿工名)
+fn pool_login(
+    url: Option<&PoolUrl>, login: &[String], rig: &str,
+) -> (Vec<String>, String) {
+    let url = match url {
+        Some(url) => url,
+        None => return (login.to_vec(), rig.to_string()),
+    };
+    let wallet = login.first().map(String::as_str).unwrap_or_default();
+    let password = login.get(1).map(String::as_str).unwrap_or_default();
+    let (wallet, rig, password) = url.login(wallet, rig, password);
+    let mut login = vec![wallet];
+    if !password.is_empty() {
+        login.push(password);
+    }
+    (login, rig)
+}
+
+// 提交 id -> (提交时间, 矿机端 id, 回传通道)
+type Pending = HashMap<u64, (Instant, u64, mpsc::UnboundedSender<(u64, bool)>)>;
+
+// 没有得到结果的份额按拒绝回传给矿机
+fn drop_pending(pending: &mut Pending, older_than: Duration) {
+    pending.retain(|_, (sent, id, res_tx)| {
+        if sent.elapsed() < older_than {
+            return true;
+        }
+        let _ = res_tx.send((*id, false));
+        false
+    });
+}
+
+// 会话正常结束的原因
+#[derive(Debug, PartialEq)]
+enum End {
+    Idle,
+    LoginFailed(String),
+}
+
+// 聚合连接, 断线或切换矿池后自动重连。
+// 长时间没有矿机或矿池一直拒绝登录时退出
+async fn run_session(
+    config: Settings, login: Vec<String>, health: Arc<PoolHealth>,
+    mut rx: mpsc::Receiver<Request>, jobs: watch::Sender<Vec<String>>,
+    miners: Arc<AtomicUsize>,
+) {
+    let coin = match config.coin() {
+        Ok(coin) => coin,
+        Err(_) => return,
+    };
+    let name = format!(
+        "{}.{}",
+        login.first().cloned().unwrap_or_default(),
+        config.name
+    );
+
+    let mut pending = Pending::new();
+    let mut login_failures = 0;
+    loop {
+        let res = match health.connect().await {
+            Ok((idx, stream)) => {
+                let mut switch = health.subscribe();
+                let (login, rig) =
+                    pool_login(health.url(idx), &login, &config.name);
+                session(
+                    stream,
+                    &login,
+                    &rig,
+                    &name,
+                    coin,
+                    &mut rx,
+                    &jobs,
+                    &miners,
+                    &mut switch,
+                    &mut pending,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        // 断开后矿池不会再回复之前的份额
+        drop_pending(&mut pending, Duration::ZERO);
+
+        match res {
+            Ok(End::Idle) => {
+                info!("聚合连接 {} 没有矿机, 断开", name);
+                return;
+            }
+            Ok(End::LoginFailed(reply)) => {
+                login_failures += 1;
+                tracing::warn!("聚合连接 {} 矿池登录失败 {}", name, reply);
+                if login_failures >= LOGIN_RETRIES {
+                    tracing::error!(
+                        "聚合连接 {} 连续 {} 次登录失败, 断开所有矿机",
+                        name,
+                        login_failures
+                    );
+                    return;
+                }
+            }
+            Err(e) => {
+                login_failures = 0;
+                tracing::warn!("聚合连接 {} 断开 {}", name, e)
+            }
+        }
+        time::sleep(Duration::from_secs(RECONNECT_SECS)).await;
+    }
+}
+
+// 返回 Ok 表示空闲退出或登录失败, Err 表示连接断开需要重连
+#[allow(clippy::too_many_arguments)]
+async fn session<S>(
+    stream: S, login: &[String], rig: &str, name: &String,
+    coin: &'static dyn Coin, rx: &mut mpsc::Receiver<Request>,
+    jobs: &watch::Sender<Vec<String>>, miners: &AtomicUsize,
+    switch: &mut watch::Receiver<usize>, pending: &mut Pending,
+) -> Result<End>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (r, mut w) = split(stream);
+    let mut lines = BufReader::new(r).lines();
+
+    let login = EthClientWorkerObject {
+        id: CLIENT_LOGIN,
+        method: "eth_submitLogin".into(),
+        params: login.to_vec(),
+        worker: rig.to_string(),
+    };
+    write_to_socket(&mut w, &login, name).await?;
+    get_work(&mut w, name).await?;
+
+    let mut submit_id = SUBMIT_ID_START;
+    let mut idle_since: Option<Instant> = None;
+    let mut getwork = time::interval(Duration::from_secs(2));
+
+    loop {
+        select! {
+            res = lines.next_line() => {
+                let buffer = lines_unwrap(res,name,"矿池").await?;
+                if let Ok(job) = serde_json::from_str::<EthServerRootObject>(&buffer) {
+                    if job.result.len() >= 3 {
+                        match coin.parse_job(&job.result) {
+                            Ok(job) => {
+                                if *jobs.borrow() != job {
+                                    let _ = jobs.send(job);
+                                }
+                            },
+                            Err(e) => tracing::warn!("矿池任务格式错误: {}", e),
+                        }
+                        continue;
+                    }
+                }
+
+                if let StraumServerMessage::Result(result) = parse_server_message(&buffer) {
+                    let ok = is_ok(&result.result, &result.error);
+                    if result.id == CLIENT_LOGIN {
+                        if !ok {
+                            return Ok(End::LoginFailed(buffer));
+                        }
+                    } else if let Some((_, id, res_tx)) = pending.remove(&result.id) {
+                        let _ = res_tx.send((id, ok));
+                    }
+                }
+            },
+            Some(request) = rx.recv() => match request {
+                Request::Submit(id, params, worker, res_tx) => {
+                    submit_id += 1;
+                    pending.insert(submit_id, (Instant::now(), id, res_tx));
+                    let submit = EthClientWorkerObject {
+                        id: submit_id,
+                        method: "eth_submitWork".into(),
+                        params,
+                        worker,
+                    };
+                    write_to_socket(&mut w, &submit, name).await?;
+                },
+                Request::Hashrate(params, worker) => {
+                    let hashrate = EthClientWorkerObject {
+                        id: CLIENT_SUBHASHRATE,
+                        method: "eth_submitHashrate".into(),
+                        params,
+                        worker,
+                    };
+                    write_to_socket(&mut w, &hashrate, name).await?;
+                },
+            },
+            _ = switch.changed() => {
+                bail!("切换矿池");
+            },
+            _ = getwork.tick() => {
+                if miners.load(Ordering::SeqCst) == 0 {
+                    let since = *idle_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= Duration::from_secs(IDLE_SECS) {
+                        return Ok(End::Idle);
+                    }
+                } else {
+                    idle_since = None;
+                }
+                // 矿池一直不回复的份额不再等待
+                drop_pending(pending, Duration::from_secs(SUBMIT_TIMEOUT_SECS));
+                get_work(&mut w, name).await?;
+            },
+        }
+    }
+}
+
+async fn get_work<W>(w: &mut WriteHalf<W>, name: &String) -> Result<()>
+where
+    W: AsyncWrite,
+{
+    let get_work = EthClientRootObject {
+        id: CLIENT_GETWORK,
+        method: "eth_getWork".into(),
+        params: vec![],
+    };
+    write_to_socket(w, &get_work, name).await
+}
+
+#[test]
+fn test_pick_session() {
+    let upstream = |miners| {
+        let (tx, _) = mpsc::channel(1);
+        let (_, jobs) = watch::channel(Vec::new());
+        Upstream {
+            tx,
+            jobs,
+            miners: Arc::new(AtomicUsize::new(miners)),
+        }
+    };
+
+    let sessions = vec![upstream(3), upstream(1)];
+    assert_eq!(pick_session(&sessions, 3), None);
+    assert_eq!(pick_session(&sessions, 2), Some(1));
+    assert_eq!(pick_session(&[], 1), None);
+}
+
+#[test]
+fn test_drop_pending() {
+    let (res_tx, mut res_rx) = mpsc::unbounded_channel();
+    let old = Instant::now() - Duration::from_secs(SUBMIT_TIMEOUT_SECS + 1);
+    let mut pending = Pending::new();
+    pending.insert(SUBMIT_ID_START + 1, (old, 7, res_tx.clone()));
+    pending.insert(SUBMIT_ID_START + 2, (Instant::now(), 8, res_tx));
+
+    // 超时的份额按拒绝回给矿机
+    drop_pending(&mut pending, Duration::from_secs(SUBMIT_TIMEOUT_SECS));
+    assert_eq!(res_rx.try_recv().ok(), Some((7, false)));
+    assert!(res_rx.try_recv().is_err());
+    assert_eq!(pending.len(), 1);
+
+    // 连接断开时剩下的都回复
+    drop_pending(&mut pending, Duration::ZERO);
+    assert_eq!(res_rx.try_recv().ok(), Some((8, false)));
+    assert!(pending.is_empty());
+}
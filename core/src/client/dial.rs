@@ -0,0 +1,191 @@
+use anyhow::{bail, Result};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+use tokio::{net::TcpStream, task::JoinSet, time};
+
+use crate::client::via::{split_via, Via};
+
+// 未配置时的连接超时
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+// 未配置时重新解析域名的间隔
+const DNS_REFRESH_SECS: u64 = 60;
+// 前一个地址还没连上时, 隔多久开始尝试下一个地址
+const ATTEMPT_DELAY_MS: u64 = 250;
+
+static CONNECT_TIMEOUT: AtomicU64 = AtomicU64::new(CONNECT_TIMEOUT_SECS);
+static DNS_REFRESH: AtomicU64 = AtomicU64::new(DNS_REFRESH_SECS);
+
+lazy_static! {
+    // 域名 -> (解析时间, 地址)
+    static ref DNS_CACHE: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>> =
+        Mutex::new(HashMap::new());
+}
+
+// 启动时按配置设置, 0 表示使用默认值
+pub fn set_options(connect_timeout: u64, dns_refresh: u64) {
+    let or_default = |v, default| if v == 0 { default } else { v };
+    CONNECT_TIMEOUT.store(
+        or_default(connect_timeout, CONNECT_TIMEOUT_SECS),
+        Ordering::Relaxed,
+    );
+    DNS_REFRESH
+        .store(or_default(dns_refresh, DNS_REFRESH_SECS), Ordering::Relaxed);
+}
+
+pub fn connect_timeout() -> Duration {
+    Duration::from_secs(CONNECT_TIMEOUT.load(Ordering::Relaxed))
+}
+
+// host:port 中的主机名, IPv6 地址去掉方括号
+pub fn host_of(address: &str) -> &str {
+    let (address, _) = split_via(address);
+    let host = match address.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// IPv6 与 IPv4 地址交替排列, 保持解析结果中第一个地址的协议优先
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(|a| a.is_ipv6()).unwrap_or(false);
+    let (mut first, mut second): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut res = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => break,
+            (a, b) => res.extend(a.into_iter().chain(b)),
+        }
+    }
+    res
+}
+
+// 解析矿池地址, 缓存到期后重新解析。解析失败时继续使用上次的结果
+pub async fn resolve(address: &str) -> Result<Vec<SocketAddr>> {
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+
+    let refresh = Duration::from_secs(DNS_REFRESH.load(Ordering::Relaxed));
+    let cached = DNS_CACHE.lock().ok().and_then(|c| c.get(address).cloned());
+    if let Some((time, addrs)) = &cached {
+        if time.elapsed() < refresh {
+            return Ok(addrs.clone());
+        }
+    }
+
+    match time::timeout(connect_timeout(), tokio::net::lookup_host(address))
+        .await
+    {
+        Ok(Ok(addrs)) => {
+            let addrs = interleave(addrs.collect());
+            if addrs.is_empty() {
+                bail!("矿池地址解析结果为空 {}", address);
+            }
+            if let Some((_, old)) = &cached {
+                if *old != addrs {
+                    debug!("矿池地址 {} 解析结果变化 {:?}", address, addrs);
+                }
+            }
+            if let Ok(mut cache) = DNS_CACHE.lock() {
+                cache.insert(
+                    address.to_string(),
+                    (Instant::now(), addrs.clone()),
+                );
+            }
+            Ok(addrs)
+        }
+        res => match cached {
+            Some((_, addrs)) => {
+                warn!("矿池地址 {} 重新解析失败, 使用上次的结果", address);
+                Ok(addrs)
+            }
+            None => match res {
+                Ok(Err(e)) => bail!("矿池地址解析失败 {} {}", address, e),
+                _ => bail!("矿池地址解析超时 {}", address),
+            },
+        },
+    }
+}
+
+// 连接矿池, 地址带 ?via= 时经过代理连接
+pub async fn connect(address: &str) -> Result<(TcpStream, SocketAddr)> {
+    let (target, via) = split_via(address);
+    let via = match via {
+        Some(via) => Via::parse(via)?,
+        None => return connect_direct(target).await,
+    };
+
+    let (mut stream, addr) = connect_direct(&via.address).await?;
+    match time::timeout(connect_timeout(), via.handshake(&mut stream, target))
+        .await
+    {
+        Ok(Ok(())) => Ok((stream, addr)),
+        Ok(Err(e)) => Err(e),
+        Err(_) => bail!("代理 {} 连接矿池 {} 超时", via.address, target),
+    }
+}
+
+// 同时尝试解析到的所有地址, 每隔 ATTEMPT_DELAY_MS 多发起一个,
+// 第一个连上的返回, 其余的取消
+async fn connect_direct(address: &str) -> Result<(TcpStream, SocketAddr)> {
+    let addrs = resolve(address).await?;
+
+    let attempts = async {
+        let mut set = JoinSet::new();
+        for (i, addr) in addrs.iter().copied().enumerate() {
+            let delay = Duration::from_millis(ATTEMPT_DELAY_MS * i as u64);
+            set.spawn(async move {
+                time::sleep(delay).await;
+                (addr, TcpStream::connect(addr).await)
+            });
+        }
+
+        while let Some(res) = set.join_next().await {
+            match res {
+                Ok((addr, Ok(stream))) => return Some((stream, addr)),
+                Ok((addr, Err(e))) => {
+                    debug!("{} {} 连接失败 {}", address, addr, e)
+                }
+                Err(_) => {}
+            }
+        }
+        None
+    };
+
+    match time::timeout(connect_timeout(), attempts).await {
+        Ok(Some((stream, addr))) => {
+            stream.set_nodelay(true)?;
+            Ok((stream, addr))
+        }
+        Ok(None) => bail!("矿池 {} 访问不通", address),
+        Err(_) => bail!("矿池 {} 连接超时", address),
+    }
+}
+
+#[test]
+fn test_interleave_and_host() {
+    let addrs: Vec<SocketAddr> = vec![
+        "[2001:db8::1]:4444".parse().unwrap(),
+        "[2001:db8::2]:4444".parse().unwrap(),
+        "1.1.1.1:4444".parse().unwrap(),
+    ];
+    let res = interleave(addrs.clone());
+    assert_eq!(res, vec![addrs[0], addrs[2], addrs[1]]);
+
+    assert_eq!(host_of("[2001:db8::1]:4444"), "2001:db8::1");
+    assert_eq!(host_of("eth.f2pool.com:6688"), "eth.f2pool.com");
+    assert_eq!(host_of("pool.com:443?via=http://proxy:8080"), "pool.com");
+}
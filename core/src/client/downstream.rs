@@ -0,0 +1,328 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::VecDeque;
+
+use tokio::io::{AsyncWrite, WriteHalf};
+
+use crate::{
+    client::write_to_socket,
+    coin::Coin,
+    protocol::{
+        eth_stratum::{
+            self, full_nonce, new_set_difficulty, new_set_extranonce,
+            new_subscribe_result,
+        },
+        ethjson::{
+            EthClientObject, EthRpcError, EthServerError, EthServerRoot,
+            EthServerRootObjectJsonRpc,
+        },
+        stratum::{self, submit_to_work, StraumResultBool},
+        PROTOCOL,
+    },
+};
+
+// 保留的最近任务数, 用于按 header 找回 seed
+const MINER_JOBS: usize = 16;
+
+// 本地拒绝份额时返回给矿机的错误
+pub const ERR_INVALID: (i64, &str) = (20, "Invalid share");
+pub const ERR_STALE: (i64, &str) = (21, "Stale share");
+pub const ERR_BAD_MIX: (i64, &str) = (22, "Invalid mix digest");
+pub const ERR_LOW_DIFFICULTY: (i64, &str) = (23, "Low difficulty share");
+
+// 矿机发来的请求, 已经换算成代理内部统一的格式
+#[derive(Debug, Clone, PartialEq)]
+pub enum MinerRequest {
+    Subscribe,
+    ExtranonceSubscribe,
+    // (钱包, 矿工名, 密码)
+    Login(String, String, String),
+    // [nonce, header, mix], EthereumStratum 矿机不提交 mix
+    Submit(Vec<String>),
+    Hashrate,
+    GetWork,
+    Unknown,
+}
+
+// 与矿机之间的会话, 按矿机自己使用的协议收发
+#[derive(Debug, Clone)]
+pub struct MinerSession {
+    pub dialect: PROTOCOL,
+    pub extranonce: String,
+    pub subscribe_id: u64,
+    pub authorize_id: u64,
+    pub extranonce_subscribed: bool,
+    // 等矿池分配 extranonce 之后再回复矿机的订阅
+    pub pending_subscribe: bool,
+    coin: &'static dyn Coin,
+    miner_diff: f64,
+    jobs: VecDeque<Vec<String>>,
+}
+
+impl MinerSession {
+    pub fn new(coin: &'static dyn Coin) -> Self {
+        Self {
+            dialect: PROTOCOL::KNOWN,
+            extranonce: String::new(),
+            subscribe_id: 0,
+            authorize_id: 0,
+            extranonce_subscribed: false,
+            pending_subscribe: false,
+            coin,
+            miner_diff: 0.0,
+            jobs: VecDeque::new(),
+        }
+    }
+
+    // 按矿机的第一条消息判断协议
+    pub fn detect(rpc: &(dyn EthClientObject + Send + Sync)) -> PROTOCOL {
+        if rpc.get_method() != "mining.subscribe" {
+            PROTOCOL::ETH
+        } else if rpc.is_protocol_eth_statum() {
+            PROTOCOL::NICEHASHSTRATUM
+        } else {
+            PROTOCOL::STRATUM
+        }
+    }
+
+    pub fn parse(
+        &self, rpc: &(dyn EthClientObject + Send + Sync),
+    ) -> MinerRequest {
+        let params = rpc.get_params();
+        match rpc.get_method().as_str() {
+            "mining.subscribe" => MinerRequest::Subscribe,
+            "mining.extranonce.subscribe" => MinerRequest::ExtranonceSubscribe,
+            "eth_submitLogin" | "mining.authorize" => {
+                let account = match params.first() {
+                    Some(account) => account.clone(),
+                    None => return MinerRequest::Unknown,
+                };
+                let (wallet, worker) = match account.split_once('.') {
+                    Some((wallet, worker)) => {
+                        (wallet.to_string(), worker.to_string())
+                    }
+                    None => (account.clone(), rpc.get_worker_name()),
+                };
+                let password = params.get(1).cloned().unwrap_or_default();
+                MinerRequest::Login(wallet, worker, password)
+            }
+            "eth_submitWork" if params.len() >= 3 => {
+                MinerRequest::Submit(params[..3].to_vec())
+            }
+            "mining.submit" => match self.dialect {
+                PROTOCOL::NICEHASHSTRATUM if params.len() >= 3 => {
+                    MinerRequest::Submit(vec![
+                        full_nonce(&self.extranonce, &params[2]),
+                        format!("0x{}", params[1].trim_start_matches("0x")),
+                    ])
+                }
+                PROTOCOL::STRATUM => match submit_to_work(&params) {
+                    Some(work) => MinerRequest::Submit(work),
+                    None => MinerRequest::Unknown,
+                },
+                _ => MinerRequest::Unknown,
+            },
+            "eth_submitHashrate" => MinerRequest::Hashrate,
+            "eth_getWork" => MinerRequest::GetWork,
+            _ => MinerRequest::Unknown,
+        }
+    }
+
+    pub fn find_job(&self, header: &str) -> Option<&Vec<String>> {
+        self.jobs.iter().rev().find(|j| j[0] == header)
+    }
+
+    // 记录下发的任务, 返回是否需要矿机丢弃旧任务 (seed 或高度变化)
+    fn push_job(&mut self, job: Vec<String>) -> bool {
+        let clean = match self.jobs.back() {
+            Some(last) => last.get(1) != job.get(1) || last.get(3) != job.get(3),
+            None => true,
+        };
+        if self.jobs.len() >= MINER_JOBS {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back(job);
+        clean
+    }
+
+    async fn write_job<W>(
+        &mut self, w: &mut WriteHalf<W>, job: &[String], clean: bool,
+        worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        match self.dialect {
+            PROTOCOL::STRATUM => {
+                if let Some(notify) = stratum::job_to_notify(job, self.coin) {
+                    write_to_socket(w, &notify, worker_name).await?;
+                }
+            }
+            PROTOCOL::NICEHASHSTRATUM => {
+                if let Some(target) = job.get(2) {
+                    let diff = self.coin.share_difficulty(target);
+                    if diff != self.miner_diff {
+                        self.miner_diff = diff;
+                        write_to_socket(w, &new_set_difficulty(diff), worker_name)
+                            .await?;
+                    }
+                }
+                if let Some(notify) = eth_stratum::job_to_notify(job, clean) {
+                    write_to_socket(w, &notify, worker_name).await?;
+                }
+            }
+            _ => {
+                let job_rpc = EthServerRootObjectJsonRpc {
+                    id: 0,
+                    jsonrpc: "2.0".into(),
+                    result: job.to_vec(),
+                };
+                write_to_socket(w, &job_rpc, worker_name).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // 下发任务, clean 为 true 时要求矿机立即切换
+    pub async fn send_job<W>(
+        &mut self, w: &mut WriteHalf<W>, job: Vec<String>, clean: bool,
+        worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        let clean = self.push_job(job.clone()) || clean;
+        #[cfg(debug_assertions)]
+        tracing::debug!("{} 发送任务 #{:?}", worker_name, job);
+        self.write_job(w, &job, clean, worker_name).await
+    }
+
+    // 任务没有变化时重发最后一个任务 (ETHProxy 矿机的 eth_getWork)
+    pub async fn resend_job<W>(
+        &mut self, w: &mut WriteHalf<W>, worker_name: &String,
+    ) -> Result<()>
+    where W: AsyncWrite {
+        if let Some(job) = self.jobs.back().cloned() {
+            self.write_job(w, &job, false, worker_name).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn reply<W>(
+        &self, w: &mut WriteHalf<W>, id: u64, result: bool,
+        worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        if self.dialect == PROTOCOL::ETH {
+            let rpc = EthServerRoot {
+                id,
+                jsonrpc: "2.0".into(),
+                result,
+            };
+            write_to_socket(w, &rpc, worker_name).await
+        } else {
+            let rpc = StraumResultBool {
+                id,
+                result,
+                error: Value::Null,
+            };
+            write_to_socket(w, &rpc, worker_name).await
+        }
+    }
+
+    pub async fn reply_error<W>(
+        &self, w: &mut WriteHalf<W>, id: u64, error: (i64, &str),
+        worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        let (code, message) = error;
+        if self.dialect == PROTOCOL::ETH {
+            let rpc = EthServerError {
+                id,
+                jsonrpc: "2.0".into(),
+                result: false,
+                error: EthRpcError {
+                    code,
+                    message: message.into(),
+                },
+            };
+            write_to_socket(w, &rpc, worker_name).await
+        } else {
+            // Stratum 的错误格式 [code, message, traceback]
+            let rpc = StraumResultBool {
+                id,
+                result: false,
+                error: Value::Array(vec![
+                    code.into(),
+                    message.into(),
+                    Value::Null,
+                ]),
+            };
+            write_to_socket(w, &rpc, worker_name).await
+        }
+    }
+
+    pub async fn reply_subscribe<W>(
+        &mut self, w: &mut WriteHalf<W>, session_id: &str, extranonce: &str,
+        worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        if self.dialect == PROTOCOL::NICEHASHSTRATUM {
+            self.extranonce = extranonce.to_string();
+            let rpc =
+                new_subscribe_result(self.subscribe_id, session_id, extranonce);
+            write_to_socket(w, &rpc, worker_name).await
+        } else {
+            self.reply(w, self.subscribe_id, true, worker_name).await
+        }
+    }
+
+    pub async fn set_extranonce<W>(
+        &mut self, w: &mut WriteHalf<W>, extranonce: &str,
+        worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        self.extranonce = extranonce.to_string();
+        if self.extranonce_subscribed {
+            write_to_socket(w, &new_set_extranonce(extranonce), worker_name)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_nicehash_submit() {
+    use crate::protocol::ethjson::EthClientRootObject;
+
+    let mut miner = MinerSession::new(&crate::coin::ETH);
+    miner.dialect = PROTOCOL::NICEHASHSTRATUM;
+    miner.extranonce = "a1b2".into();
+    let rpc = EthClientRootObject {
+        id: 4,
+        method: "mining.submit".into(),
+        params: vec!["wallet.rig".into(), "aa".into(), "0011223344aa".into()],
+    };
+    assert_eq!(
+        miner.parse(&rpc),
+        MinerRequest::Submit(vec!["0xa1b20011223344aa".into(), "0xaa".into()])
+    );
+
+    let rpc = EthClientRootObject {
+        id: 1,
+        method: "mining.authorize".into(),
+        params: vec!["wallet.rig".into(), "x".into()],
+    };
+    assert_eq!(
+        miner.parse(&rpc),
+        MinerRequest::Login("wallet".into(), "rig".into(), "x".into())
+    );
+}
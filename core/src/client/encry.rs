@@ -1,12 +1,17 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use std::time::Duration;
 use tokio::{
-    io::{split, BufReader},
+    io::{split, AsyncWriteExt, BufReader, DuplexStream},
     net::{TcpListener, TcpStream},
-    sync::RwLockReadGuard,
+    sync::{mpsc::UnboundedSender, RwLockReadGuard},
 };
 use tracing::info;
 
-use crate::{state::Worker, util::config::Settings};
+use crate::{
+    client::{mux, traffic::Counted, tunnel},
+    state::Worker,
+    util::config::Settings,
+};
 
 use super::*;
 pub async fn accept_en_tcp(proxy: Arc<Proxy>) -> Result<()> {
@@ -39,59 +44,118 @@ pub async fn accept_en_tcp(proxy: Arc<Proxy>) -> Result<()> {
             // 矿工状态管理
             let mut worker: Worker = Worker::default();
             let worker_tx = p.worker_tx.clone();
-            match transfer(p, &mut worker, stream).await {
-                Ok(_) => {
-                    if worker.is_online() {
-                        worker.offline();
-                        info!("IP: {} 安全下线", addr);
-                        worker_tx.send(worker).unwrap();
-                    } else {
-                        info!("IP: {} 下线", addr);
-                    }
-                }
-                Err(e) => {
-                    if worker.is_online() {
-                        worker.offline();
-                        worker_tx.send(worker).unwrap();
-                        info!("IP: {} 下线原因 {}", addr, e);
-                    } else {
-                        debug!("IP: {} 恶意链接断开: {}", addr, e);
-                    }
-                }
-            }
+            let res = transfer(p, &mut worker, stream).await;
+            offline(worker, &worker_tx, &addr.to_string(), res);
         });
     }
 }
 
+// 连接结束后把下线的矿工交给统计
+fn offline(
+    mut worker: Worker, worker_tx: &UnboundedSender<Worker>, addr: &str,
+    res: Result<()>,
+) {
+    match res {
+        Ok(_) => {
+            if worker.is_online() {
+                worker.offline();
+                info!("IP: {} 安全下线", addr);
+                worker_tx.send(worker).unwrap();
+            } else {
+                info!("IP: {} 下线", addr);
+            }
+        }
+        Err(e) => {
+            if worker.is_online() {
+                worker.offline();
+                worker_tx.send(worker).unwrap();
+                info!("IP: {} 下线原因 {}", addr, e);
+            } else {
+                debug!("IP: {} 恶意链接断开: {}", addr, e);
+            }
+        }
+    }
+}
+
+// 多路复用隧道中的一台矿机
+async fn serve_worker(proxy: Arc<Proxy>, addr: String, stream: DuplexStream) {
+    let mut worker: Worker = Worker::default();
+    let worker_tx = proxy.worker_tx.clone();
+    let (worker_r, worker_w) = split(stream);
+    let worker_r = BufReader::new(worker_r);
+    let res = handle_tcp_random(&mut worker, worker_r, worker_w, proxy).await;
+    offline(worker, &worker_tx, &addr, res);
+}
+
 async fn transfer(
     proxy: Arc<Proxy>, worker: &mut Worker, tcp_stream: TcpStream,
 ) -> Result<()> {
-    let (worker_r, worker_w) = split(tcp_stream);
-    let worker_r = BufReader::new(worker_r);
+    let (psk, legacy) = {
+        let config = proxy.config.read().await;
+        (config.encrypt_psk.clone(), config.encrypt_legacy)
+    };
 
-    let mut pool_address: Vec<String> = Vec::new();
-    {
-        let config = RwLockReadGuard::map(proxy.config.read().await, |s| s);
-        pool_address = config.pool_address.to_vec();
+    // 隧道连接以 MAGIC 开头, 旧协议直接发送 JSON
+    let timeout = Duration::from_secs(tunnel::HANDSHAKE_SECS);
+    let mut first = [0u8; 1];
+    match tokio::time::timeout(timeout, tcp_stream.peek(&mut first)).await {
+        Ok(Ok(1)) => {}
+        Ok(Ok(_)) => bail!("连接已关闭"),
+        Ok(Err(e)) => return Err(e.into()),
+        Err(_) => bail!("等待数据超时"),
     }
+    let mut tcp_stream = Counted::new(tcp_stream, proxy.traffic.clone());
 
-    let (stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(&pool_address) {
-            Ok(pool) => pool,
-            Err(_) => {
-                bail!("未匹配到矿池 或 均不可链接。请修改后重试");
-            }
-        };
+    if first[0] != tunnel::MAGIC[0] {
+        if !psk.is_empty() && !legacy {
+            bail!("未使用加密隧道, 拒绝旧协议连接");
+        }
+        let (worker_r, worker_w) = split(tcp_stream);
+        let worker_r = BufReader::new(worker_r);
+        return handle_tcp_random(worker, worker_r, worker_w, proxy).await;
+    }
+
+    if psk.is_empty() {
+        bail!("未设置预共享密钥, 拒绝加密隧道连接");
+    }
+    let (send, mut recv) = match tokio::time::timeout(
+        timeout,
+        tunnel::accept(&mut tcp_stream, &psk),
+    )
+    .await
+    {
+        Ok(res) => res?,
+        Err(_) => bail!("隧道握手超时"),
+    };
 
-    handle_tcp_random(
-        worker,
-        worker_r,
-        worker_w,
-        &pools,
-        proxy,
-        stream_type,
-        true,
+    // monitor 的第一帧: 多路复用的握手, 或者单台矿机的数据
+    let first = match tokio::time::timeout(
+        timeout,
+        tunnel::read_frame(&mut tcp_stream, &mut recv),
     )
     .await
-    //handle_tcp_random(worker, worker_r, worker_w, &pools, proxy, true).await
+    {
+        Ok(Ok(Some(first))) => first,
+        Ok(Ok(None)) => bail!("连接已关闭"),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => bail!("等待数据超时"),
+    };
+    if first.starts_with(mux::MAGIC) {
+        let on_open: mux::OnOpen = Arc::new(move |addr, stream| {
+            tokio::spawn(serve_worker(proxy.clone(), addr, stream));
+        });
+        return mux::accept(tcp_stream, send, recv, &first, on_open).await;
+    }
+
+    // 解密后的数据经内存管道交给矿机处理流程
+    let (local, mut remote) = tokio::io::duplex(64 * 1024);
+    remote.write_all(&first).await?;
+    tokio::spawn(async move {
+        if let Err(e) = tunnel::relay(remote, tcp_stream, send, recv).await {
+            debug!("加密隧道断开 {}", e);
+        }
+    });
+    let (worker_r, worker_w) = split(local);
+    let worker_r = BufReader::new(worker_r);
+    handle_tcp_random(worker, worker_r, worker_w, proxy).await
 }
@@ -1,31 +1,37 @@
-use std::sync::{Arc, RwLockReadGuard};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
 use tokio::{
-    io::{AsyncRead, AsyncWrite, BufReader, Lines, WriteHalf},
+    io::{BufReader, Lines, ReadHalf, WriteHalf},
     select,
     sync::mpsc::Receiver,
-    sync::RwLockWriteGuard,
+    time::{self, Duration, Instant},
 };
 
 use crate::{
     protocol::ethjson::EthClientObject,
-    proxy::{Job, Proxy},
-    util::config::Settings,
+    proxy::{FeeShare, Job, Proxy},
+    util::config::{FeeDestination, Settings},
 };
 
 use crate::{
-    client::lines_unwrap,
-    protocol::ethjson::{
-        EthClientRootObject, EthClientWorkerObject, EthServer,
-        EthServerRootObject,
+    client::{lines_unwrap, upstream::is_ok},
+    protocol::{
+        ethjson::{
+            EthClientRootObject, EthClientWorkerObject, EthServerRootObject,
+        },
+        stratum::{parse_server_message, StraumServerMessage},
     },
 };
 
-use super::write_to_socket_byte;
+use super::{proxy_pool_login, write_to_socket_byte, PoolStream};
 
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
 // 已禁用：开发者抽水SSL函数（原连接到开发者矿池进行抽水）
 /*
@@ -115,265 +121,323 @@ pub async fn develop_fee_ssl(
 }
 */
 
-pub async fn fee_ssl(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<
-            tokio::io::ReadHalf<
-                tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-            >,
-        >,
-    >,
-    mut w: tokio::io::WriteHalf<
-        tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-    >,
-    worker_name: String, proxy: Arc<Proxy>,
-) -> Result<()> {
-    let mut config: Settings;
-    {
-        let rconfig = proxy.config.read().await;
-        config = rconfig.clone();
-    }
-    let mut get_work = EthClientRootObject {
-        id: 6,
-        method: "eth_getWork".into(),
-        params: vec![],
-    };
+// 抽水矿池断线后重连的最长间隔
+const FEE_RETRY_MAX_SECS: u64 = 60;
+// 任务队列只保留最新的几个任务
+const FEE_JOB_LIMIT: usize = 16;
+// 这么久没有新任务时认为抽水矿池异常, 重新连接
+const FEE_JOB_STALE_SECS: u64 = 120;
+const GETWORK_SECS: u64 = 10;
+// 提交份额的 id 从这里开始, 避开登录和取任务的固定 id
+const SUBMIT_ID_START: u64 = 100000;
+// 这么久没有回复的份额不再等待
+const SUBMIT_TIMEOUT_SECS: u64 = 60;
+// 按最近的提交结果计算接受率, 拒绝率超过阈值时告警
+const RECENT_RESULTS: usize = 100;
+const ALERT_MIN_RESULTS: usize = 20;
+const ALERT_REJECT_RATE: f64 = 0.1;
+
+// 抽水矿池的连接状态和提交结果, 随中转状态上报
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeePoolStatus {
+    // 抽水目标的名称
+    pub name: String,
+    pub connected: bool,
+    pub submits: u64,
+    pub accepts: u64,
+    pub rejects: u64,
+    // 超时、断线或通道已满, 没有得到矿池结果的份额
+    pub lost: u64,
+    // 最近 RECENT_RESULTS 个结果中接受的比例
+    pub accept_rate: f64,
+    // 提交到收到结果的平均延迟
+    pub latency_ms: u64,
+    pub alert: bool,
+}
 
-    let mut json_rpc = EthClientWorkerObject {
-        id: 40,
-        method: "eth_submitWork".into(),
-        params: vec![],
-        worker: worker_name.clone(),
-    };
+#[derive(Debug, Default)]
+pub struct FeeStats {
+    status: Mutex<FeePoolStatus>,
+    recent: Mutex<VecDeque<bool>>,
+}
 
-    let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
-    tokio::pin!(sleep);
-    let mut share_job_idx: u64 = 0;
+impl FeeStats {
+    pub fn new(name: &str) -> Self {
+        let stats = Self::default();
+        stats.update(|s| s.name = name.to_string());
+        stats
+    }
 
-    loop {
-        select! {
-            res = proxy_lines.next_line() => {
-                let buffer = match lines_unwrap(res,&worker_name,"矿池").await {
-                    Ok(buf) => buf,
-                    Err(_) => {
-                        //return Err(anyhow!("抽水旷工掉线了a"));
-                        // info!(worker_name =
-            //       ?worker_name,"退出了。重新登录到池!!");
-             let (new_lines, dev_w) = crate::client::proxy_pool_login_with_ssl(&config,config.share_name.clone()).await?;
-                        //同时加2个值
-                        w = dev_w;
-                        proxy_lines = new_lines;
-                        info!(worker_name = ?worker_name,"重新登录成功!!");
+    pub fn status(&self) -> FeePoolStatus {
+        self.status.lock().map(|s| s.clone()).unwrap_or_default()
+    }
 
-                        continue;
-                    },
-                };
-                #[cfg(debug_assertions)]
-                debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
-            }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
-                    }
-                }
-            },
-            Some(params) = rx.recv() => {
-                share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-        json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
-            },
-            () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
-                sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
-            },
+    fn update(&self, f: impl FnOnce(&mut FeePoolStatus)) {
+        if let Ok(mut status) = self.status.lock() {
+            f(&mut status);
         }
     }
 
-    Ok(())
-}
-pub async fn fee_tcp(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<tokio::io::ReadHalf<tokio::net::TcpStream>>,
-    >,
-    mut w: tokio::io::WriteHalf<tokio::net::TcpStream>, worker_name: String,
-    proxy: Arc<Proxy>,
-) -> Result<()> {
-    let mut config: Settings;
-    {
-        let rconfig = proxy.config.read().await;
-        config = rconfig.clone();
+    fn set_connected(&self, connected: bool) {
+        self.update(|s| s.connected = connected);
     }
-    let mut get_work = EthClientRootObject {
-        id: 6,
-        method: "eth_getWork".into(),
-        params: vec![],
-    };
 
-    let mut json_rpc = EthClientWorkerObject {
-        id: 40,
-        method: "eth_submitWork".into(),
-        params: vec![],
-        worker: worker_name.clone(),
-    };
+    fn submitted(&self) { self.update(|s| s.submits += 1); }
 
-    let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
-    tokio::pin!(sleep);
-    let mut share_job_idx: u64 = 0;
-
-    loop {
-        select! {
-            res = proxy_lines.next_line() => {
-                let buffer = match lines_unwrap(res,&worker_name,"矿池").await {
-                    Ok(buf) => buf,
-                    Err(_) => {
+    pub fn lost(&self) { self.update(|s| s.lost += 1); }
 
-             let (new_lines, dev_w) = crate::client::proxy_pool_login(&config,config.share_name.clone()).await?;
-                        //同时加2个值
-                        w = dev_w;
-                        proxy_lines = new_lines;
-                        info!(worker_name = ?worker_name,"重新登录成功!!");
-
-                        continue;
-                    },
-                };
-                #[cfg(debug_assertions)]
-                debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
-            }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
-                    }
+    // 记录矿池的结果, 延迟按指数移动平均
+    fn record(&self, accepted: bool, latency: Duration) {
+        let (accept_rate, count) = match self.recent.lock() {
+            Ok(mut recent) => {
+                recent.push_back(accepted);
+                if recent.len() > RECENT_RESULTS {
+                    recent.pop_front();
                 }
-            },
-            Some(params) = rx.recv() => {
-                share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-        json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
-            },
-            () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
-                sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
-            },
-        }
+                let accepts = recent.iter().filter(|a| **a).count();
+                (accepts as f64 / recent.len() as f64, recent.len())
+            }
+            Err(_) => return,
+        };
+        let alert = count >= ALERT_MIN_RESULTS
+            && 1.0 - accept_rate > ALERT_REJECT_RATE;
+
+        self.update(|s| {
+            if accepted {
+                s.accepts += 1;
+            } else {
+                s.rejects += 1;
+            }
+            let latency = latency.as_millis() as u64;
+            s.latency_ms = if s.accepts + s.rejects == 1 {
+                latency
+            } else {
+                (s.latency_ms * 4 + latency) / 5
+            };
+            s.accept_rate = accept_rate;
+            if alert && !s.alert {
+                error!(
+                    "抽水矿池拒绝率过高 最近 {} 个份额接受率 {:.1}%",
+                    count,
+                    accept_rate * 100.0
+                );
+            } else if !alert && s.alert {
+                info!("抽水矿池拒绝率恢复正常 {:.1}%", accept_rate * 100.0);
+            }
+            s.alert = alert;
+        });
     }
-
-    Ok(())
 }
 
-pub async fn fee<W: 'static, R: 'static>(
-    rx: Receiver<Vec<String>>, job: Job,
-    proxy_lines: Lines<BufReader<tokio::io::ReadHalf<R>>>, w: WriteHalf<W>,
-    worker_name: String,
-) -> Result<()>
-where
-    R: AsyncRead + Send,
-    W: AsyncWrite + Send,
-{
-    let worker_name_write = worker_name.clone();
-
-    let worker_write = tokio::spawn(async move {
-        match async_write(rx, worker_name_write, w).await {
-            Ok(()) => todo!(),
-            Err(e) => std::panic::panic_any(e),
+// 已提交等待矿池结果的份额: id -> (提交时间, 份额)
+type Pending = HashMap<u64, (Instant, FeeShare)>;
+
+// 没有得到结果的份额按拒绝回传给矿机
+fn drop_pending(pending: &mut Pending, stats: &FeeStats, older_than: Duration) {
+    pending.retain(|_, (sent, share)| {
+        if sent.elapsed() < older_than {
+            return true;
         }
+        stats.lost();
+        share.reply(false);
+        false
     });
+}
 
-    let worker_reader = tokio::spawn(async move {
-        match worker_reader(proxy_lines, job, worker_name).await {
-            Ok(()) => todo!(),
-            Err(e) => std::panic::panic_any(e),
+// 矿池对份额的回复, 拒绝时 result 可能为 false 或 null 并带上 error。
+// 返回对应份额的结果, 不是份额的回复时返回 None
+fn record_result(
+    buffer: &str, pending: &mut Pending, stats: &FeeStats,
+) -> Option<bool> {
+    let result = match parse_server_message(buffer) {
+        StraumServerMessage::Result(result) => result,
+        _ => return None,
+    };
+    let (sent, share) = pending.remove(&result.id)?;
+    let accepted = is_ok(&result.result, &result.error);
+    stats.record(accepted, sent.elapsed());
+    share.reply(accepted);
+    Some(accepted)
+}
+
+// 一个抽水目标的矿池连接, 连接失败或断线后按退避间隔重新登录,
+// 不会结束中转进程。未连接期间这个目标的任务队列为空,
+// 矿机不会收到它的抽水任务。矿池可以是 tcp 或 ssl
+pub async fn fee_pool(
+    mut rx: Receiver<FeeShare>, idx: usize, dest: FeeDestination,
+    proxy: Arc<Proxy>,
+) -> Result<()> {
+    let target = match proxy.fees.get(idx) {
+        Some(target) => target,
+        None => bail!("抽水目标 {} 不存在", dest.name),
+    };
+    let (job, stats) = (&target.job, &target.stats);
+    let worker_name = proxy
+        .config
+        .read()
+        .await
+        .fee_worker_name(&dest.worker)
+        .unwrap_or_else(|_| dest.name.clone());
+    let mut pending = Pending::new();
+    let mut delay = 1;
+    loop {
+        let config = proxy.config.read().await.clone();
+        match proxy_pool_login(&config, &dest).await {
+            Ok((lines, w)) => {
+                let name = &dest.name;
+                info!(worker_name = ?worker_name, "抽水矿池 {} 登录成功", name);
+                stats.set_connected(true);
+                let res = fee_session(
+                    &mut rx,
+                    job,
+                    lines,
+                    w,
+                    &worker_name,
+                    &config,
+                    stats,
+                    &mut pending,
+                    &mut delay,
+                )
+                .await;
+                if let Err(e) = res {
+                    warn!(
+                        worker_name = ?worker_name,
+                        "抽水矿池 {} 断开 {}",
+                        dest.name,
+                        e
+                    );
+                }
+            }
+            Err(e) => {
+                warn!(
+                    worker_name = ?worker_name,
+                    "抽水矿池 {} 登录失败 {}",
+                    dest.name,
+                    e
+                )
+            }
         }
-    });
 
-    let (_, _) = tokio::join!(worker_write, worker_reader);
+        // 旧连接的任务已经无法提交
+        stats.set_connected(false);
+        job.write().await.clear();
+        drop_pending(&mut pending, stats, Duration::ZERO);
+        time::sleep(Duration::from_secs(delay)).await;
+        delay = (delay * 2).min(FEE_RETRY_MAX_SECS);
+    }
+}
 
-    return Err(anyhow!("异常退出了"));
+// 每个抽水目标一个连接, 任意一个退出时返回它的结果
+pub async fn fee_pools(
+    receivers: Vec<(FeeDestination, Receiver<FeeShare>)>, proxy: Arc<Proxy>,
+) -> Result<()> {
+    let (done_tx, mut done) =
+        tokio::sync::mpsc::channel(receivers.len().max(1));
+    for (idx, (dest, rx)) in receivers.into_iter().enumerate() {
+        let (proxy, done_tx) = (proxy.clone(), done_tx.clone());
+        tokio::spawn(async move {
+            let _ = done_tx.send(fee_pool(rx, idx, dest, proxy).await).await;
+        });
+    }
+    drop(done_tx);
+    done.recv().await.unwrap_or(Ok(()))
 }
 
-async fn async_write<W>(
-    mut rx: Receiver<Vec<String>>, worker_name: String, mut w: WriteHalf<W>,
-) -> Result<()>
-where W: AsyncWrite + Send {
+// 一次抽水矿池连接, 返回错误表示需要重连。收到新任务后重置重连间隔
+#[allow(clippy::too_many_arguments)]
+async fn fee_session(
+    rx: &mut Receiver<FeeShare>, job: &Job,
+    mut lines: Lines<BufReader<ReadHalf<Box<dyn PoolStream>>>>,
+    mut w: WriteHalf<Box<dyn PoolStream>>, worker_name: &String,
+    config: &Settings, stats: &FeeStats, pending: &mut Pending,
+    delay: &mut u64,
+) -> Result<()> {
+    let coin = config.coin()?;
+    // 登录前收到的份额属于旧连接的任务
+    while let Ok(share) = rx.try_recv() {
+        stats.lost();
+        share.reply(false);
+    }
+
     let mut get_work = EthClientRootObject {
         id: 6,
         method: "eth_getWork".into(),
         params: vec![],
     };
-
     let mut json_rpc = EthClientWorkerObject {
         id: 40,
         method: "eth_submitWork".into(),
         params: vec![],
         worker: worker_name.clone(),
     };
-
-    let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(5));
-    tokio::pin!(sleep);
     let mut share_job_idx: u64 = 0;
+    let mut getwork = time::interval(Duration::from_secs(GETWORK_SECS));
+    let mut last_job = Instant::now();
+    let timeout = Duration::from_secs(SUBMIT_TIMEOUT_SECS);
 
     loop {
         select! {
-            Some(params) = rx.recv() => {
+            res = lines.next_line() => {
+                let buffer = lines_unwrap(res,worker_name,"矿池").await?;
+                #[cfg(debug_assertions)]
+                debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
+                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
+                    match coin.parse_job(&job_rpc.result) {
+                        Ok(job_res) => {
+                            if push_job(&mut *job.write().await, job_res) {
+                                last_job = Instant::now();
+                                *delay = 1;
+                            }
+                        },
+                        Err(e) => warn!(worker_name = ?worker_name,"抽水任务格式错误 {}",e),
+                    }
+                } else if let Some(false) = record_result(&buffer, pending, stats) {
+                    tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"抽水份额被拒绝");
+                }
+            },
+            Some(mut share) = rx.recv() => {
                 share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-        json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
+                json_rpc.id = SUBMIT_ID_START + share_job_idx;
+                json_rpc.params = std::mem::take(&mut share.params);
+                pending.insert(json_rpc.id, (Instant::now(), share));
+                stats.submitted();
+                write_to_socket_byte(&mut w, json_rpc.to_vec()?, worker_name).await?;
             },
-            () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
-                sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
+            _ = getwork.tick() => {
+                drop_pending(pending, stats, timeout);
+                if last_job.elapsed() > Duration::from_secs(FEE_JOB_STALE_SECS) {
+                    bail!("{} 秒没有收到新的抽水任务", FEE_JOB_STALE_SECS);
+                }
+                write_to_socket_byte(&mut w, get_work.to_vec()?, worker_name).await?;
             },
         }
     }
 }
 
-async fn worker_reader<R>(
-    mut proxy_lines: Lines<BufReader<tokio::io::ReadHalf<R>>>, job: Job,
-    worker_name: String,
-) -> Result<()>
-where
-    R: AsyncRead + Send,
-{
-    loop {
-        select! {
-            res = proxy_lines.next_line() => {
-                let buffer = match lines_unwrap(res,&worker_name,"矿池").await {
-                    Ok(buf) => buf,
-                    Err(_) => {
-                        return Err(anyhow!("抽水旷工掉线了a"));
-                    },
-                };
-                #[cfg(debug_assertions)]
-                debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
-            }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
-                    }
-                }
-            }
+// 区块高度, 矿池没有给出时为 None
+fn job_height(job: &[String]) -> Option<u64> {
+    let height = job.get(3)?;
+    u64::from_str_radix(height.trim_start_matches("0x"), 16).ok()
+}
 
+// 任务加入队列, 返回是否为新任务。区块高度增加时之前的任务全部作废,
+// 超过上限时丢弃最旧的任务
+fn push_job(queue: &mut VecDeque<Vec<String>>, job: Vec<String>) -> bool {
+    if queue.iter().any(|j| j.first() == job.first()) {
+        return false;
+    }
+    let last = queue.back().and_then(|j| job_height(j));
+    if let (Some(old), Some(new)) = (last, job_height(&job)) {
+        if new > old {
+            queue.clear();
         }
     }
+    queue.push_back(job);
+    while queue.len() > FEE_JOB_LIMIT {
+        queue.pop_front();
+    }
+    true
 }
 
 // pub async fn p_fee_ssl(
@@ -526,3 +590,100 @@ where
 //         }
 //     }
 // }
+
+#[test]
+fn test_push_job() {
+    let job = |header: &str, height: &str| {
+        vec![header.into(), "0xseed".into(), "0xtarget".into(), height.into()]
+    };
+    let mut queue = VecDeque::new();
+    assert!(push_job(&mut queue, job("0x01", "0x10")));
+    assert!(push_job(&mut queue, job("0x02", "0x10")));
+    // 重复的任务
+    assert!(!push_job(&mut queue, job("0x01", "0x10")));
+    assert_eq!(queue.len(), 2);
+
+    // 新区块的任务让之前的任务作废
+    assert!(push_job(&mut queue, job("0x03", "0x11")));
+    assert_eq!(queue.len(), 1);
+    assert_eq!(job_height(&queue[0]), Some(0x11));
+
+    for i in 0..FEE_JOB_LIMIT * 2 {
+        push_job(&mut queue, job(&format!("0x1{:02x}", i), "0x11"));
+    }
+    assert_eq!(queue.len(), FEE_JOB_LIMIT);
+    let last = format!("0x1{:02x}", FEE_JOB_LIMIT * 2 - 1);
+    assert_eq!(queue.back().unwrap()[0], last);
+}
+
+#[test]
+fn test_fee_stats() {
+    let stats = FeeStats::new("partner");
+    for i in 0..ALERT_MIN_RESULTS {
+        stats.submitted();
+        stats.record(i % 5 != 0, Duration::from_millis(100));
+    }
+    let status = stats.status();
+    assert_eq!(status.name, "partner");
+    assert_eq!(status.submits, ALERT_MIN_RESULTS as u64);
+    assert_eq!(status.rejects, 4);
+    assert_eq!(status.accept_rate, 0.8);
+    assert_eq!(status.latency_ms, 100);
+    assert!(status.alert);
+
+    for _ in 0..RECENT_RESULTS {
+        stats.record(true, Duration::from_millis(50));
+    }
+    let status = stats.status();
+    assert_eq!(status.accept_rate, 1.0);
+    assert!(status.latency_ms < 60);
+    assert!(!status.alert);
+
+    // 超时的份额按拒绝回传
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut pending = Pending::new();
+    let share = FeeShare {
+        params: vec![],
+        dest: 1,
+        result: tx,
+    };
+    pending.insert(SUBMIT_ID_START, (Instant::now(), share));
+    drop_pending(&mut pending, &stats, Duration::from_secs(60));
+    assert_eq!(pending.len(), 1);
+    drop_pending(&mut pending, &stats, Duration::ZERO);
+    assert!(pending.is_empty());
+    assert_eq!(rx.try_recv(), Ok((1, false)));
+    assert_eq!(stats.status().lost, 1);
+}
+
+#[test]
+fn test_record_result() {
+    let stats = FeeStats::new("partner");
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut pending = Pending::new();
+    for id in SUBMIT_ID_START..SUBMIT_ID_START + 3 {
+        let share = FeeShare {
+            params: vec![],
+            dest: 0,
+            result: tx.clone(),
+        };
+        pending.insert(id, (Instant::now(), share));
+    }
+
+    let accepted = r#"{"id":100000,"jsonrpc":"2.0","result":true}"#;
+    assert_eq!(record_result(accepted, &mut pending, &stats), Some(true));
+    // 拒绝时 result 为 null 并带 error, 不能当成丢失
+    let rejected =
+        r#"{"id":100001,"result":null,"error":[23,"Low difficulty",null]}"#;
+    assert_eq!(record_result(rejected, &mut pending, &stats), Some(false));
+    let rejected = r#"{"id":100002,"error":{"code":-1,"message":"stale"}}"#;
+    assert_eq!(record_result(rejected, &mut pending, &stats), Some(false));
+    // 不是份额的回复
+    assert_eq!(record_result(accepted, &mut pending, &stats), None);
+
+    assert!(pending.is_empty());
+    assert_eq!(rx.try_recv(), Ok((0, true)));
+    assert_eq!(rx.try_recv(), Ok((0, false)));
+    let status = stats.status();
+    assert_eq!((status.accepts, status.rejects, status.lost), (1, 2, 0));
+}
@@ -1,303 +1,613 @@
 use anyhow::{bail, Result};
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc};
 use tracing::{debug, info};
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf},
+    io::{
+        split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt,
+        BufReader, Lines, ReadHalf, WriteHalf,
+    },
     select,
-    sync::RwLockReadGuard,
+    sync::{mpsc, RwLockReadGuard},
     time,
 };
 
 use crate::{
-    client::*,
-    protocol::{
-        ethjson::{EthServerRoot, EthServerRootObject},
-        CLIENT_LOGIN, CLIENT_SUBMITWORK,
+    client::{
+        downstream::{
+            MinerRequest, MinerSession, ERR_BAD_MIX, ERR_INVALID,
+            ERR_LOW_DIFFICULTY, ERR_STALE,
+        },
+        upstream::{PoolEvent, PoolSession},
+        vardiff::VarDiff,
+        *,
     },
+    coin::Coin,
+    protocol::PROTOCOL,
+    proxy::FeeShare,
     state::Worker,
-    util::{config::Settings, is_fee_random},
-};
-
-use crate::{
-    protocol::ethjson::{
-        login, new_eth_get_work, new_eth_submit_hashrate, new_eth_submit_work,
-        EthServer, EthServerRootObjectJsonRpc,
+    util::{
+        self, config::Settings, ethash, fee_window::FeeWindow, is_fee_random,
+        SHARE_ALG_WINDOW,
     },
     DEVELOP_FEE,
 };
 
-pub async fn handle_stream<R, W, PR, PW>(
+// 最多记录的待确认份额数, 矿池不回复提交结果时丢弃最早的
+const PENDING_SHARES: usize = 64;
+
+// 当前任务由谁来挖
+enum JobRound {
+    Develop(Vec<String>),
+    // (抽水目标, 任务)
+    Fee(usize, Vec<String>),
+    Normal,
+}
+
+// 抽水任务对应的抽水目标
+fn fee_dest(fee_job: &[(String, usize)], job_id: &str) -> Option<usize> {
+    fee_job.iter().find(|(j, _)| j == job_id).map(|(_, dest)| *dest)
+}
+
+// 按费率和这台矿机在各目标的抽水难度选择抽水目标, 返回目标和它的最新任务
+async fn pick_fee_job(
+    worker: &Worker, proxy: &Proxy,
+) -> Option<(usize, Vec<String>)> {
+    let mut jobs = Vec::with_capacity(proxy.fees.len());
+    for fee in &proxy.fees {
+        jobs.push(fee.job.read().await.back().cloned());
+    }
+    let rates: Vec<f64> = proxy.fees.iter().map(|f| f.rate).collect();
+    let served: Vec<f64> = proxy
+        .fees
+        .iter()
+        .map(|f| worker.fee_accounts.get(&f.name).map_or(0.0, |a| a.diff_sum))
+        .collect();
+    let available: Vec<bool> = jobs.iter().map(|j| j.is_some()).collect();
+    let dest = util::pick_fee_destination(&rates, &served, &available)?;
+    Some((dest, jobs[dest].take()?))
+}
+
+async fn job_round(
+    worker: &mut Worker, proxy: &Proxy, config: &Settings,
+    window: &mut FeeWindow,
+) -> Result<JobRound> {
+    // 实际抽水比例偏离设置时调整这台矿机的抽水概率
+    let rate = util::corrected_fee_rate(config.share_rate.into(), worker);
+    let wants_fee = match config.share_alg {
+        SHARE_ALG_WINDOW => {
+            window.set_rate(rate);
+            window.wants_fee(std::time::Instant::now())
+        }
+        _ => util::fee(worker.total_send_idx, config, rate),
+    };
+    window.set_fee(false);
+
+    if is_fee_random(*DEVELOP_FEE) {
+        #[cfg(debug_assertions)]
+        debug!("进入开发者抽水回合");
+        let fee = RwLockReadGuard::map(proxy.develop_job.read().await, |f| f);
+        if let Some(job_res) = fee.back() {
+            worker.send_develop_job()?;
+            #[cfg(debug_assertions)]
+            debug!("获取开发者抽水任务成功 {:?}", &job_res);
+            return Ok(JobRound::Develop(job_res.clone()));
+        }
+    } else if wants_fee {
+        #[cfg(debug_assertions)]
+        debug!("进入普通抽水回合");
+        if let Some((dest, job_res)) = pick_fee_job(worker, proxy).await {
+            worker.send_fee_job()?;
+            window.set_fee(true);
+            return Ok(JobRound::Fee(dest, job_res));
+        }
+    }
+
+    Ok(JobRound::Normal)
+}
+
+// 抽水任务的份额交给抽水连接提交, 返回 false 表示是矿工自己的份额。
+// 抽水份额的结果由矿池回复后经 fee_result 回传
+#[allow(clippy::too_many_arguments)]
+fn fee_share(
+    worker: &mut Worker, job_id: &str, params: Vec<String>,
+    fee_job: &[(String, usize)], dev_fee_job: &[String], proxy: &Proxy,
+    share_diff: f64, fee_result: &mpsc::UnboundedSender<(usize, bool)>,
+) -> bool {
+    if dev_fee_job.iter().any(|j| j == job_id) {
+        if let Err(e) = proxy.dev_tx.try_send(params) {
+            debug!("中转通道已满.{}", e);
+        }
+    } else if let Some(dest) = fee_dest(fee_job, job_id) {
+        let target = &proxy.fees[dest];
+        worker.fee_share_index_add();
+        worker.fee_account(&target.name).shares += 1;
+        worker.add_fee_diff(&target.name, share_diff);
+        let share = FeeShare {
+            params,
+            dest,
+            result: fee_result.clone(),
+        };
+        if let Err(e) = target.tx.try_send(share) {
+            debug!("中转通道已满.{}", e);
+            target.stats.lost();
+            worker.fee_share_reject();
+            worker.fee_account(&target.name).rejects += 1;
+        }
+    } else {
+        return false;
+    }
+    true
+}
+
+// 矿机没有提交 mix_digest (EthereumStratum) 时由代理补上
+async fn fill_mix(
+    work: &mut Vec<String>, seed: Option<String>, coin: &dyn Coin,
+) -> Result<()> {
+    if work.len() >= 3 {
+        return Ok(());
+    }
+
+    let seed = match seed {
+        Some(seed) => seed,
+        None => bail!("未知任务 {}", work[1]),
+    };
+    let (mix, _) = ethash::compute(&work[1], &seed, &work[0], coin).await?;
+    work.push(format!("0x{}", hex::encode(mix)));
+    Ok(())
+}
+
+// 本地校验的结果
+enum ShareCheck {
+    Reject((i64, &'static str)),
+    // 份额的哈希结果
+    Valid([u8; 32]),
+}
+
+// 本地校验份额。矿机没有提交 mix_digest 时顺便补上
+async fn verify_share(
+    work: &mut Vec<String>, job: Option<&Vec<String>>, coin: &dyn Coin,
+) -> Result<ShareCheck> {
+    // 最近下发的任务里找不到, 视为过期
+    let job = match job {
+        Some(job) => job,
+        None => return Ok(ShareCheck::Reject(ERR_STALE)),
+    };
+
+    let (mix, result) =
+        ethash::compute(&work[1], &job[1], &work[0], coin).await?;
+    let mix = format!("0x{}", hex::encode(mix));
+    match work.get(2) {
+        Some(submitted) if !submitted.eq_ignore_ascii_case(&mix) => {
+            return Ok(ShareCheck::Reject(ERR_BAD_MIX));
+        }
+        Some(_) => {}
+        None => work.push(mix),
+    }
+
+    if !ethash::meets_target(&result, &job[2])? {
+        return Ok(ShareCheck::Reject(ERR_LOW_DIFFICULTY));
+    }
+    Ok(ShareCheck::Valid(result))
+}
+
+type PoolLines = Lines<BufReader<ReadHalf<Box<dyn PoolStream>>>>;
+
+// 换用新的矿池连接: 按新矿池的协议订阅, 矿机已登录时重新登录并拉取任务
+async fn reconnect_pool(
+    stream: Box<dyn PoolStream>, dialect: PROTOCOL, coin: &'static dyn Coin,
+    account: &Option<(String, String, String)>, start: bool,
+    worker_name: &String,
+) -> Result<(PoolLines, WriteHalf<Box<dyn PoolStream>>, PoolSession)> {
+    let (pool_r, mut pool_w) = split(stream);
+    let mut pool = PoolSession::new(dialect, coin);
+    if start {
+        pool.start(&mut pool_w, worker_name).await?;
+    }
+    if let Some((wallet, rig, password)) = account {
+        pool.login(&mut pool_w, wallet, rig, password, worker_name).await?;
+    }
+    pool.get_work(&mut pool_w, worker_name).await?;
+    Ok((BufReader::new(pool_r).lines(), pool_w, pool))
+}
+
+// 矿机与矿池两端各自按自己的协议收发。矿池协议由矿池地址的前缀决定,
+// 没有前缀时与矿机一致。pool_idx 为使用的矿池序号, None 表示聚合连接。
+pub async fn handle_stream<R, W>(
     worker: &mut Worker,
     worker_r: tokio::io::BufReader<tokio::io::ReadHalf<R>>,
-    mut worker_w: WriteHalf<W>,
-    pool_r: tokio::io::BufReader<tokio::io::ReadHalf<PR>>,
-    mut pool_w: WriteHalf<PW>, proxy: Arc<Proxy>, is_encrypted: bool,
+    mut worker_w: WriteHalf<W>, pool_stream: Box<dyn PoolStream>,
+    proxy: Arc<Proxy>, mut pool_idx: Option<usize>,
 ) -> Result<()>
 where
     R: AsyncRead,
     W: AsyncWrite,
-    PR: AsyncRead,
-    PW: AsyncWrite,
 {
     let mut worker_name: String = String::new();
-    let mut eth_server_result = EthServerRoot {
-        id: 0,
-        jsonrpc: "2.0".into(),
-        result: true,
-    };
 
-    let mut job_rpc = EthServerRootObjectJsonRpc {
-        id: 0,
-        jsonrpc: "2.0".into(),
-        result: vec![],
-    };
-
-    let mut fee_job: Vec<String> = Vec::new();
+    // 抽水任务和对应的抽水目标
+    let mut fee_job: Vec<(String, usize)> = Vec::new();
     let mut dev_fee_job: Vec<String> = Vec::new();
 
-    //最后一次发送的rpc_id
-    let mut rpc_id = 0;
+    // ETHProxy 矿池轮询时会重复返回同一个任务
+    let mut last_job_id = String::new();
+    // 已提交给矿池、等待结果的份额难度, 矿池按提交顺序回复
+    let mut pending_diffs: VecDeque<f64> = VecDeque::new();
+    // 抽水连接回传的抽水份额结果
+    let (fee_result, mut fee_results) = mpsc::unbounded_channel();
 
     let mut worker_lines = worker_r.lines();
-    //let mut total_send_idx = 0;
-    // 包装为封包格式。
-    let mut pool_lines = pool_r.lines();
-
-    //let mut send_job = Vec::new();
+    let (pool_r, mut pool_w) = split(pool_stream);
+    let mut pool_lines: PoolLines = BufReader::new(pool_r).lines();
 
-    // if is_encrypted {
-    //     worker_lines = worker_r.split(SPLIT);
-    // } else {
-    //     worker_lines = worker_r.split(b'\n');
-    // }
-
-    use rand::SeedableRng;	
+    use rand::SeedableRng;
     let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
     let send_time = rand::Rng::gen_range(&mut rng, 1..360) as u64;
     let workers_queue = proxy.worker_tx.clone();
     let sleep = time::sleep(tokio::time::Duration::from_secs(send_time));
     tokio::pin!(sleep);
 
-    // let mut chan = proxy.chan.subscribe();
-    // let mut dev_chan = proxy.dev_chan.subscribe();
-    let tx = proxy.tx.clone();
-    let dev_tx = proxy.dev_tx.clone();
-
-    // 当前Job高度。
-    let _job_hight = 0;
-    // 欠了几个job
-    // let mut dev_fee_idx = 0;
-    // let mut fee_idx = 0;
-    // let mut idx = 0;
-
-    let mut wait_job: VecDeque<Vec<String>> = VecDeque::new();
-    let mut wait_dev_job: VecDeque<Vec<String>> = VecDeque::new();
+    // 矿机不是 ETHProxy 而矿池是时, 由代理向矿池轮询任务
+    let mut getwork = time::interval(time::Duration::from_secs(2));
 
     let config: Settings;
     {
         let rconfig = RwLockReadGuard::map(proxy.config.read().await, |s| s);
         config = rconfig.clone();
     }
+    let mut fee_window =
+        FeeWindow::new(config.share_rate.into(), std::time::Instant::now());
+    let coin = config.coin()?;
+
+    // 聚合连接固定使用 ETHProxy
+    let dialects = get_pool_dialects(&config.pool_address);
+    let dialect_of = |idx: Option<usize>| match idx {
+        Some(idx) => dialects.get(idx).cloned().flatten(),
+        None => Some(PROTOCOL::ETH),
+    };
+    // 矿池地址中设置了钱包、矿工名或密码时替换矿机自己的
+    let login_of = |idx: Option<usize>, account: &(String, String, String)| {
+        let (wallet, rig, password) = account;
+        match idx.and_then(|idx| proxy.pool_health.url(idx)) {
+            Some(url) => url.login(wallet, rig, password),
+            None => account.clone(),
+        }
+    };
+    let mut dialect = dialect_of(pool_idx);
+    let mut pool_switch = proxy.pool_health.subscribe();
+    // 切换矿池后需要的新连接
+    let mut next_pool: Option<(usize, Box<dyn PoolStream>)> = None;
+    // 矿机自己的登录信息, 切换矿池后重新登录
+    let mut account: Option<(String, String, String)> = None;
+    // 重新登录的结果不再回复矿机
+    let mut relogin = false;
+    // 切换矿池后的第一个任务要求矿机立即切换
+    let mut clean_job = false;
+
+    let mut miner = MinerSession::new(coin);
+    let mut pool =
+        PoolSession::new(dialect.clone().unwrap_or(PROTOCOL::KNOWN), coin);
+    let mut vardiff = VarDiff::new(config.vardiff_spm);
 
     loop {
         select! {
             res = worker_lines.next_line() => {
                 let buffer = lines_unwrap(res,&worker_name,"矿机").await?;
-                    if let Some(mut json_rpc) = parse(buffer.as_bytes()) {
-                        #[cfg(debug_assertions)]
-                        info!("接受矿工: {} 提交 RPC {:?}",worker.worker_name,json_rpc);
-                        rpc_id = json_rpc.get_id();
-                        let res = match json_rpc.get_method().as_str() {
-                            "eth_submitLogin" => {
-                                eth_server_result.id = rpc_id;
-                                login(worker,&mut pool_w,&mut json_rpc,&mut worker_name,&config).await?;
-                                write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
-                                Ok(())
-                            },
-                            "eth_submitWork" => {
-                                eth_server_result.id = rpc_id;
-                                if let Some(job_id) = json_rpc.get_job_id() {
-                                    #[cfg(debug_assertions)]
-                                    debug!("0 :  收到提交工作量 {} #{:?}",worker_name, json_rpc);
-                                    let mut json_rpc = Box::new(EthClientWorkerObject{ id: json_rpc.get_id(), method: json_rpc.get_method(), params: json_rpc.get_params(), worker: worker.worker_name.clone()});
-                    if dev_fee_job.contains(&job_id) {
-//                    debug!("0 :  收到开发者工作量 {} #{:?}",worker_name, json_rpc);
-                                        match dev_tx.try_send(json_rpc.get_params()){
-                        Ok(_) => {},
-                        Err(e)=> {
-                        debug!("开发者通道已满.{}",e);
-                        },
+                let mut json_rpc = match parse(buffer.as_bytes()) {
+                    Some(json_rpc) => json_rpc,
+                    None => {
+                        tracing::warn!("协议解析错误: {:?}",buffer);
+                        continue;
+                    },
+                };
+                #[cfg(debug_assertions)]
+                info!("接受矿工: {} 提交 RPC {:?}",worker.worker_name,json_rpc);
+                let rpc_id = json_rpc.get_id();
+
+                if miner.dialect == PROTOCOL::KNOWN {
+                    miner.dialect = MinerSession::detect(json_rpc.as_ref());
+                    worker.set_protocol(miner.dialect.clone());
+                    if dialect.is_none() {
+                        pool = PoolSession::new(miner.dialect.clone(), coin);
                     }
-                                    } else if fee_job.contains(&job_id) {
+                    pool.start(&mut pool_w,&worker_name).await?;
+                }
+
+                match miner.parse(json_rpc.as_ref()) {
+                    MinerRequest::Subscribe => {
+                        miner.subscribe_id = rpc_id;
+                        if miner.dialect == PROTOCOL::ETH {
+                            //GMiner
+                            pool.get_work(&mut pool_w,&worker_name).await?;
+                        }
+
+                        if pool.dialect == PROTOCOL::NICEHASHSTRATUM && pool.extranonce.is_empty() {
+                            // 等矿池分配 extranonce 后再回复
+                            miner.pending_subscribe = true;
+                        } else {
+                            let extranonce = if pool.dialect == PROTOCOL::NICEHASHSTRATUM {
+                                pool.extranonce.clone()
+                            } else {
+                                format!("{:04x}", rand::Rng::gen::<u16>(&mut rng))
+                            };
+                            let session_id = format!("{:016x}", rand::Rng::gen::<u64>(&mut rng));
+                            miner.reply_subscribe(&mut worker_w,&session_id,&extranonce,&worker_name).await?;
+                        }
+                    },
+                    MinerRequest::ExtranonceSubscribe => {
+                        miner.extranonce_subscribed = true;
+                        miner.reply(&mut worker_w,rpc_id,true,&worker_name).await?;
+                    },
+                    MinerRequest::Login(wallet, rig, password) => {
+                        miner.authorize_id = rpc_id;
+                        worker_name = format!("{}.{}", wallet, rig);
+                        worker.login(worker_name.clone(), rig.clone(), wallet.clone());
+                        let miner_account = (wallet, rig, password);
+                        let (wallet, rig, password) = login_of(pool_idx, &miner_account);
+                        pool.login(&mut pool_w,&wallet,&rig,&password,&worker_name).await?;
+                        account = Some(miner_account);
+                    },
+                    MinerRequest::Submit(mut work) => {
+                        #[cfg(debug_assertions)]
+                        debug!("0 :  收到提交工作量 {} #{:?}",worker_name, work);
+                        let job_id = work[1].clone();
+                        let fee_target = fee_dest(&fee_job,&job_id).map(|dest| proxy.fees[dest].name.clone());
+                        let is_fee = fee_target.is_some() || dev_fee_job.contains(&job_id);
+                        let share_diff = miner.find_job(&job_id).map(|j| coin.share_difficulty(&j[2])).unwrap_or(0.0);
+
+                        // Octopus 等非 Ethash 币种无法在本地校验
+                        if (config.verify_share || vardiff.enabled()) && coin.ethash_epoch(0).is_some() {
+                            let job = miner.find_job(&job_id).cloned();
+                            let check = match verify_share(&mut work, job.as_ref(), coin).await {
+                                Ok(check) => check,
+                                Err(e) => {
+                                    tracing::warn!("矿工: {} 份额校验失败 {}",worker_name,e);
+                                    ShareCheck::Reject(ERR_INVALID)
+                                },
+                            };
+
+                            match check {
+                                ShareCheck::Reject(error) => {
+                                    debug!("矿工: {} 本地拒绝份额 {} {:?}",worker_name,error.1,work);
+                                    if is_fee {
                                         worker.fee_share_index_add();
-                                        worker.fee_share_accept();
-                    match tx.try_send(json_rpc.get_params()) {
-                        Ok(()) => {},
-                        Err(e) => {
-                        debug!("中转通道已满.{}",e);
-                        },
-                    }
+                                        worker.fee_share_reject();
+                                        if let Some(name) = &fee_target {
+                                            let account = worker.fee_account(name);
+                                            account.shares += 1;
+                                            account.rejects += 1;
+                                        }
                                     } else {
                                         worker.share_index_add();
-                                        new_eth_submit_work(worker,&mut pool_w,&mut worker_w,&mut json_rpc,&worker_name,&config).await?;
+                                        if error == ERR_STALE {
+                                            worker.share_stale();
+                                        } else {
+                                            worker.share_reject();
+                                        }
+                                    }
+                                    miner.reply_error(&mut worker_w,rpc_id,error,&worker_name).await?;
+                                    continue;
+                                },
+                                ShareCheck::Valid(result) => {
+                                    vardiff.add_share();
+                                    // 只达到矿机难度的份额不转发给矿池
+                                    if let Some(target) = vardiff.pool_target(&job_id) {
+                                        if !ethash::meets_target(&result, target)? {
+                                            worker.share_difficulty(share_diff);
+                                            if let Some(name) = &fee_target {
+                                                worker.add_fee_diff(name,share_diff);
+                                            } else if !dev_fee_job.contains(&job_id) {
+                                                worker.add_share_diff(share_diff,false);
+                                            }
+                                            miner.reply(&mut worker_w,rpc_id,true,&worker_name).await?;
+                                            continue;
+                                        }
                                     }
+                                },
+                            }
+                        }
 
-                                    write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
-                                    Ok(())
-                                } else {
-                                    pool_w.shutdown().await?;
-                                    worker_w.shutdown().await?;
-                                    bail!("非法攻击");
-                                }
-                            },
-                            "eth_submitHashrate" => {
-                                eth_server_result.id = rpc_id;
-                                let mut hash = json_rpc.get_submit_hashrate();
-                                hash = (hash as f64 * (config.hash_rate as f32 / 100.0) as f64) as u64;
-                                json_rpc.set_submit_hashrate(format!("0x{:x}", hash));
-                                new_eth_submit_hashrate(worker,&mut pool_w,&mut json_rpc,&worker_name).await?;
-                                write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
-                                Ok(())
-                            },
-                            "eth_getWork" => {
-                                new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
-                                // eth_server_result.id = rpc_id;
-                                // write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
-                                Ok(())
-                            },
-                            "mining.subscribe" =>{ //GMiner
-                                new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
-                                eth_server_result.id = rpc_id;
-                                write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
-                                Ok(())
+                        if (is_fee && coin.need_mix()) || pool.need_mix() {
+                            let seed = miner.find_job(&job_id).map(|j| j[1].clone());
+                            if let Err(e) = fill_mix(&mut work, seed, coin).await {
+                                tracing::warn!("矿工: {} 计算 mix_digest 失败 {}",worker_name,e);
+                                worker.share_index_add();
+                                worker.share_reject();
+                                miner.reply_error(&mut worker_w,rpc_id,ERR_INVALID,&worker_name).await?;
+                                continue;
                             }
-                            _ => {
-                                // tracing::warn!("Not found method {:?}",json_rpc);
-                                // eth_server_result.id = rpc_id;
-                                // write_to_socket_byte(&mut pool_w,buffer.to_vec(),&mut worker_name).await?;
-                                pool_w.shutdown().await?;
-                                worker_w.shutdown().await?;
-                                return Ok(());
-                            },
-                        };
+                        }
 
-                        if res.is_err() {
-                            tracing::warn!("写入任务错误: {:?}",res);
-                            return res;
+                        if fee_share(worker,&job_id,work.clone(),&fee_job,&dev_fee_job,&proxy,share_diff,&fee_result) {
+                            worker.share_difficulty(share_diff);
+                        } else {
+                            worker.share_index_add();
+                            if pool.submit(&mut pool_w,&work,&worker.worker_name,&worker_name).await? {
+                                worker.add_share_diff(share_diff,false);
+                                if pending_diffs.len() >= PENDING_SHARES {
+                                    pending_diffs.pop_front();
+                                }
+                                pending_diffs.push_back(share_diff);
+                            } else {
+                                tracing::warn!("矿工: {} 份额无法提交给矿池 {:?}",worker_name,work);
+                                // 矿池已经没有这个任务时按过期份额处理
+                                let error = if pool.has_job(&work[1]) {
+                                    worker.share_reject();
+                                    ERR_INVALID
+                                } else {
+                                    worker.share_stale();
+                                    ERR_STALE
+                                };
+                                miner.reply_error(&mut worker_w,rpc_id,error,&worker_name).await?;
+                                continue;
+                            }
                         }
-                    } else {
-                        tracing::warn!("协议解析错误: {:?}",buffer);
-                    }
 
+                        miner.reply(&mut worker_w,rpc_id,true,&worker_name).await?;
+                    },
+                    MinerRequest::Hashrate => {
+                        let mut hash = json_rpc.get_submit_hashrate();
+                        hash = (hash as f64 * (config.hash_rate as f32 / 100.0) as f64) as u64;
+                        json_rpc.set_submit_hashrate(format!("0x{:x}", hash));
+                        worker.new_submit_hashrate(&mut json_rpc);
+                        pool.submit_hashrate(&mut pool_w,json_rpc.get_params(),&worker.worker_name,&worker_name).await?;
+                        miner.reply(&mut worker_w,rpc_id,true,&worker_name).await?;
+                    },
+                    MinerRequest::GetWork => {
+                        if pool.dialect == PROTOCOL::ETH {
+                            pool.get_work(&mut pool_w,&worker_name).await?;
+                        } else {
+                            miner.resend_job(&mut worker_w,&worker_name).await?;
+                        }
+                    },
+                    MinerRequest::Unknown => {
+                        if miner.dialect == PROTOCOL::ETH {
+                            // tracing::warn!("Not found method {:?}",json_rpc);
+                            pool_w.shutdown().await?;
+                            worker_w.shutdown().await?;
+                            return Ok(());
+                        }
+                        debug!("矿工: {} 未处理的方法 {:?}",worker_name,json_rpc);
+                    },
+                }
             },
             res = pool_lines.next_line() => {
-                let buffer = lines_unwrap(res,&worker_name,"矿池").await?;
+                let buffer = match lines_unwrap(res,&worker_name,"矿池").await {
+                    Ok(buffer) => buffer,
+                    Err(e) => {
+                        // 聚合连接由聚合模块自己重连
+                        let idx = match pool_idx {
+                            Some(idx) => idx,
+                            None => return Err(e),
+                        };
+                        proxy.pool_health.report_disconnect(idx, &worker_name);
+                        match proxy.pool_health.connect().await {
+                            Ok(res) => next_pool = Some(res),
+                            Err(_) => return Err(e),
+                        }
+                        continue;
+                    },
+                };
                 #[cfg(debug_assertions)]
                 debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
 
-                if let Ok(rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    // 增加索引
-                    worker.send_job()?;
-                    if is_fee_random(*DEVELOP_FEE) {
-                        #[cfg(debug_assertions)]
-                        debug!("进入开发者抽水回合");
-                        //if let Some(job_res) = wait_dev_job.pop_back() {
-			let fee = RwLockReadGuard::map(proxy.develop_job.read().await, |f| f);			
-			if let Some(job_res) = fee.back() {
-                            worker.send_develop_job()?;
-                            #[cfg(debug_assertions)]
-                            debug!("获取开发者抽水任务成功 {:?}",&job_res);
-                            job_rpc.result = job_res.clone();
-                            let job_id = job_rpc.get_job_id().unwrap();
-                            dev_fee_job.push(job_id.clone());
-                            #[cfg(debug_assertions)]
-                            debug!("{} 发送开发者任务 #{:?}",worker_name, job_rpc);
-                            write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
-                            continue;
-                        }			
-			
-			// if let Ok(job_res) = dev_chan.recv().await {
-                        //     worker.send_develop_job()?;
-                        //     #[cfg(debug_assertions)]
-                        //     debug!("获取开发者抽水任务成功 {:?}",&job_res);
-                        //     job_rpc.result = job_res;
-                        //     let job_id = job_rpc.get_job_id().unwrap();
-                        //     dev_fee_job.push(job_id.clone());
-                        //     #[cfg(debug_assertions)]
-                        //     debug!("{} 发送开发者任务 #{:?}",worker_name, job_rpc);
-                        //     write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
-                        //     continue;
-                        // }
-                    } else if is_fee_random(config.share_rate.into()) {
-                        #[cfg(debug_assertions)]
-                        debug!("进入普通抽水回合");
-
-
-			let fee = RwLockReadGuard::map(proxy.fee_job.read().await, |f| f);
-			if let Some(job_res) = fee.back() {
-                            worker.send_fee_job()?;
-                            job_rpc.result = job_res.clone();
-                            let job_id = job_rpc.get_job_id().unwrap();
-                            fee_job.push(job_id.clone());
-                            #[cfg(debug_assertions)]
-                            debug!("{} 发送抽水任务 #{:?}",worker_name, job_rpc);
-                            write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
+                match pool.parse(&buffer) {
+                    PoolEvent::Job(job) => {
+                        if job[0] == last_job_id {
+                            if miner.dialect == PROTOCOL::ETH {
+                                miner.resend_job(&mut worker_w,&worker_name).await?;
+                            }
                             continue;
                         }
-			//                        if let Some(job_res) = wait_job.pop_back() {
-			// if let Ok(job_res) = chan.recv().await {
-                        //     worker.send_fee_job()?;
-                        //     job_rpc.result = job_res;
-                        //     let job_id = job_rpc.get_job_id().unwrap();
-                        //     fee_job.push(job_id.clone());
-                        //     #[cfg(debug_assertions)]
-                        //     debug!("{} 发送抽水任务 #{:?}",worker_name, job_rpc);
-                        //     write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
-                        //     continue;
-                        // }
-                    }
+                        last_job_id = job[0].clone();
 
-
-                    job_rpc.result = rpc.result;
-                    // let job_id = job_rpc.get_job_id().unwrap();
-                    // send_job.push(job_id);
-                    #[cfg(debug_assertions)]
-                    debug!("{} 发送普通任务 #{:?}",worker_name, job_rpc);
-                    write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.id == CLIENT_LOGIN {
-                        worker.logind();
-                    } else if result_rpc.id == CLIENT_SUBMITWORK && result_rpc.result {
-                        worker.share_accept();
-                    } else if result_rpc.id == CLIENT_SUBMITWORK {
-                        worker.share_reject();
+                        // 增加索引
+                        worker.send_job()?;
+                        let (mut job, fee_round) = match job_round(worker,&proxy,&config,&mut fee_window).await? {
+                            JobRound::Develop(job) => {
+                                dev_fee_job.push(job[0].clone());
+                                (job, true)
+                            },
+                            JobRound::Fee(dest, job) => {
+                                fee_job.push((job[0].clone(), dest));
+                                (job, true)
+                            },
+                            JobRound::Normal => (job, false),
+                        };
+                        if vardiff.retarget(std::time::Instant::now()) {
+                            debug!("矿工: {} 调整难度为 {}",worker_name,vardiff.diff());
+                        }
+                        vardiff.apply(&mut job,coin);
+                        miner.send_job(&mut worker_w,job,fee_round || clean_job,&worker_name).await?;
+                        clean_job = false;
+                    },
+                    PoolEvent::Login(ok) => {
+                        if relogin {
+                            relogin = false;
+                            if !ok {
+                                tracing::warn!("矿工: {} 切换矿池后登录失败",worker_name);
+                            }
+                        } else {
+                            if ok {
+                                worker.logind();
+                            } else {
+                                tracing::warn!("矿工: {} 矿池登录失败",worker_name);
+                            }
+                            miner.reply(&mut worker_w,miner.authorize_id,ok,&worker_name).await?;
+                        }
+                        pool.get_work(&mut pool_w,&worker_name).await?;
+                    },
+                    PoolEvent::Submit(ok) => {
+                        let share_diff = pending_diffs.pop_front();
+                        if ok {
+                            worker.share_accept();
+                            if let Some(diff) = share_diff {
+                                worker.share_difficulty(diff);
+                            }
+                        } else {
+                            worker.share_reject();
+                        }
+                    },
+                    PoolEvent::Subscribed(extranonce) => {
+                        if miner.pending_subscribe {
+                            miner.pending_subscribe = false;
+                            let session_id = format!("{:016x}", rand::Rng::gen::<u64>(&mut rng));
+                            miner.reply_subscribe(&mut worker_w,&session_id,&extranonce,&worker_name).await?;
+                        } else if pool.dialect == PROTOCOL::NICEHASHSTRATUM && miner.dialect == PROTOCOL::NICEHASHSTRATUM
+                            && !extranonce.is_empty() && extranonce != miner.extranonce {
+                            // 切换矿池后 extranonce 变了
+                            miner.set_extranonce(&mut worker_w,&extranonce,&worker_name).await?;
+                        }
+                    },
+                    PoolEvent::SetExtranonce(extranonce) => {
+                        if miner.dialect == PROTOCOL::NICEHASHSTRATUM {
+                            miner.set_extranonce(&mut worker_w,&extranonce,&worker_name).await?;
+                        }
+                    },
+                    PoolEvent::Other => {},
+                }
+            },
+            res = pool_switch.changed(), if pool_idx.is_some() => {
+                let idx = *pool_switch.borrow();
+                if res.is_err() || Some(idx) == pool_idx {
+                    continue;
+                }
+                match proxy.pool_health.connect_to(idx).await {
+                    Ok(stream) => next_pool = Some((idx, stream)),
+                    Err(e) => {
+                        tracing::warn!("矿工: {} 无法切换到矿池 {}",worker_name,e);
+                        proxy.pool_health.report_error(idx, &e, "连接失败");
+                    },
+                }
+            },
+            Some((dest, accepted)) = fee_results.recv() => {
+                if let Some(fee) = proxy.fees.get(dest) {
+                    let account = worker.fee_account(&fee.name);
+                    if accepted {
+                        account.accepts += 1;
+                    } else {
+                        account.rejects += 1;
                     }
                 }
+                if accepted {
+                    worker.fee_share_accept();
+                } else {
+                    worker.fee_share_reject();
+                }
+            },
+            _ = getwork.tick(), if pool.dialect == PROTOCOL::ETH && miner.dialect != PROTOCOL::ETH && worker.is_online() => {
+                pool.get_work(&mut pool_w,&worker_name).await?;
             },
-            // Ok(job_res) = dev_chan.recv() => {
-            //     wait_dev_job.push_back(job_res);
-            // },
-            // Ok(job_res) = chan.recv() => {
-            //     wait_job.push_back(job_res);
-            // },
             () = &mut sleep  => {
-		if dev_fee_job.len() > 1000 {
-		     dev_fee_job  = dev_fee_job.drain(750..).collect();
-		}
-		
-		if fee_job.len() > 1000 {
-		    fee_job = fee_job.drain(750..).collect();
-		}
-		
-		if wait_dev_job.len() > 1000 {
-		    wait_dev_job = wait_dev_job.drain(900..).collect();
-		}
-		
-		if wait_job.len() > 1000 {
-		    wait_job = wait_job.drain(900..).collect();
-		}
-		
+                if dev_fee_job.len() > 1000 {
+                    dev_fee_job = dev_fee_job.drain(750..).collect();
+                }
+
+                if fee_job.len() > 1000 {
+                    fee_job = fee_job.drain(750..).collect();
+                }
+
+                worker.update_hashrate();
                 match workers_queue.send(worker.clone()) {
                     Ok(_) => {},
                     Err(_) => {
@@ -307,5 +617,22 @@ where
                 sleep.as_mut().reset(time::Instant::now() + time::Duration::from_secs(send_time));
             },
         }
+
+        if let Some((idx, stream)) = next_pool.take() {
+            dialect = dialect_of(Some(idx));
+            let pool_dialect = dialect.clone().unwrap_or_else(|| miner.dialect.clone());
+            let started = miner.dialect != PROTOCOL::KNOWN;
+            let (lines, w, new_pool) =
+                reconnect_pool(stream, pool_dialect, coin, &account.as_ref().map(|a| login_of(Some(idx), a)), started, &worker_name).await?;
+            info!("矿工: {} 切换到矿池 {}", worker_name, proxy.pool_health.address(idx).unwrap_or_default());
+            pool_lines = lines;
+            pool_w = w;
+            pool = new_pool;
+            pool_idx = Some(idx);
+            relogin = account.is_some();
+            clean_job = true;
+            last_job_id.clear();
+            pending_diffs.clear();
+        }
     }
 }
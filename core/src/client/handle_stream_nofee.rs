@@ -30,7 +30,7 @@ use crate::{
         PROTOCOL, SUBSCRIBE,
     },
     state::Worker,
-    util::{config::Settings, get_eth_wallet},
+    util::config::Settings,
 };
 
 use super::write_to_socket;
@@ -234,42 +234,12 @@ where W: AsyncWrite {
     // bail!("端口可能被恶意扫描。");
 }
 
-async fn develop_pool_login(
-    hostname: String,
-) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
-    let stream = match pools::get_develop_pool_stream().await {
-        Ok(s) => s,
-        Err(e) => {
-            debug!("无法链接到矿池{}", e);
-            return Err(e);
-        }
-    };
-
-    let outbound = TcpStream::from_std(stream)?;
-
-    let (develop_r, mut develop_w) = tokio::io::split(outbound);
-    let develop_r = tokio::io::BufReader::new(develop_r);
-    let develop_lines = develop_r.lines();
-
-    let develop_name = hostname + "_develop";
-    let login_develop = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![get_eth_wallet(), "x".into()],
-        worker: develop_name.to_string(),
-    };
-
-    write_to_socket(&mut develop_w, &login_develop, &develop_name).await?;
-
-    Ok((develop_lines, develop_w))
-}
-
 async fn proxy_pool_login(
     config: &Settings, _hostname: String,
 ) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
     //TODO 这里要兼容SSL矿池
-    let (stream, _) =
-        match crate::client::get_pool_stream(&config.share_address) {
+    let (outbound, _) =
+        match crate::client::get_pool_stream(&config.share_address).await {
             Some((stream, addr)) => (stream, addr),
             None => {
                 tracing::error!("所有TCP矿池均不可链接。请修改后重试");
@@ -277,7 +247,6 @@ async fn proxy_pool_login(
             }
         };
 
-    let outbound = TcpStream::from_std(stream)?;
     let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
     let proxy_r = tokio::io::BufReader::new(proxy_r);
     let proxy_lines = proxy_r.lines();
@@ -313,14 +282,13 @@ pub async fn pool_with_tcp_reconnect(
             }
         };
     // if stream_type == crate::client::TCP {
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (stream, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
 
     let (pool_r, pool_w) = tokio::io::split(stream);
     let pool_r = tokio::io::BufReader::new(pool_r);
@@ -355,14 +323,13 @@ pub async fn pool_with_ssl_reconnect(
                 bail!("未匹配到矿池 或 均不可链接。请修改后重试");
             }
         };
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (stream, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
 
     let (pool_r, pool_w) = tokio::io::split(stream);
     let pool_r = tokio::io::BufReader::new(pool_r);
@@ -41,7 +41,7 @@ use crate::{
         PROTOCOL, SUBSCRIBE,
     },
     state::Worker,
-    util::{config::Settings, get_eth_wallet, is_fee_random},
+    util::{config::Settings, is_fee_random},
 };
 
 use super::write_to_socket;
@@ -247,36 +247,6 @@ where
     }
 }
 
-async fn develop_pool_login(
-    hostname: String,
-) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
-    let stream = match pools::get_develop_pool_stream().await {
-        Ok(s) => s,
-        Err(e) => {
-            debug!("无法链接到矿池{}", e);
-            return Err(e);
-        }
-    };
-
-    let outbound = TcpStream::from_std(stream)?;
-
-    let (develop_r, mut develop_w) = tokio::io::split(outbound);
-    let develop_r = tokio::io::BufReader::new(develop_r);
-    let mut develop_lines = develop_r.lines();
-
-    let develop_name = hostname + "_develop";
-    let login_develop = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![get_eth_wallet(), "x".into()],
-        worker: develop_name.to_string(),
-    };
-
-    write_to_socket(&mut develop_w, &login_develop, &develop_name).await?;
-
-    Ok((develop_lines, develop_w))
-}
-
 async fn proxy_pool_login(
     config: &Settings, hostname: String,
 ) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
@@ -401,8 +371,6 @@ where
 
     let proxy_wallet_and_worker_name =
         config.share_wallet.clone() + "." + &config.share_name;
-    let develop_wallet_and_worker_name =
-        get_eth_wallet() + "." + &crate::DEVELOP_WORKER_NAME;
 
     let mut protocol = PROTOCOL::KNOWN;
     let mut first = true;
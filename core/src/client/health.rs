@@ -0,0 +1,467 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+use tokio::{io::AsyncWriteExt, sync::watch, time};
+
+use crate::{
+    client::{
+        connect_pool, dial, pool_url::PoolUrl, tls_client::TlsOptions,
+        PoolStream,
+    },
+    proxy::Proxy,
+};
+
+// 健康检查的间隔
+const CHECK_SECS: u64 = 10;
+// 连续失败几次判定为不可用
+const FAIL_CHECKS: u32 = 2;
+// 主矿池连续正常几次后切回
+const FAILBACK_CHECKS: u32 = 3;
+// 计算可用率的检查次数
+const HISTORY: usize = 30;
+// 内存中保留的切换记录数
+const EVENTS: usize = 100;
+// 切换记录文件所在的目录
+const LOG_DIR: &str = "./logs/";
+// 一段时间内有多个矿工的连接被矿池断开才算一次失败,
+// 单个矿工被踢或者被封不影响整个矿场
+const DISCONNECT_SECS: u64 = 60;
+const DISCONNECT_SESSIONS: usize = 5;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PoolStatus {
+    pub address: String,
+    // 越小越优先, 没有设置时第一个矿池为 0, 其余为 1
+    pub priority: u32,
+    pub weight: u32,
+    // 最近一次连接成功的耗时
+    pub latency_ms: u64,
+    // 连续成功/失败的次数
+    pub successes: u32,
+    pub failures: u32,
+    // 最近一次失败的原因, 例如证书校验失败
+    pub last_error: Option<String>,
+    history: VecDeque<bool>,
+    // 最近被断开的矿工连接
+    #[serde(skip)]
+    disconnects: VecDeque<(Instant, String)>,
+}
+
+impl PoolStatus {
+    // 还没有检查过的矿池视为可用
+    pub fn healthy(&self) -> bool { self.failures < FAIL_CHECKS }
+
+    pub fn availability(&self) -> f64 {
+        if self.history.is_empty() {
+            return 1.0;
+        }
+        self.history.iter().filter(|ok| **ok).count() as f64
+            / self.history.len() as f64
+    }
+
+    // 可用率为主, 延迟最多扣 100 分
+    pub fn score(&self) -> f64 {
+        self.availability() * 100.0 - self.latency_ms.min(1000) as f64 / 10.0
+    }
+
+    fn record(&mut self, latency_ms: Option<u64>) {
+        if self.history.len() >= HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(latency_ms.is_some());
+        match latency_ms {
+            Some(latency_ms) => {
+                self.latency_ms = latency_ms;
+                self.successes += 1;
+                self.failures = 0;
+                self.last_error = None;
+            }
+            None => {
+                self.successes = 0;
+                self.failures += 1;
+            }
+        }
+    }
+
+    // 记录一个被断开的连接, 足够多的不同连接被断开时记为一次失败
+    fn disconnected(&mut self, session: &str, now: Instant) -> bool {
+        let window = Duration::from_secs(DISCONNECT_SECS);
+        while let Some((time, _)) = self.disconnects.front() {
+            if now.saturating_duration_since(*time) < window {
+                break;
+            }
+            self.disconnects.pop_front();
+        }
+        if !self.disconnects.iter().any(|(_, s)| s == session) {
+            self.disconnects.push_back((now, session.to_string()));
+        }
+        if self.disconnects.len() < DISCONNECT_SESSIONS {
+            return false;
+        }
+        self.disconnects.clear();
+        self.record(None);
+        true
+    }
+}
+
+// 矿池切换记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolSwitch {
+    pub time: String,
+    pub from: String,
+    pub to: String,
+    pub reason: String,
+}
+
+// 优先使用优先级最高的矿池, 不可用时切到评分最高的备用矿池,
+// 高优先级的矿池恢复稳定后切回。已连接的矿机通过 subscribe 收到切换通知。
+#[derive(Debug)]
+pub struct PoolHealth {
+    name: String,
+    urls: Vec<Option<PoolUrl>>,
+    tls: TlsOptions,
+    pools: Mutex<Vec<PoolStatus>>,
+    active: watch::Sender<usize>,
+    events: Mutex<VecDeque<PoolSwitch>>,
+}
+
+fn choose(pools: &[PoolStatus], active: usize) -> usize {
+    let current = pools.get(active).filter(|p| p.healthy());
+    pools
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| match current {
+            // 当前矿池正常时, 只切回连续正常的高优先级矿池
+            Some(current) => {
+                p.priority < current.priority
+                    && p.healthy()
+                    && p.successes >= FAILBACK_CHECKS
+            }
+            None => p.healthy(),
+        })
+        .min_by(|(_, a), (_, b)| {
+            a.priority
+                .cmp(&b.priority)
+                .then(b.score().total_cmp(&a.score()))
+        })
+        .map(|(idx, _)| idx)
+        .unwrap_or(active)
+}
+
+// 第一个尝试的矿池: 在与当前矿池同优先级的正常矿池中按权重随机选择
+fn pick(pools: &[PoolStatus], active: usize) -> usize {
+    let priority = match pools.get(active) {
+        Some(pool) => pool.priority,
+        None => return active,
+    };
+    let candidates: Vec<(usize, u32)> = pools
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.priority == priority && p.healthy())
+        .map(|(idx, p)| (idx, p.weight))
+        .collect();
+    let total: u32 = candidates.iter().map(|(_, w)| w).sum();
+    if total == 0 {
+        return active;
+    }
+    let mut n = rand::Rng::gen_range(&mut rand::thread_rng(), 0..total);
+    for (idx, weight) in candidates {
+        if n < weight {
+            return idx;
+        }
+        n -= weight;
+    }
+    active
+}
+
+async fn append_event(path: &str, line: &str) -> Result<()> {
+    tokio::fs::create_dir_all(LOG_DIR).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+// 只检查 TCP 是否能连上, 返回耗时
+async fn probe(address: &str) -> Result<u64> {
+    let url = PoolUrl::parse(address)?;
+    let start = Instant::now();
+    dial::connect(&url.dial_address()).await?;
+    Ok(start.elapsed().as_millis() as u64)
+}
+
+impl PoolHealth {
+    pub fn new(name: &str, addresses: &[String], tls: TlsOptions) -> Self {
+        let urls: Vec<Option<PoolUrl>> =
+            addresses.iter().map(|a| PoolUrl::parse(a).ok()).collect();
+        let pools: Vec<PoolStatus> = addresses
+            .iter()
+            .zip(urls.iter())
+            .enumerate()
+            .map(|(idx, (address, url))| PoolStatus {
+                address: address.clone(),
+                priority: url
+                    .as_ref()
+                    .and_then(|u| u.priority)
+                    .unwrap_or(if idx == 0 { 0 } else { 1 }),
+                weight: url.as_ref().map(|u| u.weight()).unwrap_or(1),
+                ..Default::default()
+            })
+            .collect();
+        // 从优先级最高的矿池开始
+        let first =
+            choose(&pools, usize::MAX).min(pools.len().saturating_sub(1));
+        let (active, _) = watch::channel(first);
+        Self {
+            name: name.to_string(),
+            urls,
+            tls,
+            pools: Mutex::new(pools),
+            active,
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn active(&self) -> usize { *self.active.borrow() }
+
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.active.subscribe()
+    }
+
+    pub fn status(&self) -> Vec<PoolStatus> {
+        self.pools.lock().map(|p| p.clone()).unwrap_or_default()
+    }
+
+    pub fn events(&self) -> Vec<PoolSwitch> {
+        self.events
+            .lock()
+            .map(|e| e.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn address(&self, idx: usize) -> Option<String> {
+        self.pools.lock().ok()?.get(idx).map(|p| p.address.clone())
+    }
+
+    pub fn url(&self, idx: usize) -> Option<&PoolUrl> {
+        self.urls.get(idx)?.as_ref()
+    }
+
+    // 按矿池自己的设置连接第 idx 个矿池
+    pub async fn connect_to(&self, idx: usize) -> Result<Box<dyn PoolStream>> {
+        match self.url(idx) {
+            Some(url) => connect_pool(url, &self.tls).await,
+            None => bail!("矿池地址设置错误 {:?}", self.address(idx)),
+        }
+    }
+
+    // 记录失败原因后再记录结果
+    pub fn report_error(&self, idx: usize, e: &anyhow::Error, reason: &str) {
+        if let Ok(mut pools) = self.pools.lock() {
+            if let Some(pool) = pools.get_mut(idx) {
+                pool.last_error = Some(e.to_string());
+            }
+        }
+        self.report(idx, None, reason);
+    }
+
+    // 记录一次检查或连接的结果, 需要时切换矿池
+    pub fn report(&self, idx: usize, latency_ms: Option<u64>, reason: &str) {
+        let mut pools = match self.pools.lock() {
+            Ok(pools) => pools,
+            Err(_) => return,
+        };
+        if let Some(pool) = pools.get_mut(idx) {
+            pool.record(latency_ms);
+        }
+        self.rechoose(&pools, reason);
+    }
+
+    // 矿工与矿池的连接断开, session 为矿工名
+    pub fn report_disconnect(&self, idx: usize, session: &str) {
+        let mut pools = match self.pools.lock() {
+            Ok(pools) => pools,
+            Err(_) => return,
+        };
+        let failed = match pools.get_mut(idx) {
+            Some(pool) => pool.disconnected(session, Instant::now()),
+            None => false,
+        };
+        if failed {
+            self.rechoose(&pools, "多个矿工被矿池断开");
+        }
+    }
+
+    fn rechoose(&self, pools: &[PoolStatus], reason: &str) {
+        let from = self.active();
+        let to = choose(pools, from);
+        if from != to {
+            let reason = if pools[to].priority < pools[from].priority {
+                "主矿池恢复"
+            } else {
+                reason
+            };
+            self.switch(pools, from, to, reason);
+        }
+    }
+
+    fn switch(
+        &self, pools: &[PoolStatus], from: usize, to: usize, reason: &str,
+    ) {
+        let event = PoolSwitch {
+            time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            from: pools[from].address.clone(),
+            to: pools[to].address.clone(),
+            reason: reason.to_string(),
+        };
+        tracing::warn!(
+            "矿池切换 {} -> {} 原因: {}",
+            event.from,
+            event.to,
+            event.reason
+        );
+
+        // 每个代理单独一个记录文件, 在后台写入, 不阻塞持有锁的调用方
+        let path = format!("{}pool_events_{}.log", LOG_DIR, self.name);
+        let line = format!(
+            "{} {} -> {} {}\n",
+            event.time, event.from, event.to, event.reason
+        );
+        tokio::spawn(async move {
+            if let Err(e) = append_event(&path, &line).await {
+                tracing::warn!("无法写入矿池切换记录 {} {}", path, e);
+            }
+        });
+
+        if let Ok(mut events) = self.events.lock() {
+            if events.len() >= EVENTS {
+                events.pop_front();
+            }
+            events.push_back(event);
+        }
+        self.active.send_replace(to);
+    }
+
+    // 从选中的矿池开始依次连接, 返回连上的矿池序号
+    pub async fn connect(&self) -> Result<(usize, Box<dyn PoolStream>)> {
+        let pools = self.status();
+        let len = pools.len();
+        let active = pick(&pools, self.active());
+        let mut errors = vec![];
+        for idx in (active..len).chain(0..active) {
+            let start = Instant::now();
+            match self.connect_to(idx).await {
+                Ok(stream) => {
+                    let latency = start.elapsed().as_millis() as u64;
+                    self.report(idx, Some(latency), "连接成功");
+                    return Ok((idx, stream));
+                }
+                Err(e) => {
+                    debug!("{}", e);
+                    self.report_error(idx, &e, "连接失败");
+                    errors.push(e.to_string());
+                }
+            }
+        }
+
+        bail!("所有矿池均不可链接。请修改后重试: {}", errors.join("; "));
+    }
+}
+
+// 后台定时检查所有矿池
+pub async fn check_pools(proxy: Arc<Proxy>) -> Result<()> {
+    let health = proxy.pool_health.clone();
+    let mut interval = time::interval(Duration::from_secs(CHECK_SECS));
+    info!("矿池健康检查启动");
+
+    loop {
+        interval.tick().await;
+        for (idx, pool) in health.status().iter().enumerate() {
+            match probe(&pool.address).await {
+                Ok(latency) => health.report(idx, Some(latency), "定时检查"),
+                Err(e) => {
+                    debug!("矿池 {} 检查失败 {}", pool.address, e);
+                    health.report_error(idx, &e, "定时检查失败");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_choose_pool() {
+    let mut pools = vec![PoolStatus::default(); 3];
+    pools[1].priority = 1;
+    pools[2].priority = 1;
+    assert_eq!(choose(&pools, 0), 0);
+
+    // 主矿池不可用, 切到评分高的备用矿池
+    pools[0].record(None);
+    pools[0].record(None);
+    pools[1].record(Some(500));
+    pools[2].record(Some(50));
+    assert_eq!(choose(&pools, 0), 2);
+
+    // 备用矿池正常时不来回切换
+    pools[1].latency_ms = 10;
+    assert_eq!(choose(&pools, 2), 2);
+
+    // 主矿池连续正常几次后才切回
+    pools[0].record(Some(20));
+    assert_eq!(choose(&pools, 2), 2);
+    pools[0].record(Some(20));
+    pools[0].record(Some(20));
+    assert_eq!(choose(&pools, 2), 0);
+
+    // 高优先级的矿池都不可用时用优先级次高的
+    pools[0].priority = 2;
+    pools[1].record(None);
+    pools[1].record(None);
+    pools[2].record(None);
+    pools[2].record(None);
+    assert_eq!(choose(&pools, 2), 0);
+}
+
+#[test]
+fn test_pool_disconnects() {
+    let start = Instant::now();
+    let mut pools = vec![PoolStatus::default(); 2];
+    pools[1].priority = 1;
+
+    // 同一个矿工反复被断开不算失败
+    for secs in 0..20 {
+        let now = start + Duration::from_secs(secs);
+        assert!(!pools[0].disconnected("rig1", now));
+    }
+    // 时间窗口内不同的矿工不够多也不算
+    for rig in ["rig2", "rig3", "rig4"] {
+        assert!(!pools[0].disconnected(rig, start));
+    }
+    assert_eq!(pools[0].failures, 0);
+    assert_eq!(choose(&pools, 0), 0);
+
+    // 过了时间窗口的记录不再计数
+    let later = start + Duration::from_secs(DISCONNECT_SECS + 30);
+    assert!(!pools[0].disconnected("rig5", later));
+    assert_eq!(pools[0].disconnects.len(), 1);
+    assert_eq!(choose(&pools, 0), 0);
+
+    // 很多矿工同时被断开才记为一次失败
+    let mut failed = 0;
+    for rig in 0..DISCONNECT_SESSIONS * 2 {
+        if pools[0].disconnected(&format!("farm{}", rig), later) {
+            failed += 1;
+        }
+    }
+    assert_eq!(failed, 2);
+    assert_eq!(choose(&pools, 0), 1);
+}
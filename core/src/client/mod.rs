@@ -1,29 +1,39 @@
+pub mod aggregate;
+pub mod cert;
 pub mod encry;
 
 pub mod fee;
+pub mod dial;
+pub mod downstream;
 pub mod handle_stream;
 pub mod handle_stream_all;
 pub mod handle_stream_nofee;
+pub mod health;
 pub mod monitor;
+pub mod mux;
+pub mod pool_url;
 pub mod pools;
 pub mod tcp;
 pub mod tls;
+pub mod tls_client;
+pub mod traffic;
+pub mod tunnel;
+pub mod upstream;
+pub mod vardiff;
+pub mod via;
 
 
 use tokio::sync::broadcast::{Receiver,error::TryRecvError};
 use anyhow::{anyhow,bail,Result};
 
-use native_tls::TlsConnector;
 use rand::prelude::SliceRandom;
 use serde::Serialize;
 use std::{
     collections::VecDeque,
     fmt::Debug,
-    net::{SocketAddr, ToSocketAddrs},
+    net::SocketAddr,
     sync::Arc,
-    time::Duration,
 };
-use tokio_native_tls::TlsStream;
 
 use tracing::debug;
 
@@ -42,86 +52,59 @@ use crate::{
         ethjson::{
             EthClientObject, EthClientRootObject, EthClientWorkerObject,
         },
+        dialect_from_scheme,
         rpc::eth::{Client, ClientWithWorkerName, ServerRpc},
-        CLIENT_LOGIN, CLIENT_SUBHASHRATE,
+        CLIENT_LOGIN, CLIENT_SUBHASHRATE, PROTOCOL,
     },
+    client::{pool_url::PoolUrl, tls_client::TlsOptions},
     proxy::Proxy,
     state::Worker,
-    util::{config::Settings, get_eth_wallet},
+    util::config::{FeeDestination, Settings},
     SPLIT,
 };
 
 pub const TCP: i32 = 1;
 pub const SSL: i32 = 2;
 
+// 旧的连接方式整个列表使用同一种传输方式, 返回 (传输方式, dial 地址)
+fn pools_with_one_transport(address: &[String]) -> Result<(i32, Vec<String>)> {
+    let urls = address
+        .iter()
+        .map(|addr| PoolUrl::parse(addr))
+        .collect::<Result<Vec<_>>>()?;
+    let stream_type = match urls.first() {
+        Some(url) => url.stream_type(),
+        None => bail!("中转池地址设置存在错误请检查"),
+    };
+    if urls.iter().any(|url| url.stream_type() != stream_type) {
+        bail!("矿池列表中混用了 tcp 和 ssl, 请使用 connect_pool 逐个连接");
+    }
+    Ok((stream_type, urls.iter().map(|url| url.dial_address()).collect()))
+}
+
 // 从配置文件返回 连接矿池类型及连接地址
 pub fn get_pool_ip_and_type(
     config: &crate::util::config::Settings,
 ) -> Result<(i32, Vec<String>)> {
-    //FIX 兼容SSL
-    if !config.pool_address.is_empty() {
-        let mut pro = TCP;
-        let address = config.pool_address.clone();
-        let mut pools = vec![];
-        for addr in address.iter() {
-            let new_pool_url: Vec<&str> = addr.split("//").collect();
-            if let Some(protocol) = new_pool_url.get(0) {
-                let p = protocol.to_string().to_lowercase();
-                if p != "tcp:" && p != "ssl:" {
-                    bail!("代理矿池{} 不支持的服务类型 {}", addr, *protocol);
-                }
-
-                if p == "tcp:" {
-                    pro = TCP;
-                }
-
-                if p == "ssl:" {
-                    pro = SSL;
-                }
-            }
-            if let Some(url) = new_pool_url.get(1) {
-                pools.push(url.to_string());
-            };
-        }
-        Ok((pro, pools))
-    } else {
-        bail!("中转池地址设置存在错误请检查");
-    }
+    pools_with_one_transport(&config.pool_address)
 }
 
 pub fn get_pool_ip_and_type_from_vec(
     config: &Vec<String>,
 ) -> Result<(i32, Vec<String>)> {
-    //FIX 兼容SSL
-    if !config.is_empty() {
-        let mut pro = TCP;
-        let address = config.clone();
-        let mut pools = vec![];
-        for addr in address.iter() {
-            let new_pool_url: Vec<&str> = addr.split("//").collect();
-            if let Some(protocol) = new_pool_url.get(0) {
-                let p = protocol.to_string().to_lowercase();
-                if p != "tcp:" && p != "ssl:" {
-                    bail!("代理矿池{} 不支持的服务类型 {}", addr, *protocol);
-                }
-
-                if p == "tcp:" {
-                    pro = TCP;
-                }
-
-                if p == "ssl:" {
-                    pro = SSL;
-                }
-            }
-            if let Some(url) = new_pool_url.get(1) {
-                pools.push(url.to_string());
-            };
-        }
+    pools_with_one_transport(config)
+}
 
-        Ok((pro, pools))
-    } else {
-        bail!("中转池地址设置存在错误请检查");
-    }
+// 每个矿池地址对应的上游协议, 顺序与 get_pool_ip_and_type_from_vec 一致
+pub fn get_pool_dialects(config: &[String]) -> Vec<Option<PROTOCOL>> {
+    config
+        .iter()
+        .filter_map(|addr| {
+            let new_pool_url: Vec<&str> = addr.splitn(2, "//").collect();
+            new_pool_url.get(1)?;
+            Some(dialect_from_scheme(new_pool_url[0]).unwrap_or(None))
+        })
+        .collect()
 }
 
 // 从配置文件返回 连接矿池类型及连接地址
@@ -133,7 +116,7 @@ pub fn get_pool_ip_and_type_for_proxyer(
         let address = config.share_address.clone();
         let mut pools = vec![];
         for addr in address.iter() {
-            let new_pool_url: Vec<&str> = addr.split("//").collect();
+            let new_pool_url: Vec<&str> = addr.splitn(2, "//").collect();
             if let Some(protocol) = new_pool_url.get(0) {
                 let p = protocol.to_string().to_lowercase();
                 if p != "tcp:" {
@@ -151,165 +134,57 @@ pub fn get_pool_ip_and_type_for_proxyer(
     }
 }
 //vs.choose(&mut rand::thread_rng())
-pub fn get_pool_random_stream(
+pub async fn get_pool_random_stream(
     pool_tcp_address: &Vec<String>,
-) -> Option<(std::net::TcpStream, SocketAddr)> {
-    for _ in 0..pool_tcp_address.len() {
-        let address = match pool_tcp_address.choose(&mut rand::thread_rng()) {
-            Some(s) => s,
-            None => continue,
-        };
-
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("矿池地址格式化失败");
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
-                continue;
-            }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
-                continue;
-            }
-        };
-        std_stream.set_nonblocking(true).unwrap();
-        return Some((std_stream, addr));
-    }
-
-    None
+) -> Option<(TcpStream, SocketAddr)> {
+    let mut pools = pool_tcp_address.clone();
+    pools.shuffle(&mut rand::thread_rng());
+    get_pool_stream(&pools).await
 }
 
-pub fn get_pool_stream(
+pub async fn get_pool_stream(
     pool_tcp_address: &Vec<String>,
-) -> Option<(std::net::TcpStream, SocketAddr)> {
+) -> Option<(TcpStream, SocketAddr)> {
     for address in pool_tcp_address {
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("矿池地址格式化失败 {}", address);
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
+        match dial::connect(address).await {
+            Ok(res) => return Some(res),
+            Err(e) => {
+                debug!("{} 切换备用矿池", e);
                 continue;
             }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
-                continue;
-            }
-        };
-        std_stream.set_nonblocking(true).unwrap();
-        return Some((std_stream, addr));
+        }
     }
 
     None
 }
 
-pub async fn get_pool_stream_with_tls(
-    pool_tcp_address: &Vec<String>,
-) -> Option<(
-    tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-    SocketAddr,
-)> {
-    for address in pool_tcp_address {
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("矿池地址格式化失败 {}", address);
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} {} 访问不通。切换备用矿池！！！！", name,
-                // address);
-                continue;
-            }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(straem) => straem,
-            Err(_) => {
-                //debug!("{} {} 访问不通。切换备用矿池！！！！", name,
-                // address);
-                continue;
-            }
-        };
-
-        std_stream.set_nonblocking(true).unwrap();
-
-        let stream = match TcpStream::from_std(std_stream) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} {} 访问不通。切换备用矿池！！！！", name,
-                // address);
-                continue;
-            }
-        };
-
-        stream.set_nodelay(true).unwrap();
-
-        let cx = match TlsConnector::builder()
-            .danger_accept_invalid_certs(true)
-            .danger_accept_invalid_hostnames(true)
-            .min_protocol_version(Some(native_tls::Protocol::Tlsv11))
-            //.disable_built_in_roots(true)
-            .build()
-        {
-            Ok(con) => con,
-            Err(_) => {
-                //debug!("{} {} SSL 校验失败！！！！", name, address);
-                continue;
-            }
-        };
-
-        let cx = tokio_native_tls::TlsConnector::from(cx);
-
-        let domain: Vec<&str> = address.split(":").collect();
-        let server_stream = match cx.connect(domain[0], stream).await {
-            Ok(stream) => stream,
-            Err(_err) => {
-                //debug!("{} {} SSL 链接失败！！！！ {:?}", name, address,
-                // err);
-                continue;
+// 按矿池地址自己的传输方式和 TLS 设置连接
+pub async fn connect_pool(
+    url: &PoolUrl, tls: &TlsOptions,
+) -> Result<Box<dyn PoolStream>> {
+    let (stream, _) = dial::connect(&url.dial_address()).await?;
+    if !url.ssl {
+        return Ok(Box::new(stream));
+    }
+    Ok(Box::new(tls_client::connect(url, tls, stream).await?))
+}
+
+// 依次连接列表中的矿池, 返回第一个连上的。全部失败时带上每个矿池的原因
+pub async fn connect_pools(
+    address: &[String], tls: &TlsOptions,
+) -> Result<(PoolUrl, Box<dyn PoolStream>)> {
+    let mut errors = vec![];
+    for addr in address {
+        let url = PoolUrl::parse(addr)?;
+        match connect_pool(&url, tls).await {
+            Ok(stream) => return Ok((url, stream)),
+            Err(e) => {
+                debug!("{} 切换备用矿池", e);
+                errors.push(e.to_string());
             }
-        };
-
-        //debug!("{} conteactd to {}", name, address);
-        return Some((server_stream, addr));
+        }
     }
-
-    None
+    bail!("所有矿池均不可链接。请修改后重试: {}", errors.join("; "));
 }
 
 // pub async fn write_encrypt_socket<W, T>(
@@ -523,64 +398,51 @@ where
     )
     .await
 }
+// 矿池连接。TCP、TLS 和聚合连接统一按这个类型处理, 切换矿池时可以直接替换
+pub trait PoolStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PoolStream for T {}
+
 pub async fn handle_tcp_random<R, W>(
     worker: &mut Worker,
     worker_r: tokio::io::BufReader<tokio::io::ReadHalf<R>>,
-    worker_w: WriteHalf<W>, pools: &Vec<String>, proxy: Arc<Proxy>,
-    stream_type: i32, is_encrypted: bool,
+    worker_w: WriteHalf<W>, proxy: Arc<Proxy>,
 ) -> Result<()>
 where
     R: AsyncRead,
     W: AsyncWrite,
 {
-    if stream_type == TCP {
-        let (outbound, _) = match crate::client::get_pool_stream(&pools) {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
-
-        let stream = tokio::net::TcpStream::from_std(outbound)?;
-        stream.set_nodelay(true)?;
-        let (pool_r, pool_w) = tokio::io::split(stream);
-        let pool_r = tokio::io::BufReader::new(pool_r);
-
-        handle_stream::handle_stream(
-            worker,
-            worker_r,
-            worker_w,
-            pool_r,
-            pool_w,
-            proxy,
-            is_encrypted,
-        )
-        .await
-    } else if stream_type == SSL {
-        let (stream, _) =
-            match crate::client::get_pool_stream_with_tls(&pools).await {
-                Some((stream, addr)) => (stream, addr),
-                None => {
-                    bail!("所有TCP矿池均不可链接。请修改后重试");
-                }
-            };
-
-        let (pool_r, pool_w) = tokio::io::split(stream);
-        let pool_r = tokio::io::BufReader::new(pool_r);
-
-        handle_stream::handle_stream(
+    let aggregate = {
+        let config = proxy.config.read().await;
+        if config.aggregate != 0 {
+            Some(proxy.aggregator.connect(&config, proxy.pool_health.clone()))
+        } else {
+            None
+        }
+    };
+    if let Some(stream) = aggregate {
+        return handle_stream::handle_stream(
             worker,
             worker_r,
             worker_w,
-            pool_r,
-            pool_w,
+            Box::new(stream),
             proxy,
-            is_encrypted,
+            None,
         )
-        .await
-    } else {
-        panic!("达到了无法达到的分支");
+        .await;
     }
+
+    // 从当前使用的矿池开始连接, 连上哪个就使用哪个矿池配置的协议
+    let (idx, stream) = proxy.pool_health.connect().await?;
+    handle_stream::handle_stream(
+        worker,
+        worker_r,
+        worker_w,
+        stream,
+        proxy,
+        Some(idx),
+    )
+    .await
 }
 
 // pub async fn handle_tcp_timer<R, W>(
@@ -644,14 +506,13 @@ where
     R: AsyncRead,
     W: AsyncWrite,
 {
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (stream, _) = match crate::client::get_pool_stream(pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
     handle_tcp(
         worker,
         worker_queue,
@@ -711,14 +572,13 @@ where
             }
         };
 
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (stream, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
 
     handle_tcp_all(
         worker,
@@ -800,8 +660,8 @@ where
 pub async fn submit_fee_hashrate(
     config: &Settings, hashrate: u64,
 ) -> Result<()> {
-    let (stream, _) =
-        match crate::client::get_pool_stream(&config.share_address) {
+    let (outbound, _) =
+        match crate::client::get_pool_stream(&config.share_address).await {
             Some((stream, addr)) => (stream, addr),
             None => {
                 tracing::error!("所有TCP矿池均不可链接。请修改后重试");
@@ -809,7 +669,6 @@ pub async fn submit_fee_hashrate(
             }
         };
 
-    let outbound = TcpStream::from_std(stream)?;
     let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
     let _proxy_r = tokio::io::BufReader::new(proxy_r);
 
@@ -834,124 +693,26 @@ pub async fn submit_fee_hashrate(
     Ok(())
 }
 
-pub async fn submit_develop_hashrate(
-    _config: &Settings, hashrate: u64,
-) -> Result<()> {
-    let stream = match pools::get_develop_pool_stream().await {
-        Ok(s) => s,
-        Err(e) => return Err(e),
-    };
-
-    let outbound = TcpStream::from_std(stream)?;
-    let (_, mut proxy_w) = tokio::io::split(outbound);
-
-    let mut hostname = String::from("develop_");
-    let name = hostname::get()?;
-    hostname += name.to_str().unwrap();
-
-    let login = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![get_eth_wallet(), "x".into()],
-        worker: hostname.clone(),
-    };
-
-    write_to_socket(&mut proxy_w, &login, &hostname).await;
-    //计算速率
-    let submit_hashrate = ClientWithWorkerName {
-        id: CLIENT_SUBHASHRATE,
-        method: "eth_submitHashrate".into(),
-        params: [format!("0x{:x}", hashrate), hex::encode(hostname.clone())]
-            .to_vec(),
-        worker: hostname.clone(),
-    };
-    write_to_socket(&mut proxy_w, &submit_hashrate, &hostname).await;
-    Ok(())
-}
-
 // new -----------------------------------------------------------------
 pub async fn proxy_pool_login(
-    config: &Settings, _hostname: String,
-) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
-    //TODO 这里要兼容SSL矿池
-    let (_stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(
-            &config.share_address,
-        ) {
-            Ok((stream, addr)) => (stream, addr),
-            Err(_e) => {
-                tracing::error!("所有TCP矿池均不可链接。请修改后重试");
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
-
-    let (stream, _) = match crate::client::get_pool_stream(&pools) {
-        Some((stream, addr)) => (stream, addr),
-        None => {
-            bail!("所有TCP矿池均不可链接。请修改后重试");
-        }
-    };
-    let outbound = TcpStream::from_std(stream)?;
-    outbound.set_nodelay(true)?;
-    let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
-
-    let s = config.get_share_name().unwrap();
-
-    let login = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![config.share_wallet.clone(), "x".into()],
-        worker: s.clone(),
-    };
-
-    match write_to_socket(&mut proxy_w, &login, &s).await {
-        Ok(_) => {}
-        Err(e) => {
-            tracing::error!("Error writing Socket {:?}", login);
-            return Err(e);
-        }
-    }
-
-    Ok((proxy_lines, proxy_w))
-}
-
-pub async fn proxy_pool_login_with_ssl(
-    config: &Settings, _hostname: String,
+    config: &Settings, dest: &FeeDestination,
 ) -> Result<(
-    Lines<BufReader<ReadHalf<tokio_native_tls::TlsStream<TcpStream>>>>,
-    WriteHalf<TlsStream<TcpStream>>,
+    Lines<BufReader<ReadHalf<Box<dyn PoolStream>>>>,
+    WriteHalf<Box<dyn PoolStream>>,
 )> {
-    let (_stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(
-            &config.share_address,
-        ) {
-            Ok((stream, addr)) => (stream, addr),
-            Err(_e) => {
-                tracing::error!("所有TCP矿池均不可链接。请修改后重试");
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
-
-    let (stream, _) =
-        match crate::client::get_pool_stream_with_tls(&pools).await {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
-
-    let (proxy_r, mut proxy_w) = tokio::io::split(stream);
+    let (url, outbound) =
+        connect_pools(&dest.address, &config.tls_options()?).await?;
+    let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
     let proxy_r = tokio::io::BufReader::new(proxy_r);
     let proxy_lines = proxy_r.lines();
 
-    let s = config.get_share_name().unwrap();
+    let (wallet, s, password) =
+        url.login(&dest.wallet, &config.fee_worker_name(&dest.worker)?, "x");
 
     let login = ClientWithWorkerName {
         id: CLIENT_LOGIN,
         method: "eth_submitLogin".into(),
-        params: vec![config.share_wallet.clone(), "x".into()],
+        params: vec![wallet, password],
         worker: s.clone(),
     };
 
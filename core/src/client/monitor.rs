@@ -1,49 +1,567 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{bail, Result};
-
-use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     select,
+    task::JoinSet,
+    time,
 };
 
-use crate::client::{self_write_socket_byte, write_to_socket_byte};
+use crate::client::{
+    dial, mux, self_write_socket_byte,
+    traffic::{Counted, Traffic},
+    tunnel::{self, Direction},
+    write_to_socket_byte,
+};
 
-pub async fn accept_monitor_tcp(port: i32, server: SocketAddr) -> Result<()> {
-    let address = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(address.clone()).await?;
-    info!("😄 Accepting Monitor Tcp On: {}", &address);
+// monitor 的配置文件, YAML 或 TOML, 按扩展名识别。例如
+// status: 127.0.0.1:9100
+// listeners:
+//   - name: eth
+//     bind: 8888
+//     servers: [a.example.com:14444, b.example.com:14444]
+//     psk: "********"
+//     mux: 2
+//     compress: true
+//   - name: etc
+//     bind: 0.0.0.0:8889
+//     servers: [a.example.com:24444]
+//     mode: legacy
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MonitorConfig {
+    // 状态接口的监听地址, 为空时不开启
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListenerConfig {
+    #[serde(default)]
+    pub name: String,
+    // 本地监听地址, 只写端口时监听 0.0.0.0
+    pub bind: String,
+    // 中转的加密端口, 当前的连不上时按顺序换下一个
+    pub servers: Vec<String>,
+    // tunnel 加密隧道, legacy 旧的明文协议, 为空时设置了 psk 就用隧道
+    #[serde(default)]
+    pub mode: String,
+    #[serde(default)]
+    pub psk: String,
+    // 多路复用的隧道数, 全部矿机共用这几条隧道, 0 为每台矿机单独连接
+    #[serde(default)]
+    pub mux: usize,
+    // 多路复用时压缩数据
+    #[serde(default)]
+    pub compress: bool,
+}
+
+const MAX_MUX: usize = 16;
+// 多路复用隧道断开后重连的最长间隔
+const MUX_RETRY_MAX_SECS: u64 = 30;
+
+fn bind_address(bind: &str) -> String {
+    if bind.contains(':') {
+        bind.to_string()
+    } else {
+        format!("0.0.0.0:{}", bind)
+    }
+}
+
+impl ListenerConfig {
+    pub fn name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.bind
+        } else {
+            &self.name
+        }
+    }
+
+    pub fn tunnel(&self) -> bool {
+        self.mode == "tunnel" || (self.mode.is_empty() && !self.psk.is_empty())
+    }
+
+    fn mode_name(&self) -> &'static str {
+        if self.tunnel() {
+            "tunnel"
+        } else {
+            "legacy"
+        }
+    }
+}
+
+impl MonitorConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let mut s = config::Config::default();
+        s.merge(config::File::from(Path::new(path)))?;
+        let config: Self = s.try_into()?;
+        config.check()?;
+        Ok(config)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            bail!("没有设置监听端口 listeners");
+        }
+        let mut binds = vec![];
+        for listener in &self.listeners {
+            let bind = bind_address(&listener.bind);
+            if bind.parse::<SocketAddr>().is_err() {
+                bail!("监听地址格式错误 {}", listener.bind);
+            }
+            if binds.contains(&bind) {
+                bail!("监听地址重复 {}", bind);
+            }
+            binds.push(bind);
+
+            if listener.servers.iter().all(|s| s.trim().is_empty()) {
+                bail!("{} 没有设置服务器地址 servers", listener.name());
+            }
+            match listener.mode.as_str() {
+                "" | "tunnel" | "legacy" => {}
+                mode => bail!("{} 不支持的加密方式 {}", listener.name(), mode),
+            }
+            if listener.tunnel() && listener.psk.len() < 8 {
+                bail!("{} 隧道密钥 psk 长度不能小于 8 位", listener.name());
+            }
+            if listener.mux > 0 && !listener.tunnel() {
+                bail!("{} 多路复用 mux 需要使用加密隧道", listener.name());
+            }
+            if listener.mux > MAX_MUX {
+                bail!("{} 多路复用隧道数不能超过 {}", listener.name(), MAX_MUX);
+            }
+            if listener.compress && listener.mux == 0 {
+                bail!("{} 压缩 compress 需要开启多路复用 mux", listener.name());
+            }
+        }
+        if !self.status.is_empty()
+            && bind_address(&self.status).parse::<SocketAddr>().is_err()
+        {
+            bail!("状态接口地址格式错误 {}", self.status);
+        }
+        Ok(())
+    }
+}
+
+struct Listener {
+    config: ListenerConfig,
+    // 当前使用的服务器下标
+    active: AtomicUsize,
+    connections: AtomicU64,
+    // 已断开的矿机转发的字节数
+    closed: Traffic,
+    // 多路复用的会话, 没有开启时为空
+    sessions: Vec<Arc<mux::Session>>,
+}
+
+struct Rig {
+    addr: SocketAddr,
+    listener: usize,
+    server: String,
+    since: Instant,
+    traffic: Arc<Traffic>,
+}
+
+pub struct MonitorState {
+    listeners: Vec<Listener>,
+    rigs: Mutex<HashMap<u64, Rig>>,
+    next_id: AtomicU64,
+    started: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListenerStatus {
+    pub name: String,
+    pub bind: String,
+    pub mode: &'static str,
+    pub server: String,
+    pub rigs: usize,
+    pub connections: u64,
+    // 已连接的多路复用隧道数
+    pub tunnels: usize,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RigStatus {
+    pub addr: String,
+    pub listener: String,
+    pub server: String,
+    pub online_secs: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonitorStatus {
+    pub uptime_secs: u64,
+    pub listeners: Vec<ListenerStatus>,
+    pub rigs: Vec<RigStatus>,
+}
+
+// 矿机断开时从列表中移除, 流量计入监听端口
+struct RigGuard {
+    state: Arc<MonitorState>,
+    id: u64,
+}
+
+impl Drop for RigGuard {
+    fn drop(&mut self) {
+        let rig = match self.state.rigs.lock() {
+            Ok(mut rigs) => rigs.remove(&self.id),
+            Err(_) => None,
+        };
+        if let Some(rig) = rig {
+            self.state.listeners[rig.listener]
+                .closed
+                .add(rig.traffic.get());
+        }
+    }
+}
+
+impl MonitorState {
+    pub fn new(config: &MonitorConfig) -> Self {
+        Self {
+            listeners: config
+                .listeners
+                .iter()
+                .map(|config| Listener {
+                    config: config.clone(),
+                    active: AtomicUsize::new(0),
+                    connections: AtomicU64::new(0),
+                    closed: Traffic::default(),
+                    sessions: (0..config.mux)
+                        .map(|_| mux::Session::client())
+                        .collect(),
+                })
+                .collect(),
+            rigs: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    fn register(
+        self: &Arc<Self>, listener: usize, addr: SocketAddr, server: &str,
+    ) -> (RigGuard, Arc<Traffic>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let traffic = Arc::new(Traffic::default());
+        if let Ok(mut rigs) = self.rigs.lock() {
+            rigs.insert(id, Rig {
+                addr,
+                listener,
+                server: server.to_string(),
+                since: Instant::now(),
+                traffic: traffic.clone(),
+            });
+        }
+        (
+            RigGuard {
+                state: self.clone(),
+                id,
+            },
+            traffic,
+        )
+    }
+
+    pub fn status(&self) -> MonitorStatus {
+        let mut listeners: Vec<_> = self
+            .listeners
+            .iter()
+            .map(|l| {
+                let (bytes_up, bytes_down) = l.closed.get();
+                let active = l.active.load(Ordering::Relaxed);
+                ListenerStatus {
+                    name: l.config.name().to_string(),
+                    bind: bind_address(&l.config.bind),
+                    mode: l.config.mode_name(),
+                    server: l
+                        .config
+                        .servers
+                        .get(active)
+                        .cloned()
+                        .unwrap_or_default(),
+                    rigs: 0,
+                    connections: l.connections.load(Ordering::Relaxed),
+                    tunnels: l
+                        .sessions
+                        .iter()
+                        .filter(|s| s.connected())
+                        .count(),
+                    bytes_up,
+                    bytes_down,
+                }
+            })
+            .collect();
+
+        let mut rigs = vec![];
+        if let Ok(online) = self.rigs.lock() {
+            for rig in online.values() {
+                let (bytes_up, bytes_down) = rig.traffic.get();
+                let listener = &mut listeners[rig.listener];
+                listener.rigs += 1;
+                listener.bytes_up += bytes_up;
+                listener.bytes_down += bytes_down;
+                rigs.push(RigStatus {
+                    addr: rig.addr.to_string(),
+                    listener: listener.name.clone(),
+                    server: rig.server.clone(),
+                    online_secs: rig.since.elapsed().as_secs(),
+                    bytes_up,
+                    bytes_down,
+                });
+            }
+        }
+        rigs.sort_by(|a, b| {
+            a.listener.cmp(&b.listener).then(a.addr.cmp(&b.addr))
+        });
+
+        MonitorStatus {
+            uptime_secs: self.started.elapsed().as_secs(),
+            listeners,
+            rigs,
+        }
+    }
+}
+
+// 启动全部监听端口和状态接口, 任意一个退出时返回
+pub async fn run(config: MonitorConfig) -> Result<()> {
+    config.check()?;
+    let state = Arc::new(MonitorState::new(&config));
+    let mut set = JoinSet::new();
+    for idx in 0..state.listeners.len() {
+        set.spawn(accept_monitor_tcp(state.clone(), idx));
+        for n in 0..state.listeners[idx].sessions.len() {
+            set.spawn(keep_mux(state.clone(), idx, n));
+        }
+    }
+    if !config.status.is_empty() {
+        set.spawn(serve_status(bind_address(&config.status), state.clone()));
+    }
+
+    match set.join_next().await {
+        Some(Ok(res)) => res,
+        Some(Err(e)) => bail!("监听任务异常退出 {}", e),
+        None => Ok(()),
+    }
+}
+
+pub async fn accept_monitor_tcp(
+    state: Arc<MonitorState>, idx: usize,
+) -> Result<()> {
+    let config = &state.listeners[idx].config;
+    let address = bind_address(&config.bind);
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => bail!("{} 监听 {} 失败 {}", config.name(), address, e),
+    };
+    info!(
+        "😄 Accepting Monitor Tcp On: {} {} {} -> {}",
+        &address,
+        config.name(),
+        config.mode_name(),
+        config.servers.join(",")
+    );
 
     loop {
         let (stream, addr) = listener.accept().await?;
         info!("😄 Accepting Monitor Tcp connection from {}", addr);
 
-        tokio::spawn(async move { transfer(stream, server).await });
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_rig(state, idx, stream, addr).await {
+                debug!("{} 断开 {}", addr, e);
+            }
+        });
     }
 }
 
-async fn transfer(stream: TcpStream, addr: SocketAddr) -> Result<()> {
-    let (worker_r, mut worker_w) = tokio::io::split(stream);
-    let worker_r = tokio::io::BufReader::new(worker_r);
-    let mut worker_r = worker_r.lines();
+async fn handle_rig(
+    state: Arc<MonitorState>, idx: usize, stream: TcpStream, addr: SocketAddr,
+) -> Result<()> {
+    let listener = &state.listeners[idx];
+    let count = listener.connections.fetch_add(1, Ordering::Relaxed);
+
+    if !listener.sessions.is_empty() {
+        let session =
+            &listener.sessions[count as usize % listener.sessions.len()];
+        let active = listener.active.load(Ordering::Relaxed);
+        let server = listener.config.servers.get(active).cloned();
+        let (_guard, traffic) =
+            state.register(idx, addr, &server.unwrap_or_default());
+        let stream = Counted::new(stream, traffic);
+        session.open(&addr.to_string(), stream).await;
+        return Ok(());
+    }
+
+    let upstream = connect_upstream(listener).await?;
+
+    let (_guard, traffic) = state.register(idx, addr, &upstream.server);
+    let stream = Counted::new(stream, traffic);
+    match upstream.keys {
+        Some((send, recv)) => {
+            tunnel::relay(stream, upstream.stream, send, recv).await
+        }
+        None => transfer(stream, upstream.stream).await,
+    }
+}
+
+// 保持一条多路复用隧道, 断开后重连, 期间矿机的连接保留
+async fn keep_mux(
+    state: Arc<MonitorState>, idx: usize, n: usize,
+) -> Result<()> {
+    let listener = &state.listeners[idx];
+    let config = &listener.config;
+    let session = &listener.sessions[n];
+    let mut delay = 1;
+    loop {
+        match connect_upstream(listener).await {
+            Ok(Upstream {
+                server,
+                stream,
+                keys: Some((send, recv)),
+            }) => {
+                info!("{} 多路复用隧道 {} 已连接 {}", config.name(), n, server);
+                delay = 1;
+                if let Err(e) =
+                    mux::connect(session, stream, send, recv, config.compress)
+                        .await
+                {
+                    warn!("{} 多路复用隧道 {} 断开 {}", config.name(), n, e);
+                }
+            }
+            Ok(_) => bail!("{} 多路复用需要使用加密隧道", config.name()),
+            Err(e) => warn!("{} 多路复用隧道 {} 连接失败 {}", config.name(), n, e),
+        }
+        time::sleep(Duration::from_secs(delay)).await;
+        delay = (delay * 2).min(MUX_RETRY_MAX_SECS);
+    }
+}
 
-    let std_stream = match std::net::TcpStream::connect_timeout(
-        &addr,
-        Duration::new(5, 0),
-    ) {
-        Ok(stream) => stream,
-        Err(_) => {
-            //info!("{} 远程地址不通！", addr);
-            //std::process::exit(1);
-            bail!("{} 远程地址不通！", addr);
+struct Upstream {
+    server: String,
+    stream: TcpStream,
+    // 加密隧道的 (发送, 接收) 密钥, 明文协议时为 None
+    keys: Option<(Direction, Direction)>,
+}
+
+// 从当前的服务器开始依次尝试, 连上的作为之后的首选。
+// 每次连接都重新解析地址, 解析失败只影响这一次
+async fn connect_upstream(listener: &Listener) -> Result<Upstream> {
+    let config = &listener.config;
+    let servers = &config.servers;
+    let start = listener.active.load(Ordering::Relaxed);
+    let mut errors = vec![];
+
+    for i in 0..servers.len() {
+        let idx = (start + i) % servers.len();
+        let psk = config.tunnel().then_some(config.psk.as_str());
+        match connect_server(&servers[idx], psk).await {
+            Ok(upstream) => {
+                if idx != start {
+                    warn!("{} 切换到服务器 {}", config.name(), servers[idx]);
+                    listener.active.store(idx, Ordering::Relaxed);
+                }
+                return Ok(upstream);
+            }
+            Err(e) => {
+                warn!("{} 服务器 {} 不可用 {}", config.name(), servers[idx], e);
+                errors.push(e.to_string());
+            }
         }
+    }
+    bail!("{} 所有服务器均不可链接: {}", config.name(), errors.join(", "))
+}
+
+async fn connect_server(server: &str, psk: Option<&str>) -> Result<Upstream> {
+    let (mut stream, _) = dial::connect(server).await?;
+    let keys = match psk {
+        Some(psk) => match time::timeout(
+            Duration::from_secs(tunnel::HANDSHAKE_SECS),
+            tunnel::connect(&mut stream, psk),
+        )
+        .await
+        {
+            Ok(res) => Some(res?),
+            Err(_) => bail!("{} 隧道握手超时", server),
+        },
+        None => None,
+    };
+    Ok(Upstream {
+        server: server.to_string(),
+        stream,
+        keys,
+    })
+}
+
+// 状态接口, GET / 或 /status 返回 JSON
+pub async fn serve_status(
+    address: String, state: Arc<MonitorState>,
+) -> Result<()> {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => bail!("状态接口监听 {} 失败 {}", address, e),
     };
+    info!("😄 Monitor 状态接口: http://{}/status", address);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            // 只看请求行, 其余内容忽略
+            let mut buf = vec![0u8; 1024];
+            let len = match time::timeout(
+                Duration::from_secs(5),
+                stream.read(&mut buf),
+            )
+            .await
+            {
+                Ok(Ok(len)) => len,
+                _ => return,
+            };
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let (code, body) = match path {
+                "/" | "/status" => (
+                    "200 OK",
+                    serde_json::to_string(&state.status()).unwrap_or_default(),
+                ),
+                _ => ("404 Not Found", "{}".to_string()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                code,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+async fn transfer<S>(stream: S, pool_stream: TcpStream) -> Result<()>
+where S: AsyncRead + AsyncWrite {
+    let (worker_r, mut worker_w) = tokio::io::split(stream);
+    let worker_r = tokio::io::BufReader::new(worker_r);
+    let mut worker_r = worker_r.lines();
 
-    std_stream.set_nonblocking(true).unwrap();
-    let pool_stream = TcpStream::from_std(std_stream)?;
     let (pool_r, mut pool_w) = tokio::io::split(pool_stream);
     let pool_r = tokio::io::BufReader::new(pool_r);
     let mut pool_r = pool_r.split(crate::SPLIT);
@@ -170,3 +688,103 @@ async fn transfer(stream: TcpStream, addr: SocketAddr) -> Result<()> {
         }
     }
 }
+
+#[test]
+fn test_monitor_config() {
+    let yaml = "
+status: 9100
+listeners:
+  - name: eth
+    bind: 8888
+    servers: [a.example.com:14444, b.example.com:14444]
+    psk: '12345678'
+  - bind: 127.0.0.1:8889
+    servers: [a.example.com:24444]
+    mode: legacy
+";
+    let mut s = config::Config::default();
+    s.merge(config::File::from_str(yaml, config::FileFormat::Yaml))
+        .unwrap();
+    let config: MonitorConfig = s.try_into().unwrap();
+    config.check().unwrap();
+    assert_eq!(config.listeners.len(), 2);
+    assert!(config.listeners[0].tunnel());
+    assert_eq!(bind_address(&config.listeners[0].bind), "0.0.0.0:8888");
+    assert!(!config.listeners[1].tunnel());
+    assert_eq!(config.listeners[1].name(), "127.0.0.1:8889");
+
+    let toml = "
+[[listeners]]
+bind = \"8888\"
+servers = [\"a.example.com:14444\"]
+mode = \"tunnel\"
+psk = \"short\"
+";
+    let mut s = config::Config::default();
+    s.merge(config::File::from_str(toml, config::FileFormat::Toml))
+        .unwrap();
+    let config: MonitorConfig = s.try_into().unwrap();
+    assert!(config.check().is_err());
+    assert!(MonitorConfig::default().check().is_err());
+
+    // 多路复用只能用于加密隧道
+    let mut listener = ListenerConfig {
+        bind: "8888".into(),
+        servers: vec!["a.example.com:14444".into()],
+        mux: 2,
+        ..Default::default()
+    };
+    let mut config = MonitorConfig {
+        listeners: vec![listener.clone()],
+        ..Default::default()
+    };
+    assert!(config.check().is_err());
+    listener.psk = "12345678".into();
+    listener.compress = true;
+    config.listeners = vec![listener];
+    config.check().unwrap();
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_failover_and_traffic() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let alive = server.local_addr().unwrap().to_string();
+    let listener = Listener {
+        config: ListenerConfig {
+            bind: "0".into(),
+            servers: vec!["127.0.0.1:1".into(), alive.clone()],
+            ..Default::default()
+        },
+        active: AtomicUsize::new(0),
+        connections: AtomicU64::new(0),
+        closed: Traffic::default(),
+        sessions: vec![],
+    };
+    let upstream = connect_upstream(&listener).await.unwrap();
+    assert_eq!(upstream.server, alive);
+    assert!(upstream.keys.is_none());
+    assert_eq!(listener.active.load(Ordering::Relaxed), 1);
+
+    let config = MonitorConfig {
+        listeners: vec![listener.config.clone()],
+        ..Default::default()
+    };
+    let state = Arc::new(MonitorState::new(&config));
+    let addr = "127.0.0.1:1000".parse().unwrap();
+    let (guard, traffic) = state.register(0, addr, &alive);
+    let (rig, mut other) = tokio::io::duplex(1024);
+    let mut rig = Counted::new(rig, traffic);
+    rig.write_all(b"hello").await.unwrap();
+    other.write_all(b"ok").await.unwrap();
+    let mut buf = [0u8; 2];
+    rig.read_exact(&mut buf).await.unwrap();
+
+    let status = state.status();
+    assert_eq!(status.rigs.len(), 1);
+    assert_eq!((status.rigs[0].bytes_up, status.rigs[0].bytes_down), (2, 5));
+    drop(guard);
+    let status = state.status();
+    assert!(status.rigs.is_empty());
+    assert_eq!(status.listeners[0].bytes_up, 2);
+}
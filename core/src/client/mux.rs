@@ -0,0 +1,840 @@
+use anyhow::{bail, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+use flate2::{
+    Compress, Compression, Decompress, FlushCompress, FlushDecompress,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::{mpsc, Semaphore},
+    time,
+};
+
+use crate::client::tunnel::{self, Direction};
+
+// 多路复用: monitor 把本地的全部矿机放在一条 (或几条) 加密隧道上。
+// 隧道握手后 monitor 先发 MAGIC + 版本 + 标志 + 16 字节会话 ID,
+// 中转回复 MAGIC + 版本 + 标志 + 是否恢复了原来的会话。
+// 之后每个隧道帧里是若干个复用帧 (2 字节长度 + 类型 + 流 ID + 内容),
+// 开启压缩时整个隧道帧用 deflate 压缩, 两个方向各自是一个连续的压缩流,
+// 重复的任务 JSON 能压得很小。
+// 每个流有发送窗口, 对方把数据写入本地连接后回复已消费的偏移量。
+// 隧道断开后会话保留 SESSION_GRACE_SECS, 重连后双方交换每个流已收到的
+// 偏移量并重发对方没收到的数据, 期间矿机的连接不断开。
+pub const MAGIC: &[u8; 4] = b"MPMX";
+const VERSION: u8 = 1;
+const FLAG_COMPRESS: u8 = 1;
+
+// 单个数据帧的最大长度
+const DATA_CHUNK: usize = 4 * 1024;
+// 合并到一个隧道帧里的数据量, 压缩后仍小于隧道帧的上限
+const BATCH: usize = 8 * 1024;
+const MAX_BATCH: usize = 16 * 1024;
+// 每个流未确认数据的上限
+const WINDOW: usize = 256 * 1024;
+const PING_SECS: u64 = 15;
+// 超过这个时间没有收到任何帧认为隧道已断开
+const IDLE_SECS: u64 = 45;
+pub const SESSION_GRACE_SECS: u64 = 120;
+
+const OPEN: u8 = 1;
+const DATA: u8 = 2;
+const CLOSE: u8 = 3;
+const ACK: u8 = 4;
+const RESUME: u8 = 5;
+const PING: u8 = 6;
+const PONG: u8 = 7;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    Open { id: u32, addr: String },
+    Data { id: u32, data: Vec<u8> },
+    Close { id: u32 },
+    // 已写入本地连接的字节数
+    Ack { id: u32, consumed: u64 },
+    // 重连后告知对方本端已收到和已消费的字节数
+    Resume {
+        id: u32,
+        received: u64,
+        consumed: u64,
+        addr: String,
+    },
+    Ping,
+    Pong,
+}
+
+impl Frame {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0, 0]);
+        match self {
+            Frame::Open { id, addr } => {
+                buf.push(OPEN);
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(addr.as_bytes());
+            }
+            Frame::Data { id, data } => {
+                buf.push(DATA);
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Frame::Close { id } => {
+                buf.push(CLOSE);
+                buf.extend_from_slice(&id.to_be_bytes());
+            }
+            Frame::Ack { id, consumed } => {
+                buf.push(ACK);
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(&consumed.to_be_bytes());
+            }
+            Frame::Resume {
+                id,
+                received,
+                consumed,
+                addr,
+            } => {
+                buf.push(RESUME);
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(&received.to_be_bytes());
+                buf.extend_from_slice(&consumed.to_be_bytes());
+                buf.extend_from_slice(addr.as_bytes());
+            }
+            Frame::Ping => {
+                buf.push(PING);
+                buf.extend_from_slice(&[0; 4]);
+            }
+            Frame::Pong => {
+                buf.push(PONG);
+                buf.extend_from_slice(&[0; 4]);
+            }
+        }
+        let len = (buf.len() - start - 2) as u16;
+        buf[start..start + 2].copy_from_slice(&len.to_be_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 5 {
+            bail!("复用帧长度错误 {}", buf.len());
+        }
+        let id = u32::from_be_bytes(buf[1..5].try_into()?);
+        let body = &buf[5..];
+        let number = |i: usize| -> Result<u64> {
+            match body.get(i..i + 8) {
+                Some(n) => Ok(u64::from_be_bytes(n.try_into()?)),
+                None => bail!("复用帧长度错误 {}", buf.len()),
+            }
+        };
+        let text = |i: usize| String::from_utf8_lossy(&body[i..]).to_string();
+        Ok(match buf[0] {
+            OPEN => Frame::Open { id, addr: text(0) },
+            DATA => Frame::Data {
+                id,
+                data: body.to_vec(),
+            },
+            CLOSE => Frame::Close { id },
+            ACK => Frame::Ack {
+                id,
+                consumed: number(0)?,
+            },
+            RESUME => Frame::Resume {
+                id,
+                received: number(0)?,
+                consumed: number(8)?,
+                addr: text(16.min(body.len())),
+            },
+            PING => Frame::Ping,
+            PONG => Frame::Pong,
+            kind => bail!("未知的复用帧类型 {}", kind),
+        })
+    }
+}
+
+// 拆开一个隧道帧里的全部复用帧
+fn decode_batch(mut buf: &[u8]) -> Result<Vec<Frame>> {
+    let mut frames = vec![];
+    while !buf.is_empty() {
+        if buf.len() < 2 {
+            bail!("复用帧长度错误 {}", buf.len());
+        }
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        let frame = match buf.get(2..2 + len) {
+            Some(frame) => frame,
+            None => bail!("复用帧长度错误 {}", len),
+        };
+        frames.push(Frame::decode(frame)?);
+        buf = &buf[2 + len..];
+    }
+    Ok(frames)
+}
+
+// 同步刷新, 每个隧道帧都能单独解压
+fn deflate(c: &mut Compress, data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    let start = c.total_in();
+    loop {
+        let done = (c.total_in() - start) as usize;
+        c.compress_vec(&data[done..], &mut out, FlushCompress::Sync)?;
+        // 输出没有写满缓冲区说明已经全部输出
+        if (c.total_in() - start) as usize == data.len()
+            && out.len() < out.capacity()
+        {
+            return Ok(out);
+        }
+        out.reserve(1024);
+    }
+}
+
+fn inflate(d: &mut Decompress, data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 4 + 64);
+    let start = d.total_in();
+    loop {
+        let done = (d.total_in() - start) as usize;
+        d.decompress_vec(&data[done..], &mut out, FlushDecompress::Sync)?;
+        if (d.total_in() - start) as usize == data.len()
+            && out.len() < out.capacity()
+        {
+            return Ok(out);
+        }
+        if out.len() > MAX_BATCH {
+            bail!("复用帧解压后过长");
+        }
+        out.reserve(1024);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct SendBuf {
+    // 对方已确认的字节数, 即 buf 第一个字节的偏移量
+    acked: u64,
+    // 已发出还没确认的数据, 重连后从这里重发
+    buf: VecDeque<u8>,
+    // 重连后等对方告知收到的偏移量, 期间只缓存不发送
+    paused: bool,
+}
+
+struct Stream {
+    addr: String,
+    // 发给本地连接的数据, 流关闭时取走
+    tx: Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    // 发送窗口, 单位字节
+    credit: Semaphore,
+    send: Mutex<SendBuf>,
+    received: AtomicU64,
+    consumed: AtomicU64,
+}
+
+impl Stream {
+    fn ack(&self, send: &mut SendBuf, consumed: u64) {
+        let n = consumed
+            .saturating_sub(send.acked)
+            .min(send.buf.len() as u64) as usize;
+        send.buf.drain(..n);
+        send.acked += n as u64;
+        self.credit.add_permits(n);
+    }
+
+    fn close(&self) {
+        lock(&self.tx).take();
+        self.credit.close();
+    }
+}
+
+// 当前使用的隧道
+struct Link {
+    generation: u64,
+    tx: Option<mpsc::UnboundedSender<Frame>>,
+    detached: Option<Instant>,
+}
+
+impl Link {
+    fn send(&self, frame: Frame) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(frame);
+        }
+    }
+}
+
+// 中转端收到新的流时调用, 参数为矿机地址和流的本地一端
+pub type OnOpen = Arc<dyn Fn(String, DuplexStream) + Send + Sync>;
+
+lazy_static! {
+    // 中转端的会话, 隧道断开后保留一段时间等 monitor 重连
+    static ref SESSIONS: Mutex<HashMap<[u8; 16], Arc<Session>>> =
+        Mutex::new(HashMap::new());
+}
+
+// 加锁顺序: link -> streams -> 单个流的 send / tx
+pub struct Session {
+    id: [u8; 16],
+    // monitor 端为 None
+    on_open: Option<OnOpen>,
+    link: Mutex<Link>,
+    streams: Mutex<HashMap<u32, Arc<Stream>>>,
+    next_id: AtomicU32,
+    // 中转端见过的最大流 ID, 用来区分断线时丢失的 Open 和已关闭的流
+    max_opened: AtomicU32,
+}
+
+impl Session {
+    fn new(id: [u8; 16], on_open: Option<OnOpen>) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            on_open,
+            link: Mutex::new(Link {
+                generation: 0,
+                tx: None,
+                detached: Some(Instant::now()),
+            }),
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            max_opened: AtomicU32::new(0),
+        })
+    }
+
+    pub fn client() -> Arc<Self> { Self::new(rand::random(), None) }
+
+    pub fn streams(&self) -> usize { lock(&self.streams).len() }
+
+    pub fn connected(&self) -> bool { lock(&self.link).tx.is_some() }
+
+    fn stream(&self, id: u32) -> Option<Arc<Stream>> {
+        lock(&self.streams).get(&id).cloned()
+    }
+
+    fn insert(
+        &self, id: u32, addr: &str,
+    ) -> (Arc<Stream>, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stream = Arc::new(Stream {
+            addr: addr.to_string(),
+            tx: Mutex::new(Some(tx)),
+            credit: Semaphore::new(WINDOW),
+            send: Mutex::new(SendBuf {
+                acked: 0,
+                buf: VecDeque::new(),
+                paused: false,
+            }),
+            received: AtomicU64::new(0),
+            consumed: AtomicU64::new(0),
+        });
+        lock(&self.streams).insert(id, stream.clone());
+        (stream, rx)
+    }
+
+    fn remove(&self, link: &Link, id: u32, notify: bool) {
+        let stream = lock(&self.streams).remove(&id);
+        if let Some(stream) = stream {
+            stream.close();
+            if notify {
+                link.send(Frame::Close { id });
+            }
+        }
+    }
+
+    // 换上新的隧道, 暂停所有流的发送并告知对方各流收到的偏移量
+    fn attach(&self) -> (u64, mpsc::UnboundedReceiver<Frame>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut link = lock(&self.link);
+        link.generation += 1;
+        link.tx = Some(tx);
+        link.detached = None;
+        for (id, stream) in lock(&self.streams).iter() {
+            lock(&stream.send).paused = true;
+            link.send(Frame::Resume {
+                id: *id,
+                received: stream.received.load(Ordering::Relaxed),
+                consumed: stream.consumed.load(Ordering::Relaxed),
+                addr: stream.addr.clone(),
+            });
+        }
+        (link.generation, rx)
+    }
+
+    fn detach(&self, generation: u64) {
+        let mut link = lock(&self.link);
+        if link.generation == generation {
+            link.tx = None;
+            link.detached = Some(Instant::now());
+        }
+    }
+
+    // 中转端没有这个会话了 (重启或已过期), 对方收到过数据的流无法恢复
+    fn reset(&self) {
+        let link = lock(&self.link);
+        let ids: Vec<u32> = lock(&self.streams)
+            .iter()
+            .filter(|(_, stream)| {
+                lock(&stream.send).acked > 0
+                    || stream.received.load(Ordering::Relaxed) > 0
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        if !ids.is_empty() {
+            info!("中转的复用会话已失效, 断开 {} 台矿机", ids.len());
+        }
+        for id in ids {
+            self.remove(&link, id, false);
+        }
+    }
+
+    fn send_data(&self, id: u32, stream: &Stream, data: &[u8]) {
+        let link = lock(&self.link);
+        let mut send = lock(&stream.send);
+        send.buf.extend(data);
+        if !send.paused {
+            link.send(Frame::Data {
+                id,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    // 中转端打开一个流, 交给 on_open 处理
+    fn accept_stream(
+        self: &Arc<Self>, id: u32, addr: &str,
+    ) -> Option<Arc<Stream>> {
+        let on_open = self.on_open.as_ref()?;
+        if let Some(stream) = self.stream(id) {
+            return Some(stream);
+        }
+        self.max_opened.fetch_max(id, Ordering::Relaxed);
+        let (stream, rx) = self.insert(id, addr);
+        let (local, remote) = tokio::io::duplex(WINDOW);
+        tokio::spawn(self.clone().pump(id, stream.clone(), local, rx));
+        on_open(addr.to_string(), remote);
+        Some(stream)
+    }
+
+    // 处理对方的一个帧, 隧道已被替换时返回 false
+    fn handle(self: &Arc<Self>, generation: u64, frame: Frame) -> bool {
+        let link = lock(&self.link);
+        if link.generation != generation {
+            return false;
+        }
+        match frame {
+            Frame::Open { id, addr } => {
+                self.accept_stream(id, &addr);
+            }
+            Frame::Data { id, data } => match self.stream(id) {
+                Some(stream) => {
+                    stream
+                        .received
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    if let Some(tx) = lock(&stream.tx).as_ref() {
+                        let _ = tx.send(data);
+                    }
+                }
+                None => link.send(Frame::Close { id }),
+            },
+            Frame::Close { id } => self.remove(&link, id, false),
+            Frame::Ack { id, consumed } => {
+                if let Some(stream) = self.stream(id) {
+                    stream.ack(&mut lock(&stream.send), consumed);
+                }
+            }
+            Frame::Resume {
+                id,
+                received,
+                consumed,
+                addr,
+            } => {
+                let stream = match self.stream(id) {
+                    Some(stream) => stream,
+                    // 断线期间打开的流, Open 帧丢了
+                    None if id > self.max_opened.load(Ordering::Relaxed) => {
+                        match self.accept_stream(id, &addr) {
+                            Some(stream) => {
+                                link.send(Frame::Resume {
+                                    id,
+                                    received: 0,
+                                    consumed: 0,
+                                    addr: String::new(),
+                                });
+                                stream
+                            }
+                            None => {
+                                link.send(Frame::Close { id });
+                                return true;
+                            }
+                        }
+                    }
+                    None => {
+                        link.send(Frame::Close { id });
+                        return true;
+                    }
+                };
+                let mut send = lock(&stream.send);
+                stream.ack(&mut send, consumed);
+                // 从对方收到的位置开始重发
+                let skip = received.saturating_sub(send.acked) as usize;
+                let pending: Vec<u8> =
+                    send.buf.iter().skip(skip).copied().collect();
+                for chunk in pending.chunks(DATA_CHUNK) {
+                    link.send(Frame::Data {
+                        id,
+                        data: chunk.to_vec(),
+                    });
+                }
+                send.paused = false;
+            }
+            Frame::Ping => link.send(Frame::Pong),
+            Frame::Pong => {}
+        }
+        true
+    }
+
+    // 在本地连接和流之间转发, 任意一端关闭时结束
+    async fn pump<S>(
+        self: Arc<Self>, id: u32, stream: Arc<Stream>, local: S,
+        mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    ) where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (mut local_r, mut local_w) = tokio::io::split(local);
+        let upload = async {
+            let mut buf = vec![0u8; DATA_CHUNK];
+            loop {
+                let len = match local_r.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(len) => len,
+                };
+                match stream.credit.acquire_many(len as u32).await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => return,
+                }
+                self.send_data(id, &stream, &buf[..len]);
+            }
+        };
+        let download = async {
+            while let Some(data) = rx.recv().await {
+                let mut len = data.len();
+                if local_w.write_all(&data).await.is_err() {
+                    return;
+                }
+                // 一次写完已经到达的数据, 合并成一个确认
+                while let Ok(data) = rx.try_recv() {
+                    len += data.len();
+                    if local_w.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+                let consumed = stream
+                    .consumed
+                    .fetch_add(len as u64, Ordering::Relaxed)
+                    + len as u64;
+                lock(&self.link).send(Frame::Ack { id, consumed });
+            }
+            let _ = local_w.shutdown().await;
+        };
+
+        tokio::select! {
+            _ = upload => {},
+            _ = download => {},
+        }
+        self.remove(&lock(&self.link), id, true);
+    }
+
+    // monitor 端打开一个流, 矿机断开时返回
+    pub async fn open<S>(self: &Arc<Self>, addr: &str, local: S)
+    where S: AsyncRead + AsyncWrite {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (stream, rx) = {
+            let link = lock(&self.link);
+            let res = self.insert(id, addr);
+            link.send(Frame::Open {
+                id,
+                addr: addr.to_string(),
+            });
+            res
+        };
+        self.clone().pump(id, stream, local, rx).await
+    }
+
+    // 在一条隧道上运行会话, 隧道断开时返回, 会话和其中的流保留
+    async fn run<T>(
+        self: &Arc<Self>, tunnel: T, mut send: Direction,
+        mut recv: Direction, compress: bool,
+    ) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let (mut tunnel_r, mut tunnel_w) = tokio::io::split(tunnel);
+        let (generation, mut rx) = self.attach();
+
+        let writer = async {
+            let mut compress =
+                compress.then(|| Compress::new(Compression::fast(), false));
+            let mut ping = time::interval(Duration::from_secs(PING_SECS));
+            loop {
+                let frame = tokio::select! {
+                    frame = rx.recv() => match frame {
+                        Some(frame) => frame,
+                        // 已换成新的隧道
+                        None => return Ok(()),
+                    },
+                    _ = ping.tick() => Frame::Ping,
+                };
+                let mut batch = vec![];
+                frame.encode(&mut batch);
+                while batch.len() < BATCH {
+                    match rx.try_recv() {
+                        Ok(frame) => frame.encode(&mut batch),
+                        Err(_) => break,
+                    }
+                }
+                if let Some(c) = compress.as_mut() {
+                    batch = deflate(c, &batch)?;
+                }
+                tunnel::write_frame(&mut tunnel_w, &mut send, &batch).await?;
+            }
+        };
+        let reader = async {
+            let mut decompress = compress.then(|| Decompress::new(false));
+            loop {
+                let batch = match time::timeout(
+                    Duration::from_secs(IDLE_SECS),
+                    tunnel::read_frame(&mut tunnel_r, &mut recv),
+                )
+                .await
+                {
+                    Ok(Ok(Some(batch))) => batch,
+                    Ok(Ok(None)) => bail!("隧道已断开"),
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => bail!("隧道 {} 秒没有收到数据", IDLE_SECS),
+                };
+                let batch = match decompress.as_mut() {
+                    Some(d) => inflate(d, &batch)?,
+                    None => batch,
+                };
+                for frame in decode_batch(&batch)? {
+                    if !self.handle(generation, frame) {
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        let res = tokio::select! {
+            res = writer => res,
+            res = reader => res,
+        };
+        self.detach(generation);
+        res
+    }
+}
+
+fn hello(flags: u8, tail: &[u8]) -> Vec<u8> {
+    let mut hello = MAGIC.to_vec();
+    hello.push(VERSION);
+    hello.push(flags);
+    hello.extend_from_slice(tail);
+    hello
+}
+
+// monitor 端: 在握手后的隧道上开始或恢复会话, 隧道断开时返回
+pub async fn connect<T>(
+    session: &Arc<Session>, mut tunnel: T, mut send: Direction,
+    mut recv: Direction, compress: bool,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let flags = if compress { FLAG_COMPRESS } else { 0 };
+    tunnel::write_frame(&mut tunnel, &mut send, &hello(flags, &session.id))
+        .await?;
+    let reply = match time::timeout(
+        Duration::from_secs(tunnel::HANDSHAKE_SECS),
+        tunnel::read_frame(&mut tunnel, &mut recv),
+    )
+    .await
+    {
+        Ok(Ok(Some(reply))) => reply,
+        Ok(Err(e)) => return Err(e),
+        _ => bail!("多路复用握手失败, 中转可能不支持, 请升级中转或关闭 mux"),
+    };
+    if reply.len() < 7 || &reply[..4] != MAGIC {
+        bail!("多路复用握手失败, 中转可能不支持, 请升级中转或关闭 mux");
+    }
+    if reply[4] != VERSION {
+        bail!("多路复用协议版本不一致 中转 {} 本地 {}", reply[4], VERSION);
+    }
+    if reply[6] == 0 {
+        session.reset();
+    }
+    session
+        .run(tunnel, send, recv, reply[5] & FLAG_COMPRESS != 0)
+        .await
+}
+
+// 中转端: hello 为隧道的第一帧, 新建或恢复会话, 隧道断开时返回
+pub async fn accept<T>(
+    mut tunnel: T, mut send: Direction, recv: Direction, hello_frame: &[u8],
+    on_open: OnOpen,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if hello_frame.len() < 22 || &hello_frame[..4] != MAGIC {
+        bail!("多路复用握手格式错误");
+    }
+    if hello_frame[4] != VERSION {
+        bail!(
+            "多路复用协议版本不一致 monitor {} 本地 {}",
+            hello_frame[4],
+            VERSION
+        );
+    }
+    let compress = hello_frame[5] & FLAG_COMPRESS != 0;
+    let id: [u8; 16] = hello_frame[6..22].try_into()?;
+
+    let (session, resumed) = {
+        let mut sessions = lock(&SESSIONS);
+        match sessions.get(&id) {
+            Some(session) => (session.clone(), true),
+            None => {
+                let session = Session::new(id, Some(on_open));
+                sessions.insert(id, session.clone());
+                tokio::spawn(reap(session.clone()));
+                (session, false)
+            }
+        }
+    };
+    debug!(
+        "多路复用会话 {} {}",
+        hex::encode(&id[..4]),
+        if resumed { "恢复" } else { "新建" }
+    );
+
+    let flags = if compress { FLAG_COMPRESS } else { 0 };
+    tunnel::write_frame(&mut tunnel, &mut send, &hello(flags, &[resumed as u8]))
+        .await?;
+    session.run(tunnel, send, recv, compress).await
+}
+
+// 隧道断开超过 SESSION_GRACE_SECS 的会话关闭全部流
+async fn reap(session: Arc<Session>) {
+    let mut interval = time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        let expired = matches!(
+            lock(&session.link).detached,
+            Some(at) if at.elapsed().as_secs() > SESSION_GRACE_SECS
+        );
+        if !expired {
+            continue;
+        }
+
+        lock(&SESSIONS).remove(&session.id);
+        let link = lock(&session.link);
+        let ids: Vec<u32> = lock(&session.streams).keys().copied().collect();
+        if !ids.is_empty() {
+            info!("多路复用会话超时未重连, 断开 {} 台矿机", ids.len());
+        }
+        for id in ids {
+            session.remove(&link, id, false);
+        }
+        return;
+    }
+}
+
+#[test]
+fn test_mux_frames() {
+    let frames = vec![
+        Frame::Open {
+            id: 1,
+            addr: "10.0.0.2:5000".into(),
+        },
+        Frame::Data {
+            id: 1,
+            data: b"{\"id\":1}\n".to_vec(),
+        },
+        Frame::Ack { id: 1, consumed: 9 },
+        Frame::Resume {
+            id: 7,
+            received: 100,
+            consumed: 90,
+            addr: String::new(),
+        },
+        Frame::Close { id: 1 },
+        Frame::Ping,
+    ];
+    let mut batch = vec![];
+    for frame in &frames {
+        frame.encode(&mut batch);
+    }
+    assert_eq!(decode_batch(&batch).unwrap(), frames);
+    assert!(decode_batch(&batch[..batch.len() - 1]).is_err());
+
+    // 连续的压缩流, 重复的任务压缩后明显变小
+    let job = br#"{"id":0,"jsonrpc":"2.0","result":["0x6a5c1f0b","0x3d1a9e","0x00000000ffff"]}"#;
+    let mut c = Compress::new(Compression::fast(), false);
+    let mut d = Decompress::new(false);
+    let first = deflate(&mut c, job).unwrap();
+    let second = deflate(&mut c, job).unwrap();
+    assert!(second.len() < first.len() / 2);
+    assert_eq!(inflate(&mut d, &first).unwrap(), job);
+    assert_eq!(inflate(&mut d, &second).unwrap(), job);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_mux_resume() {
+    // 中转端把每个流原样发回
+    let on_open: OnOpen = Arc::new(|_, stream| {
+        tokio::spawn(async move {
+            let (mut r, mut w) = tokio::io::split(stream);
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+    });
+    let spawn_link = |session: Arc<Session>| {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let on_open = on_open.clone();
+        let server = tokio::spawn(async move {
+            let (send, mut recv) = tunnel::accept(&mut server, "secret").await?;
+            let hello = tunnel::read_frame(&mut server, &mut recv).await?;
+            accept(server, send, recv, &hello.unwrap_or_default(), on_open)
+                .await
+        });
+        let client = tokio::spawn(async move {
+            let (send, recv) = tunnel::connect(&mut client, "secret").await?;
+            connect(&session, client, send, recv, true).await
+        });
+        (client, server)
+    };
+
+    let session = Session::client();
+    let (client, server) = spawn_link(session.clone());
+    let (mut rig, local) = tokio::io::duplex(64 * 1024);
+    let opened = session.clone();
+    tokio::spawn(async move { opened.open("10.0.0.2:5000", local).await });
+
+    let mut buf = [0u8; 6];
+    rig.write_all(b"login\n").await.unwrap();
+    rig.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"login\n");
+
+    // 隧道断开期间矿机发的数据在重连后送达, 矿机连接不断开
+    client.abort();
+    server.abort();
+    rig.write_all(b"job01\n").await.unwrap();
+    let (_client, _server) = spawn_link(session.clone());
+    rig.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"job01\n");
+    assert_eq!(session.streams(), 1);
+
+    drop(rig);
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(session.streams(), 0);
+}
@@ -14,7 +14,10 @@ use tokio::{
 use tokio_rustls::TlsAcceptor;
 
 use super::*;
-use crate::{proxy::Proxy, state::Worker, util::config::Settings};
+use crate::{
+    client::traffic::Counted, proxy::Proxy, state::Worker,
+    util::config::Settings,
+};
 
 pub async fn accept_tcp_with_tls(
     proxy: Arc<Proxy>, cert: ServerConfig,
@@ -85,22 +88,10 @@ async fn transfer_ssl(
     proxy: Arc<Proxy>, worker: &mut Worker, tcp_stream: TcpStream,
     tls_acceptor: TlsAcceptor,
 ) -> Result<()> {
+    let tcp_stream = Counted::new(tcp_stream, proxy.traffic.clone());
     let client_stream = tls_acceptor.accept(tcp_stream).await?;
     let (worker_r, worker_w) = split(client_stream);
     let worker_r = BufReader::new(worker_r);
-    let pool_address: Vec<String>;
-    {
-        let config = RwLockReadGuard::map(proxy.config.read().await, |s| s);
-        pool_address = config.pool_address.to_vec();
-    }
-
-    let (stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(&pool_address) {
-            Ok(pool) => pool,
-            Err(_) => {
-                bail!("未匹配到矿池 或 均不可链接。请修改后重试");
-            }
-        };
 
     // if config.share == 0 {
     //     handle_tcp_pool(
@@ -125,16 +116,7 @@ async fn transfer_ssl(
     //     false,
     // )
     // .await
-    handle_tcp_random(
-        worker,
-        worker_r,
-        worker_w,
-        &pools,
-        proxy,
-        stream_type,
-        false,
-    )
-    .await
+    handle_tcp_random(worker, worker_r, worker_w, proxy).await
     // } else {
     //     handle_tcp_pool_timer(
     //         worker,
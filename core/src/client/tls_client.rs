@@ -0,0 +1,312 @@
+use anyhow::{bail, Result};
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+        Certificate, ClientConfig, Error as TlsError, OwnedTrustAnchor,
+        RootCertStore, ServerName,
+    },
+    TlsConnector,
+};
+
+use crate::client::{dial, pool_url::PoolUrl};
+
+// 连接 SSL 矿池时的证书校验设置。矿池地址中的 ca / pin / insecure
+// 参数覆盖这里的全局设置
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOptions {
+    // 额外信任的 CA 证书文件 (PEM), 为空时只用内置的根证书
+    pub ca_path: Option<String>,
+    // 不校验证书, 用于自签名证书的矿池
+    pub insecure: bool,
+    // 公钥固定, SubjectPublicKeyInfo 的 SHA-256, 小写十六进制
+    pub pins: Vec<String>,
+}
+
+impl TlsOptions {
+    pub fn new(ca_path: &str, insecure: bool, pins: &[String]) -> Result<Self> {
+        let pins = pins
+            .iter()
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(parse_pin)
+            .collect::<Result<Vec<_>>>()?;
+        let options = Self {
+            ca_path: (!ca_path.is_empty()).then(|| ca_path.to_string()),
+            insecure,
+            pins,
+        };
+        // 启动前检查 CA 文件能否读取
+        options.roots()?;
+        Ok(options)
+    }
+
+    // 合并矿池地址中的设置
+    pub fn for_pool(&self, url: &PoolUrl) -> Self {
+        Self {
+            ca_path: url.ca.clone().or_else(|| self.ca_path.clone()),
+            insecure: url.insecure || self.insecure,
+            pins: if url.pins.is_empty() {
+                self.pins.clone()
+            } else {
+                url.pins.clone()
+            },
+        }
+    }
+
+    fn roots(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(
+            webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }),
+        );
+
+        if let Some(path) = &self.ca_path {
+            let file = match std::fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) => bail!("无法读取 CA 证书文件 {} {}", path, e),
+            };
+            let certs =
+                rustls_pemfile::certs(&mut std::io::BufReader::new(file))?;
+            let (valid, _) = roots.add_parsable_certificates(&certs);
+            if valid == 0 {
+                bail!("CA 证书文件 {} 中没有可用的证书", path);
+            }
+        }
+        Ok(roots)
+    }
+}
+
+// 公钥固定支持十六进制或 curl 的 sha256//base64 格式
+pub fn parse_pin(value: &str) -> Result<String> {
+    let pin = match value.strip_prefix("sha256//") {
+        Some(b64) => match base64::decode(b64) {
+            Ok(bytes) => hex::encode(bytes),
+            Err(_) => bail!("公钥固定格式错误 {}", value),
+        },
+        None => value.replace(':', "").to_lowercase(),
+    };
+    if pin.len() != 64 || !pin.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(
+            "公钥固定需要是 SHA-256 的十六进制或 sha256//base64 {}",
+            value
+        );
+    }
+    Ok(pin)
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, data).as_ref())
+}
+
+// 读取一个 DER 元素, 返回 (整个元素, 内容, 剩余部分)
+fn der_next(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *data.get(1)? as usize;
+    let (len, head) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |len, b| len << 8 | *b as usize);
+        (len, 2 + n)
+    };
+    let end = head.checked_add(len)?;
+    let element = data.get(..end)?;
+    Some((element, &element[head..], &data[end..]))
+}
+
+// 证书中 SubjectPublicKeyInfo 的 DER
+pub(crate) fn spki_of(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_next(cert)?;
+    let (_, mut tbs, _) = der_next(cert)?;
+    // 跳过可选的 version
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_next(tbs)?.2;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_next(tbs)?.2;
+    }
+    der_next(tbs).map(|(spki, _, _)| spki)
+}
+
+// 校验失败时记下原因, 握手出错后给出清楚的错误信息
+struct PoolVerifier {
+    name: String,
+    webpki: WebPkiVerifier,
+    insecure: bool,
+    fingerprint: Option<String>,
+    pins: Vec<String>,
+    error: Mutex<Option<String>>,
+}
+
+impl PoolVerifier {
+    fn check(
+        &self, end_entity: &Certificate, intermediates: &[Certificate],
+        server_name: &ServerName, now: SystemTime,
+    ) -> Result<(), String> {
+        // 固定了证书指纹或公钥时以固定的为准, 不再校验证书链
+        if self.fingerprint.is_some() || !self.pins.is_empty() {
+            if self.fingerprint.as_deref()
+                == Some(sha256_hex(&end_entity.0).as_str())
+            {
+                return Ok(());
+            }
+            let pinned = std::iter::once(end_entity)
+                .chain(intermediates)
+                .filter_map(|cert| spki_of(&cert.0))
+                .any(|spki| self.pins.contains(&sha256_hex(spki)));
+            if pinned {
+                return Ok(());
+            }
+            return Err(format!(
+                "证书与固定的指纹或公钥不匹配, 矿池证书公钥为 {}",
+                spki_of(&end_entity.0).map(sha256_hex).unwrap_or_default()
+            ));
+        }
+
+        if self.insecure {
+            return Ok(());
+        }
+
+        self.webpki
+            .verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                &mut std::iter::empty(),
+                &[],
+                now,
+            )
+            .map(|_| ())
+            .map_err(|e| describe(&e, &self.name))
+    }
+}
+
+fn describe(err: &TlsError, name: &str) -> String {
+    let reason = match err {
+        TlsError::UnsupportedNameType => {
+            return "矿池地址为 IP, 无法校验证书域名, 请设置 sni 或 pin 参数"
+                .to_string()
+        }
+        TlsError::InvalidCertificateData(reason) => reason.as_str(),
+        _ => return format!("证书校验失败 {}", err),
+    };
+    if reason.contains("UnknownIssuer") {
+        "证书不受信任 (自签名证书请设置 CA 证书、pin 或 insecure=1)".into()
+    } else if reason.contains("CertExpired") {
+        "证书已过期".into()
+    } else if reason.contains("CertNotValidYet") {
+        "证书尚未生效, 请检查系统时间".into()
+    } else if reason.contains("CertNotValidForName") {
+        format!("证书与域名 {} 不匹配, 可用 sni 参数指定域名", name)
+    } else {
+        format!("证书校验失败 {}", reason)
+    }
+}
+
+impl ServerCertVerifier for PoolVerifier {
+    fn verify_server_cert(
+        &self, end_entity: &Certificate, intermediates: &[Certificate],
+        server_name: &ServerName, _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8], now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        match self.check(end_entity, intermediates, server_name, now) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(e) => {
+                if let Ok(mut error) = self.error.lock() {
+                    *error = Some(e.clone());
+                }
+                Err(TlsError::General(e))
+            }
+        }
+    }
+}
+
+// 按矿池的 TLS 设置握手
+pub async fn connect(
+    url: &PoolUrl, defaults: &TlsOptions, stream: TcpStream,
+) -> Result<TlsStream<TcpStream>> {
+    let options = defaults.for_pool(url);
+    let verifier = Arc::new(PoolVerifier {
+        name: url.server_name().to_string(),
+        webpki: WebPkiVerifier::new(options.roots()?, None),
+        insecure: options.insecure,
+        fingerprint: url.fingerprint.clone(),
+        pins: options.pins,
+        error: Mutex::new(None),
+    });
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+
+    let server_name = match ServerName::try_from(url.server_name()) {
+        Ok(name) => name,
+        Err(_) => {
+            bail!("矿池 {} 域名格式错误 {}", url.address, url.server_name())
+        }
+    };
+    let cx = TlsConnector::from(Arc::new(config));
+    match tokio::time::timeout(
+        dial::connect_timeout(),
+        cx.connect(server_name, stream),
+    )
+    .await
+    {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => {
+            match verifier.error.lock().ok().and_then(|e| e.clone()) {
+                Some(reason) => bail!("矿池 {} {}", url.address, reason),
+                None => bail!("矿池 {} SSL 握手失败 {}", url.address, e),
+            }
+        }
+        Err(_) => bail!("矿池 {} SSL 握手超时", url.address),
+    }
+}
+
+#[test]
+fn test_pin_and_spki() {
+    let pin = parse_pin("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=")
+        .unwrap();
+    assert_eq!(pin, "0".repeat(64));
+    assert_eq!(parse_pin(&"AB:".repeat(32)).unwrap(), "ab".repeat(32));
+    assert!(parse_pin("sha256//abc").is_err());
+    assert!(parse_pin("1234").is_err());
+
+    // 手工拼出的最小证书结构, 只用于检查 DER 解析
+    let spki = [0x30, 0x03, 0x02, 0x01, 0x07];
+    let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01];
+    for _ in 0..4 {
+        tbs.extend_from_slice(&[0x30, 0x00]);
+    }
+    tbs.extend_from_slice(&spki);
+    let mut cert = vec![0x30, tbs.len() as u8 + 2, 0x30, tbs.len() as u8];
+    cert.extend_from_slice(&tbs);
+    assert_eq!(spki_of(&cert), Some(&spki[..]));
+    assert_eq!(spki_of(&cert[..6]), None);
+
+    let options = TlsOptions::new("", false, &[pin.clone()]).unwrap();
+    let url = PoolUrl::parse("ssl://pool.com:443?insecure=1").unwrap();
+    let merged = options.for_pool(&url);
+    assert!(merged.insecure);
+    assert_eq!(merged.pins, vec![pin]);
+    assert!(TlsOptions::new("/nonexistent/ca.pem", false, &[]).is_err());
+}
@@ -0,0 +1,253 @@
+use anyhow::{bail, Result};
+
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// 加密隧道: monitor 与中转加密端口之间的连接。
+// 握手: 双方各发 MAGIC + 版本号 + 32 字节随机数, 用预共享密钥和双方的随机数
+// 通过 HKDF-SHA256 派生两个方向的 ChaCha20-Poly1305 密钥。
+// 之后每帧为 4 字节长度 + 密文, nonce 为各方向递增的计数器,
+// 重放、乱序或篡改的帧都无法通过校验。
+// 不以 MAGIC 开头的连接为旧的明文协议 (按行转发)。
+pub const MAGIC: &[u8; 4] = b"MPTN";
+pub const VERSION: u8 = 1;
+
+const RANDOM_LEN: usize = 32;
+// 单帧明文的最大长度
+const MAX_FRAME: usize = 16 * 1024;
+pub const HANDSHAKE_SECS: u64 = 10;
+
+// 一个方向的密钥和帧计数器
+pub struct Direction {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Direction {
+    fn nonce(&mut self) -> Result<Nonce> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = match self.counter.checked_add(1) {
+            Some(counter) => counter,
+            None => bail!("隧道帧计数器用尽"),
+        };
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce()?;
+        let mut frame = data.to_vec();
+        if self
+            .key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut frame)
+            .is_err()
+        {
+            bail!("隧道加密失败");
+        }
+        Ok(frame)
+    }
+
+    pub fn open<'a>(&mut self, frame: &'a mut [u8]) -> Result<&'a [u8]> {
+        let nonce = self.nonce()?;
+        match self.key.open_in_place(nonce, Aad::empty(), frame) {
+            Ok(data) => Ok(data),
+            Err(_) => bail!("隧道数据校验失败, 密钥错误或数据被篡改"),
+        }
+    }
+}
+
+// HKDF 输出 32 字节的 ChaCha20 密钥
+struct KeyLen;
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize { CHACHA20_POLY1305.key_len() }
+}
+
+fn derive(psk: &str, salt: &[u8], label: &[u8]) -> Result<Direction> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(psk.as_bytes());
+    let mut key = [0u8; 32];
+    let res = prk
+        .expand(&[b"mining_proxy tunnel v1 ", label], KeyLen)
+        .and_then(|okm| okm.fill(&mut key));
+    let key = match res
+        .ok()
+        .and_then(|_| UnboundKey::new(&CHACHA20_POLY1305, &key).ok())
+    {
+        Some(key) => key,
+        None => bail!("隧道密钥派生失败"),
+    };
+    Ok(Direction {
+        key: LessSafeKey::new(key),
+        counter: 0,
+    })
+}
+
+fn random() -> Result<[u8; RANDOM_LEN]> {
+    let mut random = [0u8; RANDOM_LEN];
+    if SystemRandom::new().fill(&mut random).is_err() {
+        bail!("无法生成随机数");
+    }
+    Ok(random)
+}
+
+async fn send_hello<S>(stream: &mut S, random: &[u8]) -> Result<()>
+where S: AsyncWrite + Unpin {
+    let mut hello = MAGIC.to_vec();
+    hello.push(VERSION);
+    hello.extend_from_slice(random);
+    stream.write_all(&hello).await?;
+    Ok(())
+}
+
+async fn read_hello<S>(stream: &mut S) -> Result<[u8; RANDOM_LEN]>
+where S: AsyncRead + Unpin {
+    let mut head = [0u8; 5];
+    stream.read_exact(&mut head).await?;
+    if &head[..4] != MAGIC {
+        bail!("不是加密隧道连接");
+    }
+    if head[4] != VERSION {
+        bail!("隧道协议版本不一致 对方 {} 本地 {}", head[4], VERSION);
+    }
+    let mut random = [0u8; RANDOM_LEN];
+    stream.read_exact(&mut random).await?;
+    Ok(random)
+}
+
+pub async fn write_frame<S>(
+    stream: &mut S, dir: &mut Direction, data: &[u8],
+) -> Result<()>
+where S: AsyncWrite + Unpin {
+    let frame = dir.seal(data)?;
+    let mut buf = (frame.len() as u32).to_be_bytes().to_vec();
+    buf.extend_from_slice(&frame);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+pub async fn read_frame<S>(
+    stream: &mut S, dir: &mut Direction,
+) -> Result<Option<Vec<u8>>>
+where S: AsyncRead + Unpin {
+    let len = match stream.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAX_FRAME + CHACHA20_POLY1305.tag_len() {
+        bail!("隧道帧长度错误 {}", len);
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(Some(dir.open(&mut frame)?.to_vec()))
+}
+
+// monitor 端握手, 返回 (发送, 接收) 两个方向
+pub async fn connect<S>(
+    stream: &mut S, psk: &str,
+) -> Result<(Direction, Direction)>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let client = random()?;
+    send_hello(stream, &client).await?;
+    let server = read_hello(stream).await?;
+
+    let salt = [client, server].concat();
+    let mut send = derive(psk, &salt, b"c2s")?;
+    let recv = derive(psk, &salt, b"s2c")?;
+    // 第一帧用于让中转确认双方的密钥一致
+    write_frame(stream, &mut send, MAGIC).await?;
+    Ok((send, recv))
+}
+
+// 中转端握手, 返回 (发送, 接收) 两个方向
+pub async fn accept<S>(
+    stream: &mut S, psk: &str,
+) -> Result<(Direction, Direction)>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let client = read_hello(stream).await?;
+    let server = random()?;
+    send_hello(stream, &server).await?;
+
+    let salt = [client, server].concat();
+    let send = derive(psk, &salt, b"s2c")?;
+    let mut recv = derive(psk, &salt, b"c2s")?;
+    match read_frame(stream, &mut recv).await {
+        Ok(Some(confirm)) if confirm == MAGIC => Ok((send, recv)),
+        _ => bail!("隧道预共享密钥不一致"),
+    }
+}
+
+// 在明文连接和隧道之间转发, 任意一端断开时结束
+pub async fn relay<P, T>(
+    plain: P, tunnel: T, mut send: Direction, mut recv: Direction,
+) -> Result<()>
+where
+    P: AsyncRead + AsyncWrite,
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut plain_r, mut plain_w) = tokio::io::split(plain);
+    let (mut tunnel_r, mut tunnel_w) = tokio::io::split(tunnel);
+
+    let outbound = async {
+        let mut buf = vec![0u8; MAX_FRAME];
+        loop {
+            let len = plain_r.read(&mut buf).await?;
+            if len == 0 {
+                tunnel_w.shutdown().await?;
+                return Ok(());
+            }
+            write_frame(&mut tunnel_w, &mut send, &buf[..len]).await?;
+        }
+    };
+    let inbound = async {
+        while let Some(data) = read_frame(&mut tunnel_r, &mut recv).await? {
+            plain_w.write_all(&data).await?;
+        }
+        plain_w.shutdown().await?;
+        Ok(())
+    };
+
+    tokio::select! {
+        res = outbound => res,
+        res = inbound => res,
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_tunnel() {
+    let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+    let server_side = tokio::spawn(async move {
+        let (mut send, mut recv) = accept(&mut server, "secret").await?;
+        let data = read_frame(&mut server, &mut recv).await?.unwrap();
+        write_frame(&mut server, &mut send, &data).await?;
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let (mut send, mut recv) = connect(&mut client, "secret").await.unwrap();
+    write_frame(&mut client, &mut send, b"{\"id\":1}\n").await.unwrap();
+    let echo = read_frame(&mut client, &mut recv).await.unwrap().unwrap();
+    assert_eq!(echo, b"{\"id\":1}\n");
+    server_side.await.unwrap().unwrap();
+
+    // 密钥不一致时握手失败
+    let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+    let server_side =
+        tokio::spawn(async move { accept(&mut server, "other").await });
+    connect(&mut client, "secret").await.unwrap();
+    assert!(server_side.await.unwrap().is_err());
+
+    // 重放的帧无法通过校验
+    let salt = [0u8; 64];
+    let mut send = derive("secret", &salt, b"c2s").unwrap();
+    let mut recv = derive("secret", &salt, b"c2s").unwrap();
+    let frame = send.seal(b"submit").unwrap();
+    assert_eq!(recv.open(&mut frame.clone()).unwrap(), b"submit");
+    assert!(recv.open(&mut frame.clone()).is_err());
+}
@@ -0,0 +1,396 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::VecDeque;
+
+use tokio::io::{AsyncWrite, WriteHalf};
+
+use crate::{
+    client::write_to_socket_byte,
+    coin::Coin,
+    protocol::{
+        eth_stratum::ETH_STRATUM_VERSION,
+        ethjson::{
+            EthClientObject, EthClientRootObject, EthClientWorkerObject,
+            EthServerRootObject,
+        },
+        stratum::{parse_server_message, StraumRoot, StraumServerMessage},
+        CLIENT_GETWORK, CLIENT_LOGIN, CLIENT_SUBHASHRATE, CLIENT_SUBMITWORK,
+        EXTRANONCE_SUBSCRIBE, PROTOCOL, SUBSCRIBE,
+    },
+};
+
+// 保留的矿池任务数, 用于把 header 换回矿池的 job_id
+const POOL_JOBS: usize = 16;
+
+// 矿池发来的消息, 已经换算成代理内部统一的格式
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoolEvent {
+    // eth_getWork 格式 [header, seed, target, height]
+    Job(Vec<String>),
+    Login(bool),
+    Submit(bool),
+    // Stratum 订阅完成, 带矿池分配的 extranonce
+    Subscribed(String),
+    SetExtranonce(String),
+    Other,
+}
+
+// 与矿池之间的会话, 按矿池地址配置的协议收发
+#[derive(Debug, Clone)]
+pub struct PoolSession {
+    pub dialect: PROTOCOL,
+    pub extranonce: String,
+    // Stratum 下 mining.authorize 的矿工名, 提交时需要带上
+    pub account: String,
+    coin: &'static dyn Coin,
+    target: String,
+    jobs: VecDeque<(String, String)>,
+}
+
+pub(crate) fn is_ok(result: &Value, error: &Value) -> bool {
+    error.is_null() && *result != Value::Bool(false) && !result.is_null()
+}
+
+impl PoolSession {
+    pub fn new(dialect: PROTOCOL, coin: &'static dyn Coin) -> Self {
+        Self {
+            dialect,
+            extranonce: String::new(),
+            account: String::new(),
+            coin,
+            target: coin.share_target(1.0),
+            jobs: VecDeque::new(),
+        }
+    }
+
+    // 矿池是否需要 mix_digest。EthereumStratum 只提交 nonce
+    pub fn need_mix(&self) -> bool {
+        self.dialect != PROTOCOL::NICEHASHSTRATUM && self.coin.need_mix()
+    }
+
+    async fn send<W, T>(
+        &self, w: &mut WriteHalf<W>, rpc: &T, worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+        T: serde::Serialize,
+    {
+        write_to_socket_byte(w, serde_json::to_vec(rpc)?, worker_name).await
+    }
+
+    // 建立连接后的订阅。ETHProxy 不需要
+    pub async fn start<W>(
+        &mut self, w: &mut WriteHalf<W>, worker_name: &String,
+    ) -> Result<()>
+    where W: AsyncWrite {
+        match self.dialect {
+            PROTOCOL::STRATUM => {
+                let subscribe = StraumRoot {
+                    id: SUBSCRIBE,
+                    method: "mining.subscribe".into(),
+                    params: vec![],
+                };
+                self.send(w, &subscribe, worker_name).await
+            }
+            PROTOCOL::NICEHASHSTRATUM => {
+                let subscribe = StraumRoot {
+                    id: SUBSCRIBE,
+                    method: "mining.subscribe".into(),
+                    params: vec![
+                        format!("MiningProxy/{}", clap::crate_version!()),
+                        ETH_STRATUM_VERSION.into(),
+                    ],
+                };
+                self.send(w, &subscribe, worker_name).await?;
+                let extranonce = StraumRoot {
+                    id: EXTRANONCE_SUBSCRIBE,
+                    method: "mining.extranonce.subscribe".into(),
+                    params: vec![],
+                };
+                self.send(w, &extranonce, worker_name).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn login<W>(
+        &mut self, w: &mut WriteHalf<W>, wallet: &str, worker: &str,
+        password: &str, worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        self.account = format!("{}.{}", wallet, worker);
+        let mut params = vec![];
+        if self.dialect == PROTOCOL::ETH {
+            params.push(wallet.to_string());
+            if !password.is_empty() {
+                params.push(password.to_string());
+            }
+            let login = EthClientWorkerObject {
+                id: CLIENT_LOGIN,
+                method: "eth_submitLogin".into(),
+                params,
+                worker: worker.to_string(),
+            };
+            self.send(w, &login, worker_name).await
+        } else {
+            params.push(self.account.clone());
+            params.push(password.to_string());
+            let login = StraumRoot {
+                id: CLIENT_LOGIN,
+                method: "mining.authorize".into(),
+                params,
+            };
+            self.send(w, &login, worker_name).await
+        }
+    }
+
+    // work 为 [nonce, header, mix]。返回 false 表示这个份额无法提交给矿池
+    pub async fn submit<W>(
+        &mut self, w: &mut WriteHalf<W>, work: &[String], worker: &str,
+        worker_name: &String,
+    ) -> Result<bool>
+    where
+        W: AsyncWrite,
+    {
+        match self.dialect {
+            PROTOCOL::ETH => {
+                let submit = EthClientWorkerObject {
+                    id: CLIENT_SUBMITWORK,
+                    method: "eth_submitWork".into(),
+                    params: work.to_vec(),
+                    worker: worker.to_string(),
+                };
+                self.send(w, &submit, worker_name).await?;
+            }
+            PROTOCOL::STRATUM => {
+                let job_id = match self.job_id(&work[1]) {
+                    Some(job_id) => job_id,
+                    None => return Ok(false),
+                };
+                let mut params = vec![self.account.clone(), job_id];
+                params.extend_from_slice(work);
+                let submit = StraumRoot {
+                    id: CLIENT_SUBMITWORK,
+                    method: "mining.submit".into(),
+                    params,
+                };
+                self.send(w, &submit, worker_name).await?;
+            }
+            PROTOCOL::NICEHASHSTRATUM => {
+                let job_id = match self.job_id(&work[1]) {
+                    Some(job_id) => job_id,
+                    None => return Ok(false),
+                };
+                // nonce 必须以矿池分配的 extranonce 开头
+                let nonce = work[0].trim_start_matches("0x");
+                if !nonce.starts_with(&self.extranonce) {
+                    return Ok(false);
+                }
+                let submit = StraumRoot {
+                    id: CLIENT_SUBMITWORK,
+                    method: "mining.submit".into(),
+                    params: vec![
+                        self.account.clone(),
+                        job_id,
+                        nonce[self.extranonce.len()..].to_string(),
+                    ],
+                };
+                self.send(w, &submit, worker_name).await?;
+            }
+            PROTOCOL::KNOWN => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    // 三种协议的矿池都接受 eth_submitHashrate
+    pub async fn submit_hashrate<W>(
+        &mut self, w: &mut WriteHalf<W>, params: Vec<String>, worker: &str,
+        worker_name: &String,
+    ) -> Result<()>
+    where
+        W: AsyncWrite,
+    {
+        let hashrate = EthClientWorkerObject {
+            id: CLIENT_SUBHASHRATE,
+            method: "eth_submitHashrate".into(),
+            params,
+            worker: worker.to_string(),
+        };
+        self.send(w, &hashrate, worker_name).await
+    }
+
+    // 只有 ETHProxy 需要主动拉取任务
+    pub async fn get_work<W>(
+        &mut self, w: &mut WriteHalf<W>, worker_name: &String,
+    ) -> Result<()>
+    where W: AsyncWrite {
+        if self.dialect != PROTOCOL::ETH {
+            return Ok(());
+        }
+
+        let mut get_work = EthClientRootObject {
+            id: CLIENT_GETWORK,
+            method: "eth_getWork".into(),
+            params: vec![],
+        };
+        write_to_socket_byte(w, get_work.to_vec()?, worker_name).await
+    }
+
+    fn job_id(&self, header: &str) -> Option<String> {
+        self.jobs
+            .iter()
+            .rev()
+            .find(|(h, _)| h == header)
+            .map(|(_, job_id)| job_id.clone())
+    }
+
+    // Stratum 方言下矿池是否还有这个任务, 没有时提交的份额已过期
+    pub fn has_job(&self, header: &str) -> bool {
+        self.job_id(header).is_some()
+    }
+
+    fn push_job(&mut self, header: String, job_id: String) {
+        if self.jobs.len() >= POOL_JOBS {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back((header, job_id));
+    }
+
+    pub fn parse(&mut self, buf: &str) -> PoolEvent {
+        if self.dialect == PROTOCOL::ETH {
+            return self.parse_eth(buf);
+        }
+
+        match parse_server_message(buf) {
+            StraumServerMessage::Notify(notify) => {
+                let params: Vec<String> = notify
+                    .params
+                    .iter()
+                    .map(|p| p.as_str().unwrap_or_default().to_string())
+                    .collect();
+                if params.len() < 3 {
+                    return PoolEvent::Other;
+                }
+
+                let job = if self.dialect == PROTOCOL::STRATUM {
+                    self.coin.parse_notify(&params, &self.target)
+                } else {
+                    // [job_id, seed, header, clean]
+                    self.coin.parse_job(&[
+                        params[2].clone(),
+                        params[1].clone(),
+                        self.target.clone(),
+                    ])
+                };
+                match job {
+                    Ok(job) => {
+                        self.push_job(job[0].clone(), params[0].clone());
+                        PoolEvent::Job(job)
+                    }
+                    Err(e) => {
+                        tracing::warn!("矿池任务格式错误: {}", e);
+                        PoolEvent::Other
+                    }
+                }
+            }
+            StraumServerMessage::SetDifficulty(set) => {
+                if let Some(diff) = set.params.first().and_then(|d| d.as_f64())
+                {
+                    self.target = self.coin.share_target(diff);
+                }
+                PoolEvent::Other
+            }
+            StraumServerMessage::SetExtranonce(set) => {
+                match set.params.first().and_then(|e| e.as_str()) {
+                    Some(extranonce) => {
+                        self.extranonce = extranonce.to_string();
+                        PoolEvent::SetExtranonce(self.extranonce.clone())
+                    }
+                    None => PoolEvent::Other,
+                }
+            }
+            StraumServerMessage::Result(result) => match result.id {
+                SUBSCRIBE => {
+                    // EthereumStratum: [["mining.notify", id, version], extranonce]
+                    if let Some(extranonce) =
+                        result.result.get(1).and_then(|e| e.as_str())
+                    {
+                        self.extranonce = extranonce.to_string();
+                    }
+                    PoolEvent::Subscribed(self.extranonce.clone())
+                }
+                CLIENT_LOGIN => {
+                    PoolEvent::Login(is_ok(&result.result, &result.error))
+                }
+                CLIENT_SUBMITWORK => {
+                    PoolEvent::Submit(is_ok(&result.result, &result.error))
+                }
+                _ => PoolEvent::Other,
+            },
+            StraumServerMessage::Other => PoolEvent::Other,
+        }
+    }
+
+    fn parse_eth(&mut self, buf: &str) -> PoolEvent {
+        if let Ok(job) = serde_json::from_str::<EthServerRootObject>(buf) {
+            if job.result.len() >= 3 {
+                return match self.coin.parse_job(&job.result) {
+                    Ok(job) => PoolEvent::Job(job),
+                    Err(e) => {
+                        tracing::warn!("矿池任务格式错误: {}", e);
+                        PoolEvent::Other
+                    }
+                };
+            }
+        }
+
+        if let StraumServerMessage::Result(result) = parse_server_message(buf)
+        {
+            return match result.id {
+                CLIENT_LOGIN => {
+                    PoolEvent::Login(is_ok(&result.result, &result.error))
+                }
+                CLIENT_SUBMITWORK => {
+                    PoolEvent::Submit(is_ok(&result.result, &result.error))
+                }
+                _ => PoolEvent::Other,
+            };
+        }
+
+        PoolEvent::Other
+    }
+}
+
+#[test]
+fn test_parse_stratum_job() {
+    let mut pool =
+        PoolSession::new(PROTOCOL::NICEHASHSTRATUM, &crate::coin::ETH);
+    let subscribe = r#"{"id":10002,"result":[["mining.notify","ae6812eb4cd7735a302a8a9dd95cf71f","EthereumStratum/1.0.0"],"080c"],"error":null}"#;
+    assert_eq!(pool.parse(subscribe), PoolEvent::Subscribed("080c".into()));
+
+    let seed = "4c4f".repeat(16);
+    let header = "645c".repeat(16);
+    let notify = format!(
+        r#"{{"id":null,"method":"mining.notify","params":["bf0488","{}","{}",true]}}"#,
+        seed, header
+    );
+    match pool.parse(&notify) {
+        PoolEvent::Job(job) => {
+            assert_eq!(job[0], format!("0x{}", header));
+            assert_eq!(job[1], format!("0x{}", seed));
+            assert_eq!(
+                pool.job_id(&format!("0x{}", header)),
+                Some("bf0488".into())
+            );
+            assert!(!pool.has_job(&format!("0x{}", seed)));
+        }
+        e => panic!("{:?}", e),
+    }
+
+    // 格式不对的任务不下发给矿机
+    let notify = r#"{"id":null,"method":"mining.notify","params":["bf0488","4c4f","645c",true]}"#;
+    assert_eq!(pool.parse(notify), PoolEvent::Other);
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::coin::Coin;
+
+// 矿机难度下限, 约 4.3MH 一个份额
+const MIN_DIFF: f64 = 0.001;
+// 单次调整的最大倍数
+const MAX_STEP: f64 = 4.0;
+// 统计份额速度的窗口
+const RETARGET_SECS: u64 = 60;
+// 保留的最近任务数, 与下发给矿机的任务数一致
+const POOL_TARGETS: usize = 16;
+
+// 代理端的矿机难度。按每分钟份额数调整下发给矿机的目标值,
+// 矿机难度不会超过矿池难度。
+#[derive(Debug)]
+pub struct VarDiff {
+    spm: f64,
+    // 0 表示还没有收到任务
+    diff: f64,
+    pool_diff: f64,
+    shares: u32,
+    since: Instant,
+    // (header, 矿池目标值)
+    pool_targets: VecDeque<(String, String)>,
+}
+
+impl VarDiff {
+    // spm 为 0 时关闭
+    pub fn new(spm: u32) -> Self {
+        Self {
+            spm: spm as f64,
+            diff: 0.0,
+            pool_diff: 0.0,
+            shares: 0,
+            since: Instant::now(),
+            pool_targets: VecDeque::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool { self.spm > 0.0 }
+
+    // 当前下发给矿机的难度
+    pub fn diff(&self) -> f64 { self.diff.min(self.pool_diff) }
+
+    // 把任务的目标值换成矿机的, 记下原来的矿池目标值
+    pub fn apply(&mut self, job: &mut [String], coin: &dyn Coin) {
+        if !self.enabled() || job.len() < 3 {
+            return;
+        }
+
+        self.pool_diff = coin.share_difficulty(&job[2]);
+        if self.diff <= 0.0 {
+            self.diff = self.pool_diff;
+            self.since = Instant::now();
+        }
+
+        if self.pool_targets.len() >= POOL_TARGETS {
+            self.pool_targets.pop_front();
+        }
+        self.pool_targets.push_back((job[0].clone(), job[2].clone()));
+
+        if self.diff < self.pool_diff {
+            job[2] = coin.share_target(self.diff);
+        }
+    }
+
+    // 没有改写过的任务返回 None
+    pub fn pool_target(&self, header: &str) -> Option<&String> {
+        self.pool_targets
+            .iter()
+            .rev()
+            .find(|(h, _)| h == header)
+            .map(|(_, target)| target)
+    }
+
+    pub fn add_share(&mut self) { self.shares += 1; }
+
+    // 窗口到期后按实际的份额速度调整难度, 返回是否有变化
+    pub fn retarget(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.since);
+        if !self.enabled()
+            || self.diff <= 0.0
+            || elapsed < Duration::from_secs(RETARGET_SECS)
+        {
+            return false;
+        }
+
+        let rate = self.shares as f64 / (elapsed.as_secs_f64() / 60.0);
+        let step = (rate / self.spm).clamp(1.0 / MAX_STEP, MAX_STEP);
+        self.shares = 0;
+        self.since = now;
+
+        // 速度偏差不大时不调整, 避免矿机频繁切换难度
+        if (step - 1.0).abs() < 0.2 {
+            return false;
+        }
+
+        let diff = (self.diff() * step).clamp(MIN_DIFF, self.pool_diff);
+        if diff == self.diff {
+            return false;
+        }
+        self.diff = diff;
+        true
+    }
+}
+
+#[test]
+fn test_vardiff_retarget() {
+    use crate::coin::ETH;
+
+    let header =
+        "0xc9149cc0386e689d789a1c2f3d5d169a61a6218ed30e74414dc736e442ef3d1f";
+    let pool_target = ETH.share_target(8.0);
+    let mut job =
+        vec![header.to_string(), header.to_string(), pool_target.clone()];
+
+    let mut vardiff = VarDiff::new(10);
+    vardiff.apply(&mut job, &ETH);
+    assert_eq!(vardiff.diff(), 8.0);
+    assert_eq!(job[2], pool_target);
+
+    // 一分钟只有 1 个份额, 难度降到 1/4
+    vardiff.add_share();
+    let now = vardiff.since + Duration::from_secs(RETARGET_SECS);
+    assert!(vardiff.retarget(now));
+    assert_eq!(vardiff.diff(), 2.0);
+
+    let mut job = vec![header.to_string(), header.to_string(), pool_target];
+    vardiff.apply(&mut job, &ETH);
+    assert_eq!(job[2], ETH.share_target(2.0));
+    assert_eq!(vardiff.pool_target(header), Some(&ETH.share_target(8.0)));
+
+    // 份额太多时最多回到矿池难度
+    for _ in 0..100 {
+        vardiff.add_share();
+    }
+    assert!(vardiff.retarget(now + Duration::from_secs(RETARGET_SECS)));
+    assert_eq!(vardiff.diff(), 8.0);
+}
@@ -0,0 +1,117 @@
+use anyhow::{bail, Result};
+
+use super::{check_hash, check_target, Coin};
+
+// Conflux base32 地址使用的字符
+const BASE32_CHARS: &str = "abcdefghjkmnprstuvwxyz0123456789";
+
+// Conflux Octopus。任务中 seed 的位置是区块高度 (十进制),
+// 提交时不带 mix_digest。
+#[derive(Debug)]
+pub struct Cfx;
+
+pub static CFX: Cfx = Cfx;
+
+fn parse_height(height: &str) -> Result<u64> {
+    let res = match height.strip_prefix("0x") {
+        Some(hex_str) => u64::from_str_radix(hex_str, 16),
+        None => height.parse(),
+    };
+    match res {
+        Ok(height) => Ok(height),
+        Err(_) => bail!("CFX 区块高度格式错误 {}", height),
+    }
+}
+
+impl Coin for Cfx {
+    fn name(&self) -> &'static str { "CFX" }
+
+    fn default_port(&self, _ssl: bool) -> u16 { 32525 }
+
+    // [pow_hash, height, boundary]
+    fn parse_job(&self, job: &[String]) -> Result<Vec<String>> {
+        if job.len() < 3 {
+            bail!("CFX 任务参数不足 {:?}", job);
+        }
+
+        Ok(vec![
+            check_hash(&job[0])?,
+            parse_height(&job[1])?.to_string(),
+            check_target(&job[2])?,
+        ])
+    }
+
+    // mining.notify [job_id, height, pow_hash, boundary]
+    fn parse_notify(
+        &self, params: &[String], target: &str,
+    ) -> Result<Vec<String>> {
+        if params.len() < 3 {
+            bail!("CFX 任务参数不足 {:?}", params);
+        }
+
+        let target = match params.get(3) {
+            Some(t) if !t.is_empty() => t.as_str(),
+            _ => target,
+        };
+        self.parse_job(&[params[2].clone(), params[1].clone(), target.into()])
+    }
+
+    fn notify_params(&self, job: &[String]) -> Vec<String> {
+        vec![
+            job[0].clone(),
+            job[1].clone(),
+            job[0].clone(),
+            job[2].clone(),
+        ]
+    }
+
+    fn need_mix(&self) -> bool { false }
+
+    fn ethash_epoch(&self, _seed_epoch: u64) -> Option<u64> { None }
+
+    // cfx:aa...(42 位 base32) 或旧的 0x1 开头十六进制地址
+    fn check_wallet(&self, wallet: &str) -> Result<()> {
+        let lower = wallet.to_lowercase();
+        if let Some((prefix, address)) = lower.split_once(':') {
+            if !matches!(prefix, "cfx" | "cfxtest")
+                || address.len() != 42
+                || !address.chars().all(|c| BASE32_CHARS.contains(c))
+            {
+                bail!("CFX 钱包地址格式错误 {}", wallet);
+            }
+        } else if let Some(hex_str) = lower.strip_prefix("0x") {
+            if hex_str.len() != 40
+                || !hex_str.starts_with('1')
+                || hex::decode(hex_str).is_err()
+            {
+                bail!("CFX 钱包地址格式错误 {}", wallet);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_cfx_notify() {
+    let hash =
+        "0xc9149cc0386e689d789a1c2f3d5d169a61a6218ed30e74414dc736e442ef3d1f";
+    let params = vec![
+        hash.to_string(),
+        "0x3039".to_string(),
+        hash.to_string(),
+        "0x0000ffff".to_string(),
+    ];
+    let job = CFX.parse_notify(&params, "").unwrap();
+    assert_eq!(job[0], hash);
+    assert_eq!(job[1], "12345");
+    assert_eq!(CFX.notify_params(&job)[1], "12345");
+
+    // seed 位置必须是区块高度
+    assert!(CFX
+        .parse_job(&[hash.into(), hash.into(), "0x1".into()])
+        .is_err());
+    assert!(CFX
+        .check_wallet("cfx:aak2rra2njvd77ezwjvx04kkds9fzagfe6ku8scz91")
+        .is_ok());
+    assert!(CFX.check_wallet("eth:abc").is_err());
+}
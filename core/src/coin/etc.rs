@@ -0,0 +1,37 @@
+use super::Coin;
+
+// ECIP-1099 (区块 11700000) 之后 epoch 长度从 30000 变为 60000,
+// seed 仍按 30000 个区块计算。
+const ECIP1099_SEED_EPOCH: u64 = 390;
+
+#[derive(Debug)]
+pub struct Etc;
+
+pub static ETC: Etc = Etc;
+
+impl Coin for Etc {
+    fn name(&self) -> &'static str { "ETC" }
+
+    fn default_port(&self, ssl: bool) -> u16 {
+        if ssl {
+            11010
+        } else {
+            1010
+        }
+    }
+
+    fn ethash_epoch(&self, seed_epoch: u64) -> Option<u64> {
+        if seed_epoch >= ECIP1099_SEED_EPOCH {
+            Some(seed_epoch / 2)
+        } else {
+            Some(seed_epoch)
+        }
+    }
+}
+
+#[test]
+fn test_ecip1099_epoch() {
+    assert_eq!(ETC.ethash_epoch(389), Some(389));
+    assert_eq!(ETC.ethash_epoch(390), Some(195));
+    assert_eq!(ETC.ethash_epoch(500), Some(250));
+}
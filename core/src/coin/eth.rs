@@ -0,0 +1,18 @@
+use super::Coin;
+
+#[derive(Debug)]
+pub struct Eth;
+
+pub static ETH: Eth = Eth;
+
+impl Coin for Eth {
+    fn name(&self) -> &'static str { "ETH" }
+
+    fn default_port(&self, ssl: bool) -> u16 {
+        if ssl {
+            5555
+        } else {
+            4444
+        }
+    }
+}
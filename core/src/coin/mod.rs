@@ -0,0 +1,156 @@
+pub mod cfx;
+pub mod etc;
+pub mod eth;
+
+use anyhow::{bail, Result};
+
+use crate::protocol::eth_stratum::{
+    difficulty_to_target, target_to_difficulty,
+};
+
+pub use self::{cfx::CFX, etc::ETC, eth::ETH};
+
+// 支持的币种。新增 Ethash 系列币种只需要增加一个模块并加到这里
+pub static COINS: [&dyn Coin; 3] = [&ETH, &ETC, &CFX];
+
+// 币种相关的差异: 任务格式、份额目标值、算力单位、钱包格式和默认端口。
+// 默认实现按 Ethash 处理。
+pub trait Coin: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    // 矿池地址没有写端口时使用
+    fn default_port(&self, ssl: bool) -> u16;
+
+    // 矿池的 eth_getWork 任务整理为内部格式 [header, seed, target, height]
+    fn parse_job(&self, job: &[String]) -> Result<Vec<String>> {
+        if job.len() < 3 {
+            bail!("{} 任务参数不足 {:?}", self.name(), job);
+        }
+
+        let mut res = vec![
+            check_hash(&job[0])?,
+            check_hash(&job[1])?,
+            check_target(&job[2])?,
+        ];
+        if let Some(height) = job.get(3) {
+            res.push(height.clone());
+        }
+        Ok(res)
+    }
+
+    // Stratum mining.notify [job_id, header, seed, target], target
+    // 为空时使用 mining.set_difficulty 换算出来的值
+    fn parse_notify(
+        &self, params: &[String], target: &str,
+    ) -> Result<Vec<String>> {
+        if params.len() < 3 {
+            bail!("{} 任务参数不足 {:?}", self.name(), params);
+        }
+
+        let target = match params.get(3) {
+            Some(t) if !t.is_empty() => t.as_str(),
+            _ => target,
+        };
+        self.parse_job(&[params[1].clone(), params[2].clone(), target.into()])
+    }
+
+    // parse_notify 的逆过程, job_id 使用 header
+    fn notify_params(&self, job: &[String]) -> Vec<String> {
+        vec![
+            job[0].clone(),
+            job[0].clone(),
+            job[1].clone(),
+            job[2].clone(),
+        ]
+    }
+
+    fn share_target(&self, diff: f64) -> String { difficulty_to_target(diff) }
+
+    fn share_difficulty(&self, target: &str) -> f64 {
+        target_to_difficulty(target)
+    }
+
+    // 矿池验证份额时是否需要 mix_digest
+    fn need_mix(&self) -> bool { true }
+
+    // seed 是 keccak 迭代 n 次得到的, 返回 light cache 对应的 epoch。
+    // None 表示不是 Ethash, 不能在本地计算
+    fn ethash_epoch(&self, seed_epoch: u64) -> Option<u64> {
+        Some(seed_epoch)
+    }
+
+    fn hashrate_unit(&self) -> &'static str { "H/s" }
+
+    // 只检查明显写错的钱包, 矿池用户名原样放行
+    fn check_wallet(&self, wallet: &str) -> Result<()> {
+        if wallet.contains(':') {
+            bail!("{} 钱包地址格式错误 {}", self.name(), wallet);
+        }
+        if let Some(hex_str) = wallet.strip_prefix("0x") {
+            if hex_str.len() != 40 || hex::decode(hex_str).is_err() {
+                bail!("{} 钱包地址格式错误 {}", self.name(), wallet);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn from_name(name: &str) -> Result<&'static dyn Coin> {
+    match COINS.iter().find(|c| c.name().eq_ignore_ascii_case(name)) {
+        Some(coin) => Ok(*coin),
+        None => bail!("不支持的代理币种 {}", name),
+    }
+}
+
+fn with_hex_prefix(s: &str) -> String {
+    format!("0x{}", s.trim_start_matches("0x"))
+}
+
+// 32 字节的哈希 (header/seed)
+pub fn check_hash(hash: &str) -> Result<String> {
+    let hex_str = hash.trim_start_matches("0x");
+    if hex_str.len() != 64 || hex::decode(hex_str).is_err() {
+        bail!("哈希格式错误 {}", hash);
+    }
+    Ok(with_hex_prefix(hex_str))
+}
+
+// 份额目标值, 不足 32 字节的补齐
+pub fn check_target(target: &str) -> Result<String> {
+    let hex_str = target.trim_start_matches("0x");
+    if hex_str.is_empty()
+        || hex_str.len() > 64
+        || !hex_str.chars().all(|c| c.is_ascii_hexdigit())
+        || hex_str.chars().all(|c| c == '0')
+    {
+        bail!("目标值格式错误 {}", target);
+    }
+    Ok(format!("0x{:0>64}", hex_str))
+}
+
+#[test]
+fn test_from_name() {
+    assert_eq!(from_name("eth").unwrap().name(), "ETH");
+    assert_eq!(from_name("CFX").unwrap().name(), "CFX");
+    assert!(from_name("BTC").is_err());
+}
+
+#[test]
+fn test_parse_job() {
+    let header =
+        "0xc9149cc0386e689d789a1c2f3d5d169a61a6218ed30e74414dc736e442ef3d1f";
+    let job =
+        vec![header.to_string(), header[2..].to_string(), "0x1234".into()];
+    let job = ETH.parse_job(&job).unwrap();
+    assert_eq!(job[1], header);
+    assert_eq!(job[2], format!("0x{:0>64}", "1234"));
+
+    assert!(ETH
+        .parse_job(&[header.into(), "0x12".into(), "0x1".into()])
+        .is_err());
+    assert!(ETH
+        .check_wallet("0x98be5c44d574b96b320dffb0ccff116bda433b8e")
+        .is_ok());
+    assert!(ETH.check_wallet("0x98be5c44").is_err());
+    assert!(ETH.check_wallet("username").is_ok());
+}
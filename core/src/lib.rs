@@ -13,6 +13,12 @@ lazy_static! {
         });
 }
 
+// /metrics 接口的访问令牌, 为空时不校验
+lazy_static! {
+    pub static ref METRICS_TOKEN: String =
+        std::env::var("MINING_PROXY_METRICS_TOKEN").unwrap_or_default();
+}
+
 // 已禁用：开发者工作矿机名称（原用于开发者抽水）
 lazy_static! {
     pub static ref DEVELOP_WORKER_NAME: String = {
@@ -53,6 +59,7 @@ pub fn init() {
 }
 
 pub mod client;
+pub mod coin;
 pub mod protocol;
 pub mod proxy;
 pub mod state;
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use super::stratum::{StraumMiningNotify, StraumMiningSet};
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EthLoginNotify {
@@ -16,3 +18,109 @@ pub struct EthSubscriptionNotify {
     pub result: (Vec<String>, String),
     pub error: Value,
 }
+
+pub const ETH_STRATUM_VERSION: &str = "EthereumStratum/1.0.0";
+
+// NiceHash 难度1对应的目标值高128位 0x00000000ffff0000...
+const DIFF1_TARGET_HIGH: f64 = 65535.0 * 1208925819614629174706176.0; // 0xffff * 2^80
+
+// 目标值 (share target) 换算为 EthereumStratum 难度
+pub fn target_to_difficulty(target: &str) -> f64 {
+    let target = target.trim_start_matches("0x");
+    let target = format!("{:0>64}", target);
+    match u128::from_str_radix(&target[..32], 16) {
+        Ok(high) if high > 0 => DIFF1_TARGET_HIGH / high as f64,
+        _ => 0.0,
+    }
+}
+
+// EthereumStratum 难度换算为目标值
+pub fn difficulty_to_target(diff: f64) -> String {
+    let high = if diff > 0.0 {
+        (DIFF1_TARGET_HIGH / diff) as u128
+    } else {
+        u128::MAX
+    };
+    format!("0x{:032x}{}", high, "0".repeat(32))
+}
+
+pub fn new_subscribe_result(
+    id: u64, session_id: &str, extranonce: &str,
+) -> EthSubscriptionNotify {
+    EthSubscriptionNotify {
+        id,
+        result: (
+            vec![
+                "mining.notify".into(),
+                session_id.into(),
+                ETH_STRATUM_VERSION.into(),
+            ],
+            extranonce.into(),
+        ),
+        error: Value::Null,
+    }
+}
+
+pub fn new_set_difficulty(diff: f64) -> StraumMiningSet {
+    StraumMiningSet {
+        id: Value::Null,
+        method: "mining.set_difficulty".into(),
+        params: vec![diff.into()],
+    }
+}
+
+pub fn new_set_extranonce(extranonce: &str) -> StraumMiningSet {
+    StraumMiningSet {
+        id: Value::Null,
+        method: "mining.set_extranonce".into(),
+        params: vec![extranonce.into()],
+    }
+}
+
+// eth_getWork 任务 [header, seed, target, height] 转为
+// mining.notify [job_id, seed, header, clean], 任务ID为不带0x的 header
+pub fn job_to_notify(job: &[String], clean: bool) -> Option<StraumMiningNotify> {
+    if job.len() < 3 {
+        return None;
+    }
+
+    let header = job[0].trim_start_matches("0x");
+    Some(StraumMiningNotify {
+        id: Value::Null,
+        method: "mining.notify".into(),
+        params: vec![
+            header.into(),
+            job[1].trim_start_matches("0x").into(),
+            header.into(),
+            clean.into(),
+        ],
+    })
+}
+
+// 矿机只提交 nonce 的后半段, 需要拼上分配给它的 extranonce
+pub fn full_nonce(extranonce: &str, nonce: &str) -> String {
+    let nonce = nonce.trim_start_matches("0x");
+    if nonce.len() >= 16 {
+        format!("0x{}", nonce)
+    } else {
+        format!("0x{}{}", extranonce, nonce)
+    }
+}
+
+#[test]
+fn test_difficulty_target() {
+    let target = difficulty_to_target(1.0);
+    assert_eq!(
+        target,
+        "0x00000000ffff0000000000000000000000000000000000000000000000000000"
+    );
+    assert_eq!(target_to_difficulty(&target), 1.0);
+    let diff = target_to_difficulty(&difficulty_to_target(4.0));
+    assert!((diff - 4.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_full_nonce() {
+    assert_eq!(full_nonce("a1b2", "0011223344aa"), "0xa1b20011223344aa");
+    assert_eq!(full_nonce("a1b2", "0xa1b20011223344aa"), "0xa1b20011223344aa");
+}
@@ -324,6 +324,23 @@ pub struct EthServerRoot {
     pub result: bool,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EthRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+// 带错误信息的应答, 本地拒绝份额时使用
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EthServerError {
+    pub id: u64,
+    pub jsonrpc: String,
+    pub result: bool,
+    pub error: EthRpcError,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EthServer {
@@ -3,6 +3,7 @@ pub mod ethjson;
 pub mod rpc;
 pub mod stratum;
 
+use anyhow::{bail, Result};
 use num_enum::IntoPrimitive;
 use serde::{Deserialize, Serialize};
 
@@ -11,6 +12,7 @@ pub const CLIENT_GETWORK: u64 = 1005;
 pub const CLIENT_SUBHASHRATE: u64 = 1006;
 pub const CLIENT_SUBMITWORK: u64 = 1000;
 pub const SUBSCRIBE: u64 = 10002;
+pub const EXTRANONCE_SUBSCRIBE: u64 = 10003;
 
 #[derive(
     Debug, Eq, Clone, IntoPrimitive, PartialEq, Serialize, Deserialize,
@@ -22,3 +24,46 @@ pub enum PROTOCOL {
     NICEHASHSTRATUM,
     KNOWN,
 }
+
+// 矿池地址的协议前缀, 如 stratum+tcp://、ethproxy+ssl://。
+// 没有前缀时返回 None, 与矿机使用同一种协议。
+pub fn dialect_from_scheme(scheme: &str) -> Result<Option<PROTOCOL>> {
+    let scheme = scheme.trim_end_matches(':').to_lowercase();
+    let dialect = match scheme.split_once('+') {
+        Some((dialect, _)) => dialect.to_string(),
+        None => return Ok(None),
+    };
+
+    match dialect.as_str() {
+        "ethproxy" | "stratum1" => Ok(Some(PROTOCOL::ETH)),
+        "stratum" => Ok(Some(PROTOCOL::STRATUM)),
+        "ethstratum" | "nicehash" | "stratum2" => {
+            Ok(Some(PROTOCOL::NICEHASHSTRATUM))
+        }
+        _ => bail!("不支持的矿池协议 {}", dialect),
+    }
+}
+
+// 去掉协议前缀后的传输方式 tcp: / ssl:
+pub fn transport_from_scheme(scheme: &str) -> String {
+    let scheme = scheme.to_lowercase();
+    match scheme.split_once('+') {
+        Some((_, transport)) => transport.to_string(),
+        None => scheme,
+    }
+}
+
+#[test]
+fn test_dialect_from_scheme() {
+    assert_eq!(dialect_from_scheme("tcp:").unwrap(), None);
+    assert_eq!(
+        dialect_from_scheme("stratum+tcp:").unwrap(),
+        Some(PROTOCOL::STRATUM)
+    );
+    assert_eq!(
+        dialect_from_scheme("NiceHash+ssl:").unwrap(),
+        Some(PROTOCOL::NICEHASHSTRATUM)
+    );
+    assert!(dialect_from_scheme("foo+tcp:").is_err());
+    assert_eq!(transport_from_scheme("ethproxy+SSL:"), "ssl:");
+}
@@ -1,4 +1,4 @@
-use crate::{client::write_to_socket_byte, state::Worker};
+use crate::{client::write_to_socket_byte, coin::Coin, state::Worker};
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -28,6 +28,20 @@ pub struct StraumResult {
 pub struct StraumResultBool {
     pub id: u64,
     pub result: bool,
+    #[serde(default)]
+    pub error: Value,
+}
+
+//{"id":1,"result":[["mining.notify","..."],"..."],"error":null}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StraumResultValue {
+    pub id: u64,
+    // 有的矿池拒绝时只返回 error
+    #[serde(default)]
+    pub result: Value,
+    #[serde(default)]
+    pub error: Value,
 }
 
 //{\"id\":1001,\"error\":null,\"result\":true}
@@ -42,17 +56,19 @@ pub struct StraumResultWorkNotify {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StraumMiningNotify {
-    pub id: u64,
+    #[serde(default)]
+    pub id: Value,
     pub method: String,
-    pub params: Vec<String>,
+    pub params: Vec<Value>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StraumMiningSet {
+    #[serde(default)]
     pub id: Value,
     pub method: String,
-    pub params: Vec<String>,
+    pub params: Vec<Value>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -62,6 +78,89 @@ pub struct StraumErrorResult {
     pub error: (i64, String, Value),
 }
 
+// 矿池下发给 Stratum 矿机的消息
+#[derive(Debug)]
+pub enum StraumServerMessage {
+    Notify(StraumMiningNotify),
+    SetDifficulty(StraumMiningSet),
+    SetExtranonce(StraumMiningSet),
+    Result(StraumResultValue),
+    Other,
+}
+
+pub fn parse_server_message(buf: &str) -> StraumServerMessage {
+    if let Ok(rpc) = serde_json::from_str::<StraumMiningNotify>(buf) {
+        return match rpc.method.as_str() {
+            "mining.notify" => StraumServerMessage::Notify(rpc),
+            "mining.set_difficulty" => {
+                StraumServerMessage::SetDifficulty(StraumMiningSet {
+                    id: rpc.id,
+                    method: rpc.method,
+                    params: rpc.params,
+                })
+            }
+            "mining.set_extranonce" => {
+                StraumServerMessage::SetExtranonce(StraumMiningSet {
+                    id: rpc.id,
+                    method: rpc.method,
+                    params: rpc.params,
+                })
+            }
+            _ => StraumServerMessage::Other,
+        };
+    }
+
+    if let Ok(rpc) = serde_json::from_str::<StraumResultValue>(buf) {
+        return StraumServerMessage::Result(rpc);
+    }
+
+    StraumServerMessage::Other
+}
+
+// eth_getWork 任务 [header, seed, target, height] 转为 mining.notify
+// 任务ID直接使用 header, 与 eth_submitWork 的 job_id 保持一致。
+// 参数顺序由币种决定
+pub fn job_to_notify(
+    job: &[String], coin: &dyn Coin,
+) -> Option<StraumMiningNotify> {
+    if job.len() < 3 {
+        return None;
+    }
+
+    Some(StraumMiningNotify {
+        id: Value::Null,
+        method: "mining.notify".into(),
+        params: coin
+            .notify_params(job)
+            .into_iter()
+            .map(Value::String)
+            .collect(),
+    })
+}
+
+fn with_hex_prefix(s: &str) -> String {
+    if s.starts_with("0x") {
+        s.to_string()
+    } else {
+        format!("0x{}", s)
+    }
+}
+
+// mining.submit [worker, job_id, nonce, header, mix] 转为
+// eth_submitWork [nonce, header, mix]。Octopus 等不需要 mix 的币种没有最后一项
+pub fn submit_to_work(params: &[String]) -> Option<Vec<String>> {
+    if params.len() < 4 {
+        return None;
+    }
+
+    Some(
+        params[2..params.len().min(5)]
+            .iter()
+            .map(|p| with_hex_prefix(p))
+            .collect(),
+    )
+}
+
 pub async fn login<W>(
     worker: &mut Worker, w: &mut WriteHalf<W>,
     rpc: &mut Box<dyn EthClientObject + Send + Sync>, worker_name: &mut String,
@@ -96,3 +195,43 @@ where
         bail!("请求登录出错。可能收到暴力攻击");
     }
 }
+
+#[test]
+fn test_job_to_notify() {
+    let job = vec!["0xaa".to_string(), "0xbb".into(), "0xcc".into()];
+    let notify = job_to_notify(&job, &crate::coin::ETH).unwrap();
+    assert_eq!(notify.method, "mining.notify");
+    assert_eq!(notify.params[0], Value::String("0xaa".into()));
+    assert_eq!(notify.params[2], Value::String("0xbb".into()));
+    assert!(job_to_notify(&job[..2], &crate::coin::ETH).is_none());
+}
+
+#[test]
+fn test_submit_to_work() {
+    let params = vec![
+        "wallet.rig".to_string(),
+        "0xaa".into(),
+        "1234".into(),
+        "0xaa".into(),
+        "0xdd".into(),
+    ];
+    assert_eq!(
+        submit_to_work(&params).unwrap(),
+        vec!["0x1234".to_string(), "0xaa".into(), "0xdd".into()]
+    );
+    assert!(submit_to_work(&params[..3]).is_none());
+}
+
+#[test]
+fn test_parse_server_message() {
+    let notify = r#"{"id":null,"method":"mining.notify","params":["1","0xaa","0xbb","0xcc",true]}"#;
+    assert!(matches!(
+        parse_server_message(notify),
+        StraumServerMessage::Notify(_)
+    ));
+    let result = r#"{"id":1001,"result":true,"error":null}"#;
+    assert!(matches!(
+        parse_server_message(result),
+        StraumServerMessage::Result(_)
+    ));
+}
@@ -1,8 +1,22 @@
 use std::{sync::Arc, collections::VecDeque};
 
-use tokio::sync::{broadcast::Sender, mpsc::UnboundedSender, RwLock, Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::Sender,
+    mpsc::{self, Receiver, UnboundedSender},
+    RwLock, Mutex,
+};
 
-use crate::{state::Worker, util::config::Settings};
+use crate::{
+    client::{
+        aggregate::Aggregator,
+        fee::{FeePoolStatus, FeeStats},
+        health::{PoolHealth, PoolStatus},
+        traffic::Traffic,
+    },
+    state::Worker,
+    util::config::{FeeDestination, Settings},
+};
 
 pub type Job =    Arc<RwLock<VecDeque<Vec<String>>>>;
 
@@ -11,11 +25,95 @@ pub struct Proxy {
     pub config: Arc<RwLock<Settings>>,
     // pub chan: Sender<Vec<String>>,
     // pub dev_chan: Sender<Vec<String>>,
-    pub fee_job:Job,
+    // 每个抽水目标一个连接和任务队列
+    pub fees: Vec<FeeTarget>,
     pub develop_job:Job,
-    pub tx: tokio::sync::mpsc::Sender<Vec<String>>,
     pub dev_tx: tokio::sync::mpsc::Sender<Vec<String>>,
     pub worker_tx: UnboundedSender<Worker>,
+    // 聚合模式下共用的矿池连接
+    pub aggregator: Arc<Aggregator>,
+    // 矿池健康状态和当前使用的矿池
+    pub pool_health: Arc<PoolHealth>,
+    // 矿机连接的收发字节数
+    pub traffic: Arc<Traffic>,
     // pub proxy_write: Arc<Mutex<Box<dyn AsyncWrite + Send + Sync + Unpin>>>,
     // pub dev_write: Arc<Mutex<Box<dyn AsyncWrite + Send + Sync + Unpin>>>,
 }
+
+// 中转进程定时上报给主控的运行状态
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyStatus {
+    pub pools: Vec<PoolStatus>,
+    pub active_pool: usize,
+    // 抽水和开发者任务队列的长度
+    pub fee_jobs: usize,
+    pub develop_jobs: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // 每个抽水目标的矿池连接和提交结果
+    #[serde(default)]
+    pub fee_pools: Vec<FeePoolStatus>,
+}
+
+// 交给抽水连接提交的份额, 矿池的结果回传给提交份额的矿机连接
+#[derive(Debug)]
+pub struct FeeShare {
+    pub params: Vec<String>,
+    // 抽水目标的序号, 与结果一起回传
+    pub dest: usize,
+    pub result: UnboundedSender<(usize, bool)>,
+}
+
+impl FeeShare {
+    pub fn reply(&self, accepted: bool) {
+        let _ = self.result.send((self.dest, accepted));
+    }
+}
+
+// 一个抽水目标: 任务队列、交给抽水连接的份额和矿池的提交结果。
+// rate 为调度时的权重
+pub struct FeeTarget {
+    pub name: String,
+    pub rate: f64,
+    pub job: Job,
+    pub tx: mpsc::Sender<FeeShare>,
+    pub stats: Arc<FeeStats>,
+}
+
+impl FeeTarget {
+    // 返回的接收端交给这个目标的抽水连接
+    pub fn new(dest: &FeeDestination) -> (Self, Receiver<FeeShare>) {
+        let (tx, rx) = mpsc::channel(FEE_SHARE_QUEUE);
+        let target = Self {
+            name: dest.name.clone(),
+            rate: dest.rate.into(),
+            job: Arc::new(RwLock::new(VecDeque::new())),
+            tx,
+            stats: Arc::new(FeeStats::new(&dest.name)),
+        };
+        (target, rx)
+    }
+}
+
+// 等待抽水连接提交的份额数
+const FEE_SHARE_QUEUE: usize = 15;
+
+impl Proxy {
+    pub async fn status(&self) -> ProxyStatus {
+        let (bytes_in, bytes_out) = self.traffic.get();
+        let mut fee_jobs = 0;
+        for fee in &self.fees {
+            fee_jobs += fee.job.read().await.len();
+        }
+        ProxyStatus {
+            pools: self.pool_health.status(),
+            active_pool: self.pool_health.active(),
+            fee_jobs,
+            develop_jobs: self.develop_job.read().await.len(),
+            bytes_in,
+            bytes_out,
+            fee_pools: self.fees.iter().map(|f| f.stats.status()).collect(),
+        }
+    }
+}
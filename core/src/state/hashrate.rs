@@ -0,0 +1,96 @@
+use std::{collections::VecDeque, time::Instant};
+
+// 难度 1 对应的哈希次数
+const DIFF1_HASHES: f64 = 4294967296.0;
+
+// 按份额难度统计的实际算力。最近一小时按分钟累计, 一天内按小时累计,
+// 窗口边缘的误差不超过一个桶。
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashMeter {
+    start: Instant,
+    // (分钟序号, 哈希数)
+    minutes: VecDeque<(u64, f64)>,
+    // (小时序号, 哈希数)
+    hours: VecDeque<(u64, f64)>,
+}
+
+impl Default for HashMeter {
+    fn default() -> Self { Self::new(Instant::now()) }
+}
+
+impl HashMeter {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            minutes: VecDeque::new(),
+            hours: VecDeque::new(),
+        }
+    }
+
+    fn add_to(buckets: &mut VecDeque<(u64, f64)>, idx: u64, hashes: f64) {
+        match buckets.back_mut() {
+            Some((last, sum)) if *last == idx => *sum += hashes,
+            _ => buckets.push_back((idx, hashes)),
+        }
+    }
+
+    // 记一个有效份额, difficulty 为矿机挖这个份额时的难度
+    pub fn add_share(&mut self, difficulty: f64, now: Instant) {
+        let secs = now.duration_since(self.start).as_secs();
+        let (minute, hour) = (secs / 60, secs / 3600);
+        let hashes = difficulty * DIFF1_HASHES;
+        Self::add_to(&mut self.minutes, minute, hashes);
+        Self::add_to(&mut self.hours, hour, hashes);
+
+        while matches!(self.minutes.front(), Some((m, _)) if m + 60 <= minute) {
+            self.minutes.pop_front();
+        }
+        while matches!(self.hours.front(), Some((h, _)) if h + 24 <= hour) {
+            self.hours.pop_front();
+        }
+    }
+
+    // 最近 window 秒内的平均算力 (H/s)。统计时间不足一个窗口时按实际时长计算
+    pub fn hashrate(&self, window: u64, now: Instant) -> u64 {
+        let secs = now.duration_since(self.start).as_secs();
+        let (buckets, size) = if window <= 3600 {
+            (&self.minutes, 60)
+        } else {
+            (&self.hours, 3600)
+        };
+
+        let first = secs.saturating_sub(window) / size;
+        let hashes: f64 = buckets
+            .iter()
+            .filter(|(idx, _)| *idx >= first)
+            .map(|(_, hashes)| hashes)
+            .sum();
+
+        let elapsed = secs.min(window).max(1);
+        (hashes / elapsed as f64) as u64
+    }
+}
+
+#[test]
+fn test_hash_meter() {
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let mut meter = HashMeter::new(start);
+    // 10 分钟, 每分钟 10 个难度 1 的份额
+    for i in 0..100 {
+        meter.add_share(1.0, start + Duration::from_secs(i * 6));
+    }
+
+    let now = start + Duration::from_secs(600);
+    let expected = (10.0 * DIFF1_HASHES / 60.0) as u64;
+    let rate_1h = meter.hashrate(3600, now);
+    assert!(rate_1h.abs_diff(expected) < expected / 100);
+    assert!(meter.hashrate(300, now).abs_diff(expected) < expected / 5);
+    assert_eq!(meter.hashrate(86400, now), rate_1h);
+
+    // 之后没有份额, 5 分钟算力归零, 一小时算力下降
+    let now = start + Duration::from_secs(1800);
+    assert_eq!(meter.hashrate(300, now), 0);
+    assert!(meter.hashrate(3600, now) < rate_1h);
+}
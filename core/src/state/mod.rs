@@ -4,11 +4,15 @@ extern crate serde_millis;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::{collections::BTreeMap, time::Instant};
 use tracing::{debug, info};
 
 use crate::protocol::PROTOCOL;
 
+pub mod hashrate;
+
+use self::hashrate::HashMeter;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Worker {
     pub worker: String,
@@ -22,6 +26,15 @@ pub struct Worker {
     pub last_subwork_time: Instant,
     pub rpc_id: u64,
     pub hash: u64,
+    // 按有效份额难度计算的实际算力, hash 是矿机自己上报的
+    #[serde(default)]
+    pub hash_5m: u64,
+    #[serde(default)]
+    pub hash_1h: u64,
+    #[serde(default)]
+    pub hash_24h: u64,
+    #[serde(skip)]
+    meter: HashMeter,
     pub total_send_idx: u128,
     pub total_dev_idx: u128,
     pub total_fee_idx: u128,
@@ -29,9 +42,29 @@ pub struct Worker {
     pub share_index: u64,
     pub accept_index: u64,
     pub invalid_index: u64,
+    // 本地校验判定为过期的份额, 同时计入 invalid_index
+    #[serde(default)]
+    pub stale_index: u64,
     pub fee_share_index: u64,
     pub fee_accept_index: u64,
     pub fee_invalid_index: u64,
+    // 有效份额按难度累加, 矿工自己的和抽水的分开, 不含开发者抽水
+    #[serde(default)]
+    pub share_diff_sum: f64,
+    #[serde(default)]
+    pub fee_diff_sum: f64,
+    // 按抽水目标名称分开的抽水份额
+    #[serde(default)]
+    pub fee_accounts: BTreeMap<String, FeeAccount>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeAccount {
+    pub shares: u64,
+    pub accepts: u64,
+    pub rejects: u64,
+    // 有效份额的难度之和, 调度时按它与费率的比例选择抽水目标
+    pub diff_sum: f64,
 }
 
 impl Worker {
@@ -48,15 +81,23 @@ impl Worker {
             last_subwork_time: Instant::now(),
             protocol: PROTOCOL::KNOWN,
             hash: 0,
+            hash_5m: 0,
+            hash_1h: 0,
+            hash_24h: 0,
+            meter: HashMeter::default(),
             total_send_idx: 0,
             total_fee_idx: 0,
             total_dev_idx: 0,
             share_index: 0,
             accept_index: 0,
             invalid_index: 0,
+            stale_index: 0,
             fee_share_index: 0,
             fee_accept_index: 0,
             fee_invalid_index: 0,
+            share_diff_sum: 0.0,
+            fee_diff_sum: 0.0,
+            fee_accounts: BTreeMap::new(),
             rpc_id: 0,
         }
     }
@@ -71,15 +112,23 @@ impl Worker {
             login_time: Instant::now(),
             last_subwork_time: Instant::now(),
             hash: 0,
+            hash_5m: 0,
+            hash_1h: 0,
+            hash_24h: 0,
+            meter: HashMeter::default(),
             share_index: 0,
             accept_index: 0,
             total_send_idx: 0,
             total_fee_idx: 0,
             total_dev_idx: 0,
             invalid_index: 0,
+            stale_index: 0,
             fee_share_index: 0,
             fee_accept_index: 0,
             fee_invalid_index: 0,
+            share_diff_sum: 0.0,
+            fee_diff_sum: 0.0,
+            fee_accounts: BTreeMap::new(),
             rpc_id: 0,
         }
     }
@@ -144,6 +193,7 @@ impl Worker {
         self.share_index = 0;
         self.accept_index = 0;
         self.invalid_index = 0;
+        self.stale_index = 0;
         //self.login_time = Instant::now();
     }
 
@@ -167,6 +217,12 @@ impl Worker {
         debug!("矿工: {} Share Reject #{}", self.worker, self.share_index);
     }
 
+    // 过期的份额
+    pub fn share_stale(&mut self) {
+        self.stale_index += 1;
+        self.share_reject();
+    }
+
     // 总份额增加
     pub fn fee_share_index_add(&mut self) {
         //self.last_subwork_time = Instant::now();
@@ -187,6 +243,43 @@ impl Worker {
         //debug!("矿工: {} Share Reject #{}", self.worker, self.share_index);
     }
 
+    pub fn fee_account(&mut self, dest: &str) -> &mut FeeAccount {
+        self.fee_accounts.entry(dest.to_string()).or_default()
+    }
+
+    // 抽水目标 dest 的有效份额
+    pub fn add_fee_diff(&mut self, dest: &str, difficulty: f64) {
+        self.add_share_diff(difficulty, true);
+        self.fee_account(dest).diff_sum += difficulty;
+    }
+
+    // 有效份额按难度计入, fee 为抽水任务的份额
+    pub fn add_share_diff(&mut self, difficulty: f64, fee: bool) {
+        if fee {
+            self.fee_diff_sum += difficulty;
+        } else {
+            self.share_diff_sum += difficulty;
+        }
+    }
+
+    // 实际的抽水比例: 抽水份额的难度占全部份额难度的比例。
+    // 不同任务的难度不同时按份额数量计算会有偏差, 没有难度时才按数量
+    pub fn fee_ratio(&self) -> f64 { fee_ratio(std::iter::once(self)) }
+
+    // 有效份额, difficulty 为矿机挖这个份额时的难度
+    pub fn share_difficulty(&mut self, difficulty: f64) {
+        self.meter.add_share(difficulty, Instant::now());
+        self.update_hashrate();
+    }
+
+    // 没有新份额时实际算力也要随时间下降, 上报状态前调用
+    pub fn update_hashrate(&mut self) {
+        let now = Instant::now();
+        self.hash_5m = self.meter.hashrate(300, now);
+        self.hash_1h = self.meter.hashrate(3600, now);
+        self.hash_24h = self.meter.hashrate(86400, now);
+    }
+
     pub fn submit_hashrate<T>(&mut self, rpc: &T) -> bool
     where T: crate::protocol::rpc::eth::ClientRpc {
         self.hash = rpc.get_submit_hashrate();
@@ -204,6 +297,24 @@ impl Worker {
     }
 }
 
+// 多台矿机合计的实际抽水比例
+pub fn fee_ratio<'a>(workers: impl IntoIterator<Item = &'a Worker>) -> f64 {
+    let (mut diff, mut fee_diff, mut shares, mut fee_shares) = (0.0, 0.0, 0, 0);
+    for w in workers {
+        diff += w.share_diff_sum;
+        fee_diff += w.fee_diff_sum;
+        shares += w.share_index;
+        fee_shares += w.fee_share_index;
+    }
+    if diff + fee_diff > 0.0 {
+        return fee_diff / (diff + fee_diff);
+    }
+    if shares + fee_shares == 0 {
+        return 0.0;
+    }
+    fee_shares as f64 / (shares + fee_shares) as f64
+}
+
 #[test]
 fn test_new_work() {
     let w = Worker::default();
@@ -238,3 +349,27 @@ fn test_share_reject() {
     assert_eq!(w.accept_index, 0);
     assert_eq!(w.invalid_index, 1);
 }
+
+#[test]
+fn test_share_stale() {
+    let mut w = Worker::default();
+    w.share_stale();
+    assert_eq!(w.stale_index, 1);
+    assert_eq!(w.invalid_index, 1);
+}
+
+#[test]
+fn test_fee_ratio() {
+    let mut a = Worker::default();
+    let mut b = Worker::default();
+    assert_eq!(fee_ratio([&a, &b]), 0.0);
+    a.share_index = 9;
+    a.fee_share_index = 1;
+    assert_eq!(a.fee_ratio(), 0.1);
+    // 有难度时按难度计算
+    a.add_share_diff(90.0, false);
+    a.add_share_diff(30.0, true);
+    b.add_share_diff(80.0, false);
+    assert_eq!(a.fee_ratio(), 0.25);
+    assert_eq!(fee_ratio([&a, &b]), 0.15);
+}
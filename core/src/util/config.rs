@@ -3,7 +3,11 @@ use config::{Config, ConfigError, Environment, File};
 use serde::{Deserialize, Serialize};
 use std::{env, net::TcpListener};
 
-use crate::client::{SSL, TCP};
+use crate::{
+    client::{connect_pools, pool_url::PoolUrl, tls_client::TlsOptions},
+    coin::Coin,
+    protocol::PROTOCOL,
+};
 
 use super::get_develop_fee;
 
@@ -25,6 +29,70 @@ pub struct Settings {
     pub share_alg: u32,
     pub pem_path: String,
     pub key_path: String,
+    // 提交给矿池前在本地校验份额 (Ethash light cache)
+    #[serde(default)]
+    pub verify_share: bool,
+    // 矿机每分钟的目标份额数, 0 表示直接使用矿池难度
+    #[serde(default)]
+    pub vardiff_spm: u32,
+    // 每个钱包共用的矿池连接数, 0 表示每台矿机单独连接矿池
+    #[serde(default)]
+    pub aggregate: u32,
+    // 连接矿池的超时秒数, 0 表示默认 10 秒
+    #[serde(default)]
+    pub connect_timeout: u64,
+    // 重新解析矿池域名的间隔秒数, 0 表示默认 60 秒
+    #[serde(default)]
+    pub dns_refresh: u64,
+    // 连接 SSL 矿池时额外信任的 CA 证书文件, 为空时只用内置根证书
+    #[serde(default)]
+    pub pool_tls_ca: String,
+    // 不校验 SSL 矿池的证书
+    #[serde(default)]
+    pub pool_tls_insecure: bool,
+    // 固定 SSL 矿池的公钥 (SPKI SHA-256), 匹配其中之一即可
+    #[serde(default)]
+    pub pool_tls_pins: Vec<String>,
+    // 证书文件不存在时生成自签名证书使用的名称、域名/IP 和有效天数
+    #[serde(default)]
+    pub cert_cn: String,
+    #[serde(default)]
+    pub cert_sans: Vec<String>,
+    #[serde(default)]
+    pub cert_days: u32,
+    // 加密端口隧道的预共享密钥, 为空时只接受旧的明文协议
+    #[serde(default)]
+    pub encrypt_psk: String,
+    // 设置了预共享密钥后是否仍接受旧的明文协议
+    #[serde(default)]
+    pub encrypt_legacy: bool,
+    // 多个抽水目标按费率分配抽水。为空时使用 share_address 等旧的设置,
+    // 不为空时 share_rate 为各目标费率之和
+    #[serde(default)]
+    pub fee_destinations: Vec<FeeDestination>,
+}
+
+// 一个抽水目标: 矿池、收款钱包、矿工名和费率
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FeeDestination {
+    // 用于统计和日志, 不能重复
+    pub name: String,
+    pub address: Vec<String>,
+    pub wallet: String,
+    // 为空时使用主机名, 不使用旧的 share_name
+    pub worker: String,
+    pub rate: f32,
+}
+
+// 抽水连接只会 ETHProxy 协议 (eth_submitLogin/eth_getWork),
+// 其他协议的矿池收不到任务
+fn check_fee_address(addr: &str) -> Result<()> {
+    let url = PoolUrl::parse(addr)?;
+    if !matches!(url.dialect, None | Some(PROTOCOL::ETH)) {
+        bail!("抽水矿池只支持 ETHProxy 协议");
+    }
+    Ok(())
 }
 
 impl Default for Settings {
@@ -46,6 +114,20 @@ impl Default for Settings {
             hash_rate: 100,
             pool_address: Vec::new(),
             share_address: Vec::new(),
+            verify_share: false,
+            vardiff_spm: 0,
+            aggregate: 0,
+            connect_timeout: 0,
+            dns_refresh: 0,
+            pool_tls_ca: "".into(),
+            pool_tls_insecure: false,
+            pool_tls_pins: Vec::new(),
+            cert_cn: "".into(),
+            cert_sans: Vec::new(),
+            cert_days: 0,
+            encrypt_psk: "".into(),
+            encrypt_legacy: false,
+            fee_destinations: Vec::new(),
         }
     }
 }
@@ -77,10 +159,29 @@ impl Settings {
         }
 
         if let Ok(address) = env::var("PROXY_SHARE_ADDRESS") {
-            let arr: Vec<&str> = address.split(',').collect();
+            let arr: Vec<&str> =
+                address.split(',').filter(|a| !a.is_empty()).collect();
             s.set("share_address", arr)?;
         }
 
+        // 抽水目标是结构体列表, 环境变量中为 JSON, 不经过 Config 解析
+        let destinations = env::var("PROXY_FEE_DESTINATIONS").ok();
+        if destinations.is_some() {
+            s.set("fee_destinations", Vec::<String>::new())?;
+        }
+
+        if let Ok(pins) = env::var("PROXY_POOL_TLS_PINS") {
+            let arr: Vec<&str> =
+                pins.split(',').filter(|p| !p.is_empty()).collect();
+            s.set("pool_tls_pins", arr)?;
+        }
+
+        if let Ok(sans) = env::var("PROXY_CERT_SANS") {
+            let arr: Vec<&str> =
+                sans.split(',').filter(|s| !s.is_empty()).collect();
+            s.set("cert_sans", arr)?;
+        }
+
         // match env::var("PROXY_POOL_TCP_ADDRESS") {
         //     Ok(tcp_address) => {
         //         let arr: Vec<&str> = tcp_address.split(',').collect();
@@ -112,7 +213,70 @@ impl Settings {
         //     }
         //     Err(_) => {}
         // }
-        s.try_into()
+        let mut settings: Settings = s.try_into()?;
+        if let Some(json) = destinations {
+            settings.fee_destinations = serde_json::from_str(&json)
+                .map_err(|e| ConfigError::Message(e.to_string()))?;
+        }
+        settings.fill_fee_destinations();
+        settings.fill_default_ports();
+        Ok(settings)
+    }
+
+    pub fn coin(&self) -> Result<&'static dyn Coin> {
+        crate::coin::from_name(&self.coin)
+    }
+
+    // 矿池地址没有写端口时补上币种的默认端口
+    pub fn fill_default_ports(&mut self) {
+        let coin = match self.coin() {
+            Ok(coin) => coin,
+            Err(_) => return,
+        };
+
+        let fee_addresses = self
+            .fee_destinations
+            .iter_mut()
+            .flat_map(|d| d.address.iter_mut());
+        for addr in self
+            .pool_address
+            .iter_mut()
+            .chain(self.share_address.iter_mut())
+            .chain(fee_addresses)
+        {
+            if let Ok(mut url) = PoolUrl::parse(addr) {
+                if !url.has_port() {
+                    url.address = format!(
+                        "{}:{}",
+                        url.address,
+                        coin.default_port(url.ssl)
+                    );
+                    *addr = url.to_string();
+                }
+            }
+        }
+    }
+
+    // 设置了多个抽水目标时, 总费率为各目标费率之和
+    pub fn fill_fee_destinations(&mut self) {
+        if !self.fee_destinations.is_empty() {
+            self.share_rate =
+                self.fee_destinations.iter().map(|d| d.rate).sum();
+        }
+    }
+
+    // 抽水目标列表, 没有设置时由旧的 share_address 等设置组成一个
+    pub fn fee_destinations(&self) -> Vec<FeeDestination> {
+        if !self.fee_destinations.is_empty() {
+            return self.fee_destinations.clone();
+        }
+        vec![FeeDestination {
+            name: "fee".into(),
+            address: self.share_address.clone(),
+            wallet: self.share_wallet.clone(),
+            worker: self.share_name.clone(),
+            rate: self.share_rate,
+        }]
     }
 
     pub fn get_fee(&self) -> f64 {
@@ -125,7 +289,12 @@ impl Settings {
     }
 
     pub fn get_share_name(&self) -> Result<String> {
-        let mut hostname = self.share_name.clone();
+        self.fee_worker_name(&self.share_name)
+    }
+
+    // 抽水矿工名, 为空时使用主机名
+    pub fn fee_worker_name(&self, worker: &str) -> Result<String> {
+        let mut hostname = worker.to_string();
         if hostname.is_empty() {
             let name = hostname::get()?;
             if name.is_empty() {
@@ -137,97 +306,151 @@ impl Settings {
         Ok(hostname)
     }
 
+    pub fn tls_options(&self) -> Result<TlsOptions> {
+        TlsOptions::new(
+            &self.pool_tls_ca,
+            self.pool_tls_insecure,
+            &self.pool_tls_pins,
+        )
+    }
+
     pub async fn check(&self) -> Result<()> {
         if self.share_rate > 1.0 && self.share_rate < 0.001 {
             bail!("抽水费率不正确不能大于1.或小于0.001")
         };
 
-        if self.share_name.is_empty() {
+        // 设置了抽水目标时不使用旧的 share_name 和 share_address
+        let legacy_fee = self.fee_destinations.is_empty();
+        if legacy_fee && self.share_name.is_empty() {
             bail!("抽水旷工名称未设置")
         };
 
+        if self.share_alg > crate::util::SHARE_ALG_WINDOW {
+            bail!(
+                "抽水算法 {} 不存在, 可选 0 随机 1 取模 2 时间段",
+                self.share_alg
+            )
+        };
+
         if self.pool_address.is_empty() {
             bail!("代理池地址为空")
         };
 
-        if self.share_address.is_empty() {
+        if legacy_fee && self.share_address.is_empty() {
             bail!("抽水矿池代理池地址为空")
         };
 
-        match self.coin.as_str() {
-            "ETH" => {}
-            "ETC" => {}
-            "CFX" => {}
-            _ => {
-                bail!("不支持的代理币种 {}", self.coin)
-            }
-        }
+        let coin = self.coin()?;
 
         if self.tcp_port == 0 && self.ssl_port == 0 && self.encrypt_port == 0 {
             bail!("本地监听端口必须启动一个。目前全部为0")
         };
 
-        if self.share != 0 && self.share_wallet.is_empty() {
-            bail!("抽水模式或统一钱包功能，收款钱包不能为空。")
+        if legacy_fee {
+            if self.share != 0 && self.share_wallet.is_empty() {
+                bail!("抽水模式或统一钱包功能，收款钱包不能为空。")
+            }
+
+            if self.share != 0 {
+                coin.check_wallet(&self.share_wallet)?;
+            }
+
+            for addr in &self.share_address {
+                if let Err(e) = check_fee_address(addr) {
+                    bail!("抽水矿池地址 {} 设置错误: {}", addr, e)
+                }
+            }
+        }
+
+        self.check_fee_destinations(coin)?;
+
+        for addr in &self.pool_address {
+            if let Err(e) = PoolUrl::parse(addr) {
+                bail!("矿池地址 {} 设置错误: {}", addr, e)
+            }
+        }
+
+        if let Err(e) = self.tls_options() {
+            bail!("矿池 SSL 设置错误: {}", e)
+        }
+
+        if !self.encrypt_psk.is_empty() && self.encrypt_psk.len() < 8 {
+            bail!("加密端口的预共享密钥至少需要 8 位")
+        }
+
+        // 需要在本地计算份额哈希才能筛选出达到矿池难度的份额
+        if self.vardiff_spm != 0 && coin.ethash_epoch(0).is_none() {
+            bail!("{} 不支持代理端调整矿机难度", coin.name())
+        }
+
+        // 聚合连接用 EthClientWorkerObject.worker 区分矿机, 只有 ETHProxy 支持
+        if self.aggregate != 0
+            && crate::client::get_pool_dialects(&self.pool_address)
+                .iter()
+                .any(|d| !matches!(d, None | Some(PROTOCOL::ETH)))
+        {
+            bail!("聚合模式只支持 ETHProxy 协议的矿池")
         }
 
         Ok(())
     }
 
-    pub async fn check_net_work(&self) -> Result<()> {
-        let (stream_type, pools) =
-            match crate::client::get_pool_ip_and_type_from_vec(
-                &self.share_address,
-            ) {
-                Ok(s) => s,
-                Err(e) => {
-                    bail!("{}", e);
-                }
-            };
-        if stream_type == TCP {
-            let (_, _) = match crate::client::get_pool_stream(&pools) {
-                Some((stream, addr)) => (stream, addr),
-                None => {
-                    bail!("无法链接到TCP代理矿池");
+    fn check_fee_destinations(&self, coin: &dyn Coin) -> Result<()> {
+        let mut total = 0.0;
+        for (idx, dest) in self.fee_destinations.iter().enumerate() {
+            if dest.name.is_empty() {
+                bail!("第 {} 个抽水目标没有设置名称", idx + 1)
+            }
+            if self.fee_destinations[..idx].iter().any(|d| d.name == dest.name)
+            {
+                bail!("抽水目标名称 {} 重复", dest.name)
+            }
+            if dest.address.is_empty() {
+                bail!("抽水目标 {} 的矿池地址为空", dest.name)
+            }
+            for addr in &dest.address {
+                if let Err(e) = check_fee_address(addr) {
+                    bail!(
+                        "抽水目标 {} 的矿池地址 {} 设置错误: {}",
+                        dest.name,
+                        addr,
+                        e
+                    )
                 }
-            };
-        } else if stream_type == SSL {
-            let (_, _) =
-                match crate::client::get_pool_stream_with_tls(&pools).await {
-                    Some((stream, addr)) => (stream, addr),
-                    None => {
-                        bail!("无法链接到SSL代理矿池");
-                    }
-                };
+            }
+            if dest.wallet.is_empty() {
+                bail!("抽水目标 {} 的收款钱包为空", dest.name)
+            }
+            coin.check_wallet(&dest.wallet)?;
+            if dest.rate <= 0.0 || dest.rate > 1.0 {
+                bail!("抽水目标 {} 的费率 {} 不正确", dest.name, dest.rate)
+            }
+            total += dest.rate;
         }
+        if total > 1.0 {
+            bail!("抽水目标的费率之和 {} 不能大于 1", total)
+        }
+        Ok(())
+    }
 
+    pub async fn check_net_work(&self) -> Result<()> {
+        // 每个矿池按自己的传输方式连接
+        let tls = self.tls_options()?;
+        if let Err(e) = connect_pools(&self.pool_address, &tls).await {
+            bail!("无法链接到代理矿池 {}", e);
+        }
+
+        // 抽水矿池连不上时只提示, 启动后由 fee_pools 按退避间隔重连,
+        // 期间不下发这个目标的抽水任务
         if self.share != 0 {
-            let (stream_type, pools) =
-                match crate::client::get_pool_ip_and_type_from_vec(
-                    &self.share_address,
-                ) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        bail!("{}", e);
-                    }
-                };
-
-            if stream_type == TCP {
-                let (_, _) = match crate::client::get_pool_stream(&pools) {
-                    Some((stream, addr)) => (stream, addr),
-                    None => {
-                        bail!("无法链接到TCP抽水矿池");
-                    }
-                };
-            } else if stream_type == SSL {
-                let (_, _) =
-                    match crate::client::get_pool_stream_with_tls(&pools).await
-                    {
-                        Some((stream, addr)) => (stream, addr),
-                        None => {
-                            bail!("无法链接到SSL抽水矿池");
-                        }
-                    };
+            for dest in self.fee_destinations() {
+                if let Err(e) = connect_pools(&dest.address, &tls).await {
+                    tracing::warn!(
+                        "暂时无法链接到抽水矿池 {} {}",
+                        dest.name,
+                        e
+                    );
+                }
             }
         }
 
@@ -267,3 +490,13 @@ impl Settings {
         }
     }
 }
+
+#[test]
+fn test_check_fee_address() {
+    assert!(check_fee_address("tcp://pool.com:4444").is_ok());
+    assert!(check_fee_address("ssl://pool.com:5555").is_ok());
+    assert!(check_fee_address("ethproxy+tcp://pool.com:4444").is_ok());
+    assert!(check_fee_address("stratum+tcp://pool.com:4444").is_err());
+    assert!(check_fee_address("ethstratum+ssl://pool.com:5555").is_err());
+    assert!(check_fee_address("pool.com:4444").is_err());
+}
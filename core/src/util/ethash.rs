@@ -0,0 +1,349 @@
+// Ethash 轻量验证 (light cache + hashimoto_light)
+// 不需要完整的 DAG, 只用于在代理侧计算 mix_digest 以及校验份额。
+use std::{collections::VecDeque, sync::Arc};
+
+use anyhow::{bail, Result};
+use tiny_keccak::{Hasher, Keccak};
+use tokio::sync::Mutex;
+
+use crate::coin::Coin;
+
+pub const EPOCH_LENGTH: u64 = 30000;
+const MAX_EPOCH: u64 = 4096;
+
+const HASH_BYTES: usize = 64;
+const HASH_WORDS: usize = 16;
+const MIX_BYTES: usize = 128;
+const MIX_WORDS: usize = 32;
+const CACHE_BYTES_INIT: u64 = 1 << 24;
+const CACHE_BYTES_GROWTH: u64 = 1 << 17;
+const DATASET_BYTES_INIT: u64 = 1 << 30;
+const DATASET_BYTES_GROWTH: u64 = 1 << 23;
+const CACHE_ROUNDS: usize = 3;
+const DATASET_PARENTS: u32 = 256;
+const ACCESSES: u32 = 64;
+const FNV_PRIME: u32 = 0x01000193;
+
+// 内存中最多保留的 light cache 个数。换 epoch 时新旧两个都会用到。
+const MAX_CACHES: usize = 2;
+
+lazy_static! {
+    static ref CACHES: Mutex<VecDeque<Arc<LightCache>>> =
+        Mutex::new(VecDeque::new());
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let mut hasher = Keccak::v256();
+    hasher.update(data);
+    hasher.finalize(&mut out);
+    out
+}
+
+fn keccak512(data: &[u8]) -> [u8; 64] {
+    let mut out = [0u8; 64];
+    let mut hasher = Keccak::v512();
+    hasher.update(data);
+    hasher.finalize(&mut out);
+    out
+}
+
+fn keccak512_words(words: &[u32; HASH_WORDS]) -> [u32; HASH_WORDS] {
+    let mut bytes = [0u8; HASH_BYTES];
+    for (i, w) in words.iter().enumerate() {
+        bytes[i * 4..i * 4 + 4].copy_from_slice(&w.to_le_bytes());
+    }
+    to_words(&keccak512(&bytes))
+}
+
+fn to_words(bytes: &[u8; HASH_BYTES]) -> [u32; HASH_WORDS] {
+    let mut words = [0u32; HASH_WORDS];
+    for (i, w) in words.iter_mut().enumerate() {
+        *w = u32::from_le_bytes([
+            bytes[i * 4],
+            bytes[i * 4 + 1],
+            bytes[i * 4 + 2],
+            bytes[i * 4 + 3],
+        ]);
+    }
+    words
+}
+
+fn fnv(a: u32, b: u32) -> u32 {
+    a.wrapping_mul(FNV_PRIME) ^ b
+}
+
+fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    let mut i = 2;
+    while i * i <= n {
+        if n.is_multiple_of(i) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+pub fn cache_size(epoch: u64) -> usize {
+    let mut size =
+        CACHE_BYTES_INIT + CACHE_BYTES_GROWTH * epoch - HASH_BYTES as u64;
+    while !is_prime(size / HASH_BYTES as u64) {
+        size -= 2 * HASH_BYTES as u64;
+    }
+    size as usize
+}
+
+pub fn dataset_size(epoch: u64) -> u64 {
+    let mut size =
+        DATASET_BYTES_INIT + DATASET_BYTES_GROWTH * epoch - MIX_BYTES as u64;
+    while !is_prime(size / MIX_BYTES as u64) {
+        size -= 2 * MIX_BYTES as u64;
+    }
+    size
+}
+
+pub fn seed_hash(epoch: u64) -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    for _ in 0..epoch {
+        seed = keccak256(&seed);
+    }
+    seed
+}
+
+pub fn epoch_from_seed(seed: &[u8]) -> Option<u64> {
+    let mut hash = [0u8; 32];
+    for epoch in 0..MAX_EPOCH {
+        if hash[..] == *seed {
+            return Some(epoch);
+        }
+        hash = keccak256(&hash);
+    }
+    None
+}
+
+fn make_cache(size: usize, seed: &[u8]) -> Vec<u32> {
+    let rows = size / HASH_BYTES;
+    let mut cache: Vec<[u8; HASH_BYTES]> = Vec::with_capacity(rows);
+    cache.push(keccak512(seed));
+    for i in 1..rows {
+        let next = keccak512(&cache[i - 1]);
+        cache.push(next);
+    }
+
+    for _ in 0..CACHE_ROUNDS {
+        for i in 0..rows {
+            let src = (i + rows - 1) % rows;
+            let xor = u32::from_le_bytes([
+                cache[i][0],
+                cache[i][1],
+                cache[i][2],
+                cache[i][3],
+            ]) as usize
+                % rows;
+            let mut temp = [0u8; HASH_BYTES];
+            for (k, t) in temp.iter_mut().enumerate() {
+                *t = cache[src][k] ^ cache[xor][k];
+            }
+            cache[i] = keccak512(&temp);
+        }
+    }
+
+    cache.iter().flat_map(to_words).collect()
+}
+
+fn dataset_item(cache: &[u32], index: u32) -> [u32; HASH_WORDS] {
+    let rows = (cache.len() / HASH_WORDS) as u32;
+    let offset = (index % rows) as usize * HASH_WORDS;
+    let mut mix = [0u32; HASH_WORDS];
+    mix.copy_from_slice(&cache[offset..offset + HASH_WORDS]);
+    mix[0] ^= index;
+    let mut mix = keccak512_words(&mix);
+
+    for j in 0..DATASET_PARENTS {
+        let parent = fnv(index ^ j, mix[j as usize % HASH_WORDS]) % rows;
+        let offset = parent as usize * HASH_WORDS;
+        for (k, m) in mix.iter_mut().enumerate() {
+            *m = fnv(*m, cache[offset + k]);
+        }
+    }
+
+    keccak512_words(&mix)
+}
+
+// 返回 (mix_digest, result)
+fn hashimoto_light(
+    full_size: u64, cache: &[u32], header: &[u8; 32], nonce: u64,
+) -> ([u8; 32], [u8; 32]) {
+    let rows = (full_size / MIX_BYTES as u64) as u32;
+
+    let mut seed = [0u8; 40];
+    seed[..32].copy_from_slice(header);
+    seed[32..].copy_from_slice(&nonce.to_le_bytes());
+    let seed = keccak512(&seed);
+    let seed_words = to_words(&seed);
+
+    let mut mix = [0u32; MIX_WORDS];
+    for (i, m) in mix.iter_mut().enumerate() {
+        *m = seed_words[i % HASH_WORDS];
+    }
+
+    for i in 0..ACCESSES {
+        let parent = fnv(i ^ seed_words[0], mix[i as usize % MIX_WORDS]) % rows;
+        for j in 0..(MIX_BYTES / HASH_BYTES) {
+            let item = dataset_item(cache, 2 * parent + j as u32);
+            for k in 0..HASH_WORDS {
+                let m = &mut mix[j * HASH_WORDS + k];
+                *m = fnv(*m, item[k]);
+            }
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for i in 0..MIX_WORDS / 4 {
+        let c = fnv(
+            fnv(fnv(mix[i * 4], mix[i * 4 + 1]), mix[i * 4 + 2]),
+            mix[i * 4 + 3],
+        );
+        digest[i * 4..i * 4 + 4].copy_from_slice(&c.to_le_bytes());
+    }
+
+    let mut result = [0u8; 96];
+    result[..64].copy_from_slice(&seed);
+    result[64..].copy_from_slice(&digest);
+    (digest, keccak256(&result))
+}
+
+#[derive(Debug)]
+pub struct LightCache {
+    pub epoch: u64,
+    pub seed: [u8; 32],
+    full_size: u64,
+    cache: Vec<u32>,
+}
+
+impl LightCache {
+    pub fn new(epoch: u64) -> Self { Self::with_seed(epoch, seed_hash(epoch)) }
+
+    // ETC 在 ECIP-1099 之后 seed 与 epoch 不再一一对应
+    pub fn with_seed(epoch: u64, seed: [u8; 32]) -> Self {
+        Self {
+            epoch,
+            seed,
+            full_size: dataset_size(epoch),
+            cache: make_cache(cache_size(epoch), &seed),
+        }
+    }
+
+    pub fn compute(
+        &self, header: &[u8; 32], nonce: u64,
+    ) -> ([u8; 32], [u8; 32]) {
+        hashimoto_light(self.full_size, &self.cache, header, nonce)
+    }
+}
+
+pub fn decode_hash(hex_str: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(hex_str.trim_start_matches("0x"))?;
+    if bytes.len() != 32 {
+        bail!("哈希长度错误: {}", hex_str);
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&bytes);
+    Ok(hash)
+}
+
+pub fn decode_nonce(hex_str: &str) -> Result<u64> {
+    Ok(u64::from_str_radix(hex_str.trim_start_matches("0x"), 16)?)
+}
+
+// 计算结果不大于份额目标值即满足难度
+pub fn meets_target(result: &[u8; 32], target: &str) -> Result<bool> {
+    let target =
+        decode_hash(&format!("{:0>64}", target.trim_start_matches("0x")))?;
+    Ok(result[..] <= target[..])
+}
+
+// 按 seed_hash 取得 light cache, 没有则在阻塞线程中生成。
+pub async fn light_cache(
+    seed: &str, coin: &dyn Coin,
+) -> Result<Arc<LightCache>> {
+    let seed = decode_hash(seed)?;
+    let epoch = match epoch_from_seed(&seed) {
+        Some(seed_epoch) => match coin.ethash_epoch(seed_epoch) {
+            Some(epoch) => epoch,
+            None => bail!("{} 不是 Ethash 币种, 无法在本地计算", coin.name()),
+        },
+        None => bail!("未知的 seed_hash: 0x{}", hex::encode(seed)),
+    };
+
+    let mut caches = CACHES.lock().await;
+    if let Some(cache) =
+        caches.iter().find(|c| c.seed == seed && c.epoch == epoch)
+    {
+        return Ok(cache.clone());
+    }
+
+    let cache = tokio::task::spawn_blocking(move || {
+        Arc::new(LightCache::with_seed(epoch, seed))
+    })
+    .await?;
+
+    tracing::info!("生成 {} light cache epoch #{}", coin.name(), cache.epoch);
+    if caches.len() >= MAX_CACHES {
+        caches.pop_front();
+    }
+    caches.push_back(cache.clone());
+    Ok(cache)
+}
+
+// 根据任务的 header/seed 与矿机提交的 nonce 计算 (mix_digest, result)。
+pub async fn compute(
+    header: &str, seed: &str, nonce: &str, coin: &dyn Coin,
+) -> Result<([u8; 32], [u8; 32])> {
+    let header = decode_hash(header)?;
+    let nonce = decode_nonce(nonce)?;
+    let cache = light_cache(seed, coin).await?;
+    Ok(
+        tokio::task::spawn_blocking(move || cache.compute(&header, nonce))
+            .await?,
+    )
+}
+
+#[test]
+fn test_hashimoto_light() {
+    let cache = make_cache(1024, &[0u8; 32]);
+    let header = decode_hash(
+        "0xc9149cc0386e689d789a1c2f3d5d169a61a6218ed30e74414dc736e442ef3d1f",
+    )
+    .unwrap();
+    let (digest, result) = hashimoto_light(32 * 1024, &cache, &header, 0);
+    assert_eq!(
+        hex::encode(digest),
+        "e4073cffaef931d37117cefd9afd27ea0f1cad6a981dd2605c4a1ac97c519800"
+    );
+    assert_eq!(
+        hex::encode(result),
+        "d3539235ee2e6f8db665c0a72169f55b7f6c605712330b778ec3944f0eb5a557"
+    );
+}
+
+#[test]
+fn test_meets_target() {
+    let mut result = [0u8; 32];
+    result[3] = 1;
+    assert!(meets_target(&result, &format!("0x00000001{}", "0".repeat(56)))
+        .unwrap());
+    assert!(!meets_target(&result, &format!("0x00000000{}", "f".repeat(56)))
+        .unwrap());
+    // 不足 32 字节的目标值在左边补零
+    assert!(!meets_target(&result, "0xffff").unwrap());
+}
+
+#[test]
+fn test_epoch_seed() {
+    assert_eq!(cache_size(0), 16776896);
+    assert_eq!(dataset_size(0), 1073739904);
+    assert_eq!(epoch_from_seed(&seed_hash(3)), Some(3));
+}
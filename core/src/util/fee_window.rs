@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+// 按时间段抽水 (share_alg = 2): 每个周期给每台矿机留一段连续的时间挖抽水任务,
+// 费率 1% 时每小时 36 秒。时间段在周期内的位置随机, 避免所有矿机同时切换。
+// 时间段内没有抽水任务或者错过了时, 在时间段结束后连续补上。
+// 抽水与否只能在下发任务时决定, 任务之间的时间按上一次的决定计算。
+pub const WINDOW_PERIOD_SECS: f64 = 3600.0;
+
+#[derive(Debug, Clone)]
+pub struct FeeWindow {
+    rate: f64,
+    period: f64,
+    started: Instant,
+    // 当前周期的序号, 以及抽水时间段在周期内的开始位置 (秒)
+    cycle: Option<u64>,
+    offset: f64,
+    // 上一次决定的时间和结果
+    last: Instant,
+    fee: bool,
+    // 已经抽水的秒数, 以及按费率应该抽水的秒数
+    served: f64,
+    due: f64,
+}
+
+impl FeeWindow {
+    pub fn new(rate: f64, now: Instant) -> Self {
+        Self::with_period(rate, WINDOW_PERIOD_SECS, now)
+    }
+
+    pub fn with_period(rate: f64, period: f64, now: Instant) -> Self {
+        Self {
+            rate: rate.clamp(0.0, 1.0),
+            period,
+            started: now,
+            cycle: None,
+            offset: 0.0,
+            last: now,
+            fee: false,
+            served: 0.0,
+            due: 0.0,
+        }
+    }
+
+    // 每个周期的抽水时长
+    fn length(&self) -> f64 { self.period * self.rate }
+
+    // 下发任务前调用, 返回这个任务是否应该是抽水任务
+    pub fn wants_fee(&mut self, now: Instant) -> bool {
+        let since = now.saturating_duration_since(self.last).as_secs_f64();
+        if self.fee {
+            self.served += since;
+        }
+        self.due += since * self.rate;
+        self.last = now;
+
+        let elapsed = now.saturating_duration_since(self.started).as_secs_f64();
+        let cycle = (elapsed / self.period) as u64;
+        let length = self.length();
+        if self.cycle != Some(cycle) {
+            self.cycle = Some(cycle);
+            self.offset = (self.period - length) * rand::random::<f64>();
+        }
+
+        let owed = self.due - self.served;
+        let pos = elapsed - cycle as f64 * self.period;
+        if pos < self.offset {
+            // 落后超过一个时间段时不等时间段开始
+            owed > length
+        } else if pos < self.offset + length {
+            true
+        } else {
+            // 时间段已过仍然不足时补上, 差几秒的留给下个周期
+            owed > length / 4.0
+        }
+    }
+
+    // 记录这个任务实际是否为抽水任务, 没有抽水任务时为 false
+    pub fn set_fee(&mut self, fee: bool) { self.fee = fee; }
+
+    // 修改费率, 只影响之后的时间
+    pub fn set_rate(&mut self, rate: f64) { self.rate = rate.clamp(0.0, 1.0); }
+}
+
+#[cfg(test)]
+fn simulate(
+    window: &mut FeeWindow, start: Instant, secs: u64,
+    available: impl Fn(u64) -> bool,
+) -> u32 {
+    // 每 5 秒一个任务, 返回开始抽水的次数
+    let mut blocks = 0;
+    let mut fee = false;
+    for t in (0..secs).step_by(5) {
+        let now = start + std::time::Duration::from_secs(t);
+        let next = window.wants_fee(now) && available(t);
+        if next && !fee {
+            blocks += 1;
+        }
+        fee = next;
+        window.set_fee(fee);
+    }
+    blocks
+}
+
+#[test]
+fn test_fee_window() {
+    let start = Instant::now();
+    let mut window = FeeWindow::new(0.01, start);
+    let blocks = simulate(&mut window, start, 10 * 3600, |_| true);
+    // 每小时一段连续的抽水时间
+    assert!((10..=12).contains(&blocks), "{}", blocks);
+    let ratio = window.served / (10.0 * 3600.0);
+    assert!((ratio - 0.01).abs() < 0.001, "{}", ratio);
+
+    // 前两个小时没有抽水任务, 之后补上
+    let mut window = FeeWindow::new(0.01, start);
+    simulate(&mut window, start, 5 * 3600, |t| t >= 2 * 3600);
+    let ratio = window.served / (5.0 * 3600.0);
+    assert!((ratio - 0.01).abs() < 0.002, "{}", ratio);
+
+    let mut window = FeeWindow::new(0.0, start);
+    assert_eq!(simulate(&mut window, start, 3600, |_| true), 0);
+}
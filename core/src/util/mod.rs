@@ -1,4 +1,6 @@
 pub mod config;
+pub mod ethash;
+pub mod fee_window;
 pub mod logger;
 
 extern crate clap;
@@ -88,7 +90,11 @@ fn test_clac_phread_num() {
     assert_eq!(clac_phread_num(0.08), 80);
 }
 
-pub fn is_fee(idx: u128, fee: f64) -> bool { idx % (fee * 1000.0) as u128 == 0 }
+// 第 idx 个任务是否抽水, 连续的任务中按费率均匀地选出抽水任务
+pub fn is_fee(idx: u128, fee: f64) -> bool {
+    let fee = fee.clamp(0.0, 1.0);
+    ((idx + 1) as f64 * fee).floor() > (idx as f64 * fee).floor()
+}
 
 #[test]
 fn test_is_fee() {
@@ -103,7 +109,7 @@ fn test_is_fee() {
         }
     }
 
-    assert_eq!(idx, 190);
+    assert_eq!(idx, 19);
 
     let mut idx = 0;
     for i in 0..1000 {
@@ -203,8 +209,57 @@ pub fn is_fee_random(mut fee: f64) -> bool {
 //     }
 // }
 
+// 抽水算法, 对应配置中的 share_alg
+pub const SHARE_ALG_RANDOM: u32 = 0;
+pub const SHARE_ALG_MODULO: u32 = 1;
+// 按时间段抽水, 见 fee_window
+pub const SHARE_ALG_WINDOW: u32 = 2;
+
+pub fn share_alg_name(alg: u32) -> &'static str {
+    match alg {
+        SHARE_ALG_MODULO => "modulo",
+        SHARE_ALG_WINDOW => "window",
+        _ => "random",
+    }
+}
+
+// 开始纠偏前至少应该有的抽水份额数, 份额太少时比例波动太大
+const FEE_CORRECTION_MIN_SHARES: f64 = 5.0;
+
+// 按矿机实际的抽水比例修正费率: 少抽了就多抽, 多抽了就少抽,
+// 修正后的费率在 0 到设置值的两倍之间
+pub fn corrected_fee_rate(rate: f64, worker: &crate::state::Worker) -> f64 {
+    let shares = (worker.share_index + worker.fee_share_index) as f64;
+    if rate <= 0.0 || shares * rate < FEE_CORRECTION_MIN_SHARES {
+        return rate;
+    }
+    let corrected = rate + (rate - worker.fee_ratio());
+    corrected.clamp(0.0, (rate * 2.0).min(1.0))
+}
+
+// 按费率选择抽水目标: served 为各目标已抽水的难度, 选实际抽水
+// 最落后于费率比例的目标, 相同时选费率高的。没有任务的目标跳过
+pub fn pick_fee_destination(
+    rates: &[f64], served: &[f64], available: &[bool],
+) -> Option<usize> {
+    let total_rate: f64 = rates.iter().sum();
+    let total_served: f64 = served.iter().sum();
+    let behind = |i: usize| {
+        let share = if total_rate > 0.0 { rates[i] / total_rate } else { 0.0 };
+        (total_served * share - served[i], rates[i])
+    };
+    (0..rates.len())
+        .filter(|i| available[*i] && rates[*i] > 0.0)
+        .max_by(|a, b| {
+            behind(*a)
+                .partial_cmp(&behind(*b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+}
+
+// 按任务决定是否抽水, 时间段算法由 FeeWindow 决定
 pub fn fee(idx: u128, config: &Settings, fee: f64) -> bool {
-    if config.share_alg == 1 {
+    if config.share_alg == SHARE_ALG_MODULO {
         return is_fee(idx, fee);
     } else {
         return is_fee_random(fee);
@@ -307,19 +362,14 @@ pub fn get_agent_fee(share_fee: f64) -> f64 {
     0.0
 }
 
-//TODO 整理代码 删除无用代码。 目前折中防止报错
-#[inline(always)]
-pub fn get_eth_wallet() -> String { return "".into(); }
-
-#[inline(always)]
-pub fn get_etc_wallet() -> String { return "".into(); }
-
-#[inline(always)]
-pub fn get_cfx_wallet() -> String { return "".into(); }
+// 相对路径按当前工作目录展开, 中转子进程和 web 端读取同一个文件
+pub fn work_path(path: &str) -> String {
+    let dir = std::env::current_dir().expect("获取当前可执行程序路径错误");
+    dir.join(path).to_str().expect("无法转换路径为字符串").to_string()
+}
 
 pub fn run_server(config: &Settings) -> Result<tokio::process::Child> {
     let exe = std::env::current_exe().expect("无法获取当前可执行程序路径");
-    let exe_path = std::env::current_dir().expect("获取当前可执行程序路径错误");
 
     let mut handle = tokio::process::Command::new(exe);
 
@@ -330,8 +380,11 @@ pub fn run_server(config: &Settings) -> Result<tokio::process::Child> {
         .env("PROXY_TCP_PORT", config.tcp_port.to_string())
         .env("PROXY_SSL_PORT", config.ssl_port.to_string())
         .env("PROXY_ENCRYPT_PORT", config.encrypt_port.to_string())
-        .env("PROXY_POOL_ADDRESS", config.pool_address[0].clone())
-        .env("PROXY_SHARE_ADDRESS", config.share_address[0].clone())
+        .env("PROXY_POOL_ADDRESS", config.pool_address.join(","))
+        .env(
+            "PROXY_SHARE_ADDRESS",
+            config.share_address.first().cloned().unwrap_or_default(),
+        )
         .env("PROXY_SHARE_RATE", config.share_rate.to_string())
         .env("PROXY_SHARE_WALLET", config.share_wallet.to_string())
         .env("PROXY_SHARE_ALG", config.share_alg.to_string())
@@ -339,16 +392,25 @@ pub fn run_server(config: &Settings) -> Result<tokio::process::Child> {
         .env("PROXY_COIN", config.coin.to_string())
         .env("PROXY_SHARE_NAME", config.share_name.to_string())
         .env("PROXY_SHARE", config.share.to_string())
-        .env(
-            "PROXY_PEM_PATH",
-            exe_path.to_str().expect("无法转换路径为字符串").to_string()
-                + config.pem_path.as_str(),
-        )
-        .env(
-            "PROXY_KEY_PATH",
-            exe_path.to_str().expect("无法转换路径为字符串").to_string()
-                + config.key_path.as_str(),
-        );
+        .env("PROXY_VERIFY_SHARE", config.verify_share.to_string())
+        .env("PROXY_VARDIFF_SPM", config.vardiff_spm.to_string())
+        .env("PROXY_AGGREGATE", config.aggregate.to_string())
+        .env("PROXY_CONNECT_TIMEOUT", config.connect_timeout.to_string())
+        .env("PROXY_DNS_REFRESH", config.dns_refresh.to_string())
+        .env("PROXY_POOL_TLS_CA", &config.pool_tls_ca)
+        .env("PROXY_POOL_TLS_INSECURE", config.pool_tls_insecure.to_string())
+        .env("PROXY_POOL_TLS_PINS", config.pool_tls_pins.join(","))
+        .env("PROXY_CERT_CN", &config.cert_cn)
+        .env("PROXY_CERT_SANS", config.cert_sans.join(","))
+        .env("PROXY_CERT_DAYS", config.cert_days.to_string())
+        .env("PROXY_ENCRYPT_PSK", &config.encrypt_psk)
+        .env("PROXY_ENCRYPT_LEGACY", config.encrypt_legacy.to_string())
+        .env("PROXY_PEM_PATH", work_path(&config.pem_path))
+        .env("PROXY_KEY_PATH", work_path(&config.key_path));
+    if !config.fee_destinations.is_empty() {
+        let destinations = serde_json::to_string(&config.fee_destinations)?;
+        handle.env("PROXY_FEE_DESTINATIONS", destinations);
+    }
     match handle.spawn() {
         Ok(t) => Ok(t),
         Err(e) => {
@@ -379,3 +441,61 @@ pub fn human_bytes<T: Into<f64>>(size: T) -> String {
 
     result
 }
+
+// 按币种的算力单位显示, 如 12.3 MH/s
+pub fn human_hashrate<T: Into<f64>>(size: T, unit: &str) -> String {
+    let size = size.into();
+    let mut result = human_bytes(size);
+    if let Some(prefix) = result.strip_suffix('B') {
+        result = format!("{}{}", prefix, unit);
+    }
+    result
+}
+
+#[test]
+fn test_corrected_fee_rate() {
+    let mut worker = crate::state::Worker::default();
+    // 份额太少时不修正
+    worker.share_index = 100;
+    assert_eq!(corrected_fee_rate(0.01, &worker), 0.01);
+
+    // 抽水份额数量够了, 但难度低, 按难度算少抽了
+    worker.share_index = 990;
+    worker.fee_share_index = 10;
+    worker.add_share_diff(990.0 * 4.0, false);
+    worker.add_share_diff(10.0 * 2.0, true);
+    assert!(worker.fee_ratio() < 0.006);
+    let rate = corrected_fee_rate(0.01, &worker);
+    assert!(rate > 0.014 && rate <= 0.02, "{}", rate);
+
+    // 多抽了
+    worker.add_share_diff(80.0, true);
+    assert!(corrected_fee_rate(0.01, &worker) < 0.01);
+    worker.add_share_diff(1000.0, true);
+    assert_eq!(corrected_fee_rate(0.01, &worker), 0.0);
+}
+
+#[test]
+fn test_pick_fee_destination() {
+    let rates = [0.006, 0.004];
+    let all = [true, true];
+    // 还没有抽水时选费率高的
+    assert_eq!(pick_fee_destination(&rates, &[0.0, 0.0], &all), Some(0));
+    assert_eq!(pick_fee_destination(&rates, &[60.0, 30.0], &all), Some(1));
+    assert_eq!(pick_fee_destination(&rates, &[50.0, 40.0], &all), Some(0));
+    // 没有任务的目标跳过
+    assert_eq!(
+        pick_fee_destination(&rates, &[60.0, 30.0], &[true, false]),
+        Some(0)
+    );
+    let none = [false, false];
+    assert_eq!(pick_fee_destination(&rates, &[0.0, 0.0], &none), None);
+
+    // 按选择结果模拟抽水, 难度按费率分配
+    let mut served = [0.0, 0.0];
+    for _ in 0..1000 {
+        let i = pick_fee_destination(&rates, &served, &all).unwrap();
+        served[i] += 1.0;
+    }
+    assert_eq!(served, [600.0, 400.0]);
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::util::config::FeeDestination;
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
 pub struct CreateRequest {
@@ -14,6 +16,23 @@ pub struct CreateRequest {
     pub share_address: String,
     pub share_rate: f32,
     pub share_wallet: String,
+    pub verify_share: bool,
+    pub vardiff_spm: u32,
+    pub aggregate: u32,
+    pub connect_timeout: u64,
+    pub dns_refresh: u64,
+    pub pool_tls_ca: String,
+    pub pool_tls_insecure: bool,
+    // 多个公钥用逗号分隔
+    pub pool_tls_pins: String,
+    pub cert_cn: String,
+    // 多个域名或 IP 用逗号分隔
+    pub cert_sans: String,
+    pub cert_days: u32,
+    pub encrypt_psk: String,
+    pub encrypt_legacy: bool,
+    // 多个抽水目标, 费率为百分比。设置后不使用 share_address 等
+    pub fee_destinations: Vec<FeeDestination>,
     pub key: String,
     pub iv: String,
 }
@@ -53,3 +72,12 @@ pub struct LoginResponse {
     pub code: i32,
     pub data: TokenDataResponse,
 }
+
+// 历史统计的查询参数, 时间为 unix 秒, res 为 minute 或 hour, 为空时自动选择
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct StatsQuery {
+    pub from: u64,
+    pub to: u64,
+    pub res: String,
+}
@@ -0,0 +1,40 @@
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+
+use crate::{
+    web::{
+        metrics::{
+            authorized, render, snapshot, OPENMETRICS_FORMAT, TEXT_FORMAT,
+        },
+        AppState,
+    },
+    METRICS_TOKEN,
+};
+
+// 给 Prometheus 抓取, 不使用登录的 token, 用单独的访问令牌。
+// 没有设置令牌时任何人都能访问, 只输出不含钱包的指标
+#[get("/metrics")]
+async fn metrics(req: HttpRequest, app: web::Data<AppState>) -> HttpResponse {
+    let headers = req.headers();
+    let auth = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    if !authorized(auth, &METRICS_TOKEN) {
+        return HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish();
+    }
+
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/openmetrics-text"));
+    let format = if openmetrics {
+        OPENMETRICS_FORMAT
+    } else {
+        TEXT_FORMAT
+    };
+    let private = !METRICS_TOKEN.is_empty();
+    HttpResponse::Ok()
+        .content_type(format)
+        .body(render(&snapshot(&app), openmetrics, private))
+}
@@ -1,3 +1,5 @@
 pub mod auth;
+pub mod metrics;
 pub mod server;
+pub mod stats;
 pub mod user;
@@ -2,16 +2,21 @@ use actix_web_grants::proc_macro::has_permissions;
 use std::{
     fs::OpenOptions,
     io::{Read, Write},
+    path::Path,
 };
 
 use clap::crate_version;
 
-use actix_web::{get, post, web, Responder};
+use actix_web::{get, post, web, HttpResponse, Responder};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    util::{config::Settings, human_bytes, time_to_string},
+    client::fee::FeePoolStatus,
+    util::{
+        config::{FeeDestination, Settings},
+        human_hashrate, time_to_string,
+    },
     web::{data::*, AppState, OnlineWorker},
 };
 
@@ -47,7 +52,7 @@ pub async fn crate_app(
         }));
     }
 
-    if req.share != 0 {
+    if req.share != 0 && req.fee_destinations.is_empty() {
         if req.share_address.is_empty() {
             //println!("抽水矿池必须填写");
             return Ok(web::Json(Response::<String> {
@@ -81,8 +86,32 @@ pub async fn crate_app(
     config.log_level = "DEBUG".into();
     //config.log_path = "".into();
     config.name = req.name.clone();
-    config.pool_address = vec![req.pool_address.clone()];
-    config.share_address = vec![req.share_address.clone()];
+    // 多个矿池用逗号分隔, 第一个为主矿池
+    config.pool_address = req
+        .pool_address
+        .split(',')
+        .map(|addr| addr.trim().to_string())
+        .filter(|addr| !addr.is_empty())
+        .collect();
+    if !req.share_address.is_empty() {
+        config.share_address = vec![req.share_address.clone()];
+    }
+    config.fee_destinations = req
+        .fee_destinations
+        .iter()
+        .map(|dest| FeeDestination {
+            name: dest.name.trim().to_string(),
+            address: dest
+                .address
+                .iter()
+                .map(|addr| addr.trim().to_string())
+                .filter(|addr| !addr.is_empty())
+                .collect(),
+            wallet: dest.wallet.trim().to_string(),
+            worker: dest.worker.trim().to_string(),
+            rate: dest.rate / 100.0,
+        })
+        .collect();
     config.tcp_port = req.tcp_port;
     config.ssl_port = req.ssl_port;
     config.encrypt_port = req.encrypt_port;
@@ -91,6 +120,31 @@ pub async fn crate_app(
     config.share_alg = req.share_alg;
     config.hash_rate = 100;
     config.share_wallet = req.share_wallet.clone();
+    config.verify_share = req.verify_share;
+    config.vardiff_spm = req.vardiff_spm;
+    config.aggregate = req.aggregate;
+    config.connect_timeout = req.connect_timeout;
+    config.dns_refresh = req.dns_refresh;
+    config.pool_tls_ca = req.pool_tls_ca.trim().to_string();
+    config.pool_tls_insecure = req.pool_tls_insecure;
+    config.pool_tls_pins = req
+        .pool_tls_pins
+        .split(',')
+        .map(|pin| pin.trim().to_string())
+        .filter(|pin| !pin.is_empty())
+        .collect();
+    config.cert_cn = req.cert_cn.trim().to_string();
+    config.cert_sans = req
+        .cert_sans
+        .split(',')
+        .map(|san| san.trim().to_string())
+        .filter(|san| !san.is_empty())
+        .collect();
+    config.cert_days = req.cert_days;
+    config.encrypt_psk = req.encrypt_psk.clone();
+    config.encrypt_legacy = req.encrypt_legacy;
+    config.fill_fee_destinations();
+    config.fill_default_ports();
 
     match config.check().await {
         Ok(_) => {}
@@ -200,12 +254,7 @@ pub async fn crate_app(
 
             match crate::util::run_server(&config) {
                 Ok(child) => {
-                    let online = OnlineWorker {
-                        child,
-                        config: config.clone(),
-                        workers: vec![],
-                        online: 0,
-                    };
+                    let online = OnlineWorker::new(child, config.clone());
                     app.lock().unwrap().insert(config.name, online);
                 }
                 Err(e) => {
@@ -253,12 +302,7 @@ pub async fn crate_app(
 
             match crate::util::run_server(&config) {
                 Ok(child) => {
-                    let online = OnlineWorker {
-                        child,
-                        config: config.clone(),
-                        workers: vec![],
-                        online: 0,
-                    };
+                    let online = OnlineWorker::new(child, config.clone());
                     app.lock().unwrap().insert(config.name, online);
                 }
                 Err(e) => {
@@ -299,17 +343,105 @@ async fn server_list(
     }))
 }
 
+// 通知中转重新加载 SSL 证书, 已连接的矿机不会断开
+#[post("/user/server/{name}/reload_cert")]
+#[has_permissions("ROLE_ADMIN")]
+async fn reload_cert(
+    proxy_server_name: web::Path<String>, app: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let pid = {
+        let proxy_server = app.lock().unwrap();
+        proxy_server
+            .get(proxy_server_name.as_str())
+            .and_then(|online| online.child.id())
+    };
+
+    let message = match pid {
+        None => "中转不存在或未运行".to_string(),
+        #[cfg(unix)]
+        Some(pid) => {
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGHUP) } == 0 {
+                return Ok(web::Json(Response::<String> {
+                    code: 20000,
+                    message: "".into(),
+                    data: String::default(),
+                }));
+            }
+            std::io::Error::last_os_error().to_string()
+        }
+        #[cfg(not(unix))]
+        Some(_) => "当前系统不支持, 证书文件修改后会自动重新加载".to_string(),
+    };
+
+    Ok(web::Json(Response::<String> {
+        code: 40000,
+        message,
+        data: String::default(),
+    }))
+}
+
+// 下载中转 SSL 端口使用的证书, 矿机可以用来校验或固定证书
+#[get("/user/server/{name}/cert")]
+#[has_permissions("ROLE_ADMIN")]
+async fn download_cert(
+    proxy_server_name: web::Path<String>, app: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let pem_path = {
+        let proxy_server = app.lock().unwrap();
+        proxy_server
+            .get(proxy_server_name.as_str())
+            .map(|online| crate::util::work_path(&online.config.pem_path))
+    };
+
+    let res = match pem_path {
+        Some(path) => crate::client::cert::load_certs(Path::new(&path))
+            .and_then(|certs| Ok((std::fs::read(&path)?, certs))),
+        None => Err(anyhow::anyhow!("中转不存在")),
+    };
+    match res {
+        Ok((pem, certs)) => {
+            let (fingerprint, pin) =
+                crate::client::cert::fingerprints(&certs[0]);
+            Ok(HttpResponse::Ok()
+                .content_type("application/x-pem-file")
+                .insert_header((
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"{}.pem\"",
+                        proxy_server_name
+                    ),
+                ))
+                .insert_header(("X-Cert-Fingerprint", fingerprint))
+                .insert_header(("X-Cert-Pin", pin))
+                .body(pem))
+        }
+        Err(e) => Ok(HttpResponse::Ok().json(Response::<String> {
+            code: 40000,
+            message: format!("证书读取失败 {}", e),
+            data: String::default(),
+        })),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ResWorker {
     pub worker_name: String,
     pub worker_wallet: String,
     pub hash: String,
+    // 按有效份额计算的实际算力
+    pub hash_5m: String,
+    pub hash_1h: String,
+    pub hash_24h: String,
     pub last_subwork_time: String,
     pub online_time: String,
     pub share_index: u64,
     pub accept_index: u64,
     pub fee_accept_index: u64,
     pub invalid_index: u64,
+    pub stale_index: u64,
+    pub fee_share_index: u64,
+    // 实际抽水比例, 百分比
+    pub fee_ratio: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -320,6 +452,8 @@ pub struct OnlineWorkerResult {
     pub config: Settings,
     pub fee_hash: String,
     pub total_hash: String,
+    // 在线矿机最近一小时的实际算力之和
+    pub effective_hash: String,
     pub accept_index: u64,
     pub share_index: u64,
     pub reject_index: u64,
@@ -328,6 +462,10 @@ pub struct OnlineWorkerResult {
     pub fee_reject_index: u64,
     pub rate: f64,
     pub share_rate: f64,
+    // 按份额难度计算的实际抽水比例, 百分比
+    pub fee_ratio: f64,
+    // 各抽水目标的矿池连接状态和提交结果
+    pub fee_pools: Vec<FeePoolStatus>,
 }
 
 // 展示选中的数据信息。以json格式返回
@@ -336,6 +474,7 @@ async fn server(
     proxy_server_name: web::Path<String>, app: web::Data<AppState>,
 ) -> actix_web::Result<impl Responder> {
     let mut total_hash: f64 = 0.0;
+    let mut effective_hash: f64 = 0.0;
 
     let mut res: OnlineWorkerResult = OnlineWorkerResult::default();
     {
@@ -350,18 +489,30 @@ async fn server(
 
         for (name, server) in &*proxy_server {
             if *name == proxy_server_name.to_string() {
+                let unit = server
+                    .config
+                    .coin()
+                    .map(|c| c.hashrate_unit())
+                    .unwrap_or("H/s");
                 for r in &server.workers {
                     if r.is_online() {
                         online += 1;
                         total_hash += r.hash as f64;
+                        effective_hash += r.hash_1h as f64;
                         res.workers.push(ResWorker {
                             worker_name: r.worker_name.clone(),
                             worker_wallet: r.worker_wallet.clone(),
-                            hash: human_bytes(r.hash as f64),
+                            hash: human_hashrate(r.hash as f64, unit),
+                            hash_5m: human_hashrate(r.hash_5m as f64, unit),
+                            hash_1h: human_hashrate(r.hash_1h as f64, unit),
+                            hash_24h: human_hashrate(r.hash_24h as f64, unit),
                             share_index: r.share_index,
                             accept_index: r.accept_index,
                             invalid_index: r.invalid_index,
+                            stale_index: r.stale_index,
                             fee_accept_index: r.fee_accept_index,
+                            fee_share_index: r.fee_share_index,
+                            fee_ratio: floor(r.fee_ratio() * 100.0, 2),
                             online_time: time_to_string(
                                 r.login_time.elapsed().as_secs(),
                             ),
@@ -379,6 +530,13 @@ async fn server(
                     }
                 }
                 res.config = server.config.clone();
+                res.fee_pools = server.status.fee_pools.clone();
+                res.fee_ratio = floor(
+                    crate::state::fee_ratio(
+                        server.workers.iter().filter(|w| w.is_online()),
+                    ) * 100.0,
+                    2,
+                );
             }
         }
 
@@ -401,9 +559,14 @@ async fn server(
             );
         }
 
-        res.fee_hash =
-            human_bytes(total_hash as f64 * res.config.share_rate as f64);
-        res.total_hash = human_bytes(total_hash as f64);
+        let unit =
+            res.config.coin().map(|c| c.hashrate_unit()).unwrap_or("H/s");
+        res.fee_hash = human_hashrate(
+            total_hash * res.config.share_rate as f64,
+            unit,
+        );
+        res.total_hash = human_hashrate(total_hash, unit);
+        res.effective_hash = human_hashrate(effective_hash, unit);
     }
 
     //1. 基本配置文件信息 .
@@ -428,6 +591,8 @@ pub struct DashboardResult {
     pub online: u32,
     pub fee_hash: String,
     pub total_hash: String,
+    // 在线矿机最近一小时的实际算力之和
+    pub effective_hash: String,
     pub accept_index: u64,
     pub share_index: u64,
     pub reject_index: u64,
@@ -436,6 +601,8 @@ pub struct DashboardResult {
     pub fee_reject_index: u64,
     pub rate: f64,       //总代理算力
     pub share_rate: f64, //抽水算力
+    // 按份额难度计算的实际抽水比例, 百分比
+    pub fee_ratio: f64,
     pub version: String,
     pub develop_worker_name: String,
     pub online_time: String,
@@ -447,6 +614,7 @@ async fn dashboard(
     app: web::Data<AppState>,
 ) -> actix_web::Result<impl Responder> {
     let mut total_hash: f64 = 0.0;
+    let mut effective_hash: f64 = 0.0;
     let mut fee_hash: f64 = 0.0;
     let mut res: DashboardResult = DashboardResult::default();
     {
@@ -464,6 +632,7 @@ async fn dashboard(
                 if r.is_online() {
                     online += 1;
                     total_hash += r.hash as f64;
+                    effective_hash += r.hash_1h as f64;
                     share_index += r.share_index;
                     accept_index += r.accept_index;
                     reject_index += r.invalid_index;
@@ -486,10 +655,17 @@ async fn dashboard(
 
         res.proxy_num = proxy_server.len() as i32;
         res.online = online;
+        let workers = proxy_server
+            .values()
+            .flat_map(|s| s.workers.iter())
+            .filter(|w| w.is_online());
+        res.fee_ratio = floor(crate::state::fee_ratio(workers) * 100.0, 2);
     }
 
-    res.fee_hash = human_bytes(fee_hash as f64);
-    res.total_hash = human_bytes(total_hash as f64);
+    // 多个代理的币种可能不同, 汇总时统一按 H/s 显示
+    res.fee_hash = human_hashrate(fee_hash, "H/s");
+    res.total_hash = human_hashrate(total_hash, "H/s");
+    res.effective_hash = human_hashrate(effective_hash, "H/s");
     if res.accept_index > 0 {
         res.rate =
             floor(res.accept_index as f64 / res.share_index as f64 * 100.0, 2);
@@ -0,0 +1,99 @@
+use actix_web_grants::proc_macro::has_permissions;
+
+use actix_web::{get, web, Responder};
+
+use crate::web::{
+    data::{Response, StatsQuery},
+    stats::{unix_now, Sample, Series, StatsData},
+};
+
+fn query(
+    store: &StatsData, series: Series, req: &StatsQuery,
+) -> Response<Vec<Sample>> {
+    let error = |message: String| Response::<Vec<Sample>> {
+        code: 40000,
+        message,
+        data: vec![],
+    };
+    let store = match store.as_ref() {
+        Some(store) => store,
+        None => return error("历史统计未开启".into()),
+    };
+
+    // 默认查询最近一天
+    let now = unix_now();
+    let to = if req.to == 0 { now } else { req.to };
+    let from = if req.from == 0 {
+        to.saturating_sub(24 * 3600)
+    } else {
+        req.from
+    };
+    if from >= to {
+        return error("开始时间必须早于结束时间".into());
+    }
+
+    match store
+        .resolution(&req.res, from, to, now)
+        .and_then(|res| store.query(&series, from, to, res))
+    {
+        Ok(samples) => Response::<Vec<Sample>> {
+            code: 20000,
+            message: "".into(),
+            data: samples,
+        },
+        Err(e) => error(e.to_string()),
+    }
+}
+
+// 全部中转合计的历史统计
+#[get("/user/stats")]
+#[has_permissions("ROLE_ADMIN")]
+async fn farm_stats(
+    req: web::Query<StatsQuery>, store: web::Data<StatsData>,
+) -> actix_web::Result<impl Responder> {
+    Ok(web::Json(query(&store, Series::Farm, &req)))
+}
+
+#[get("/user/stats/server/{name}")]
+#[has_permissions("ROLE_ADMIN")]
+async fn server_stats(
+    proxy_server_name: web::Path<String>, req: web::Query<StatsQuery>,
+    store: web::Data<StatsData>,
+) -> actix_web::Result<impl Responder> {
+    let series = Series::Proxy(proxy_server_name.into_inner());
+    Ok(web::Json(query(&store, series, &req)))
+}
+
+// 中转下有历史数据的矿工
+#[get("/user/stats/server/{name}/workers")]
+#[has_permissions("ROLE_ADMIN")]
+async fn worker_list(
+    proxy_server_name: web::Path<String>, store: web::Data<StatsData>,
+) -> actix_web::Result<impl Responder> {
+    let res = match store.as_ref().as_ref() {
+        Some(store) => store.workers(&proxy_server_name),
+        None => Err(anyhow::anyhow!("历史统计未开启")),
+    };
+    Ok(web::Json(match res {
+        Ok(workers) => Response::<Vec<String>> {
+            code: 20000,
+            message: "".into(),
+            data: workers,
+        },
+        Err(e) => Response::<Vec<String>> {
+            code: 40000,
+            message: e.to_string(),
+            data: vec![],
+        },
+    }))
+}
+
+#[get("/user/stats/server/{name}/worker/{worker}")]
+#[has_permissions("ROLE_ADMIN")]
+async fn worker_stats(
+    path: web::Path<(String, String)>, req: web::Query<StatsQuery>,
+    store: web::Data<StatsData>,
+) -> actix_web::Result<impl Responder> {
+    let (name, worker) = path.into_inner();
+    Ok(web::Json(query(&store, Series::Worker(name, worker), &req)))
+}
@@ -0,0 +1,511 @@
+use std::fmt::Write;
+
+use crate::{
+    proxy::ProxyStatus,
+    state::{fee_ratio, Worker},
+    util::share_alg_name,
+    web::{stats::counters, AppState},
+};
+
+// /metrics 接口, 按 Prometheus 文本格式输出, 抓取端请求 OpenMetrics 时
+// 按 OpenMetrics 格式输出。计数器在矿机重新登录或中转重启后会清零,
+// Prometheus 的 rate/increase 会自动处理。
+// 没有设置访问令牌时不输出矿机的指标, 矿池地址也去掉钱包等信息
+pub const TEXT_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_FORMAT: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// 采集时复制一份中转的数据, 避免输出时一直持有锁
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub name: String,
+    pub workers: Vec<Worker>,
+    pub status: ProxyStatus,
+    pub running: bool,
+    pub share_alg: u32,
+    pub share_rate: f64,
+    // 抽水目标的 (名称, 费率)
+    pub fee_destinations: Vec<(String, f64)>,
+}
+
+// 经过字符串转换, 避免 f32 转 f64 后输出 0.009999999776
+fn rate(rate: f32) -> f64 { rate.to_string().parse().unwrap_or_default() }
+
+pub fn snapshot(app: &AppState) -> Vec<Snapshot> {
+    let mut proxies: Vec<Snapshot> = app
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, online)| Snapshot {
+            name: name.clone(),
+            workers: online.workers.clone(),
+            status: online.status.clone(),
+            running: online.running,
+            share_alg: online.config.share_alg,
+            share_rate: rate(online.config.share_rate),
+            fee_destinations: online
+                .config
+                .fee_destinations()
+                .into_iter()
+                .map(|d| (d.name, rate(d.rate)))
+                .collect(),
+        })
+        .collect();
+    proxies.sort_by(|a, b| a.name.cmp(&b.name));
+    proxies
+}
+
+// 未设置令牌时不校验, 设置后需要 Authorization: Bearer <令牌>
+pub fn authorized(header: Option<&str>, token: &str) -> bool {
+    if token.is_empty() {
+        return true;
+    }
+    match header.and_then(|h| h.strip_prefix("Bearer ")) {
+        Some(given) => ring::constant_time::verify_slices_are_equal(
+            given.trim().as_bytes(),
+            token.as_bytes(),
+        )
+        .is_ok(),
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Gauge,
+    Counter,
+}
+
+struct Family {
+    name: String,
+    kind: Kind,
+    help: String,
+    samples: Vec<(String, f64)>,
+}
+
+impl Family {
+    fn new(
+        name: impl Into<String>, kind: Kind, help: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            help: help.into(),
+            samples: vec![],
+        }
+    }
+
+    fn add(&mut self, labels: &[(&str, &str)], value: f64) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.samples.push((labels, value));
+    }
+
+    fn write(&self, out: &mut String, openmetrics: bool) {
+        // OpenMetrics 中计数器的名称不带 _total 后缀, 样本带
+        let name = match self.kind {
+            Kind::Counter if openmetrics => {
+                self.name.trim_end_matches("_total")
+            }
+            _ => &self.name,
+        };
+        let kind = match self.kind {
+            Kind::Gauge => "gauge",
+            Kind::Counter => "counter",
+        };
+        let _ = writeln!(out, "# HELP {} {}", name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in &self.samples {
+            let _ = writeln!(out, "{}{{{}}} {}", self.name, labels, value);
+        }
+    }
+}
+
+// 只保留协议和主机端口, 钱包、密码和代理设置不公开
+fn public_pool(address: &str, idx: usize) -> String {
+    match crate::client::pool_url::PoolUrl::parse(address) {
+        Ok(url) => format!("{}//{}", url.scheme, url.address),
+        Err(_) => format!("#{}", idx),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// private 为 false 时不输出带钱包和矿工名的指标
+pub fn render(
+    proxies: &[Snapshot], openmetrics: bool, private: bool,
+) -> String {
+    use Kind::*;
+
+    let mut online = Family::new(
+        "mining_proxy_online_workers",
+        Gauge,
+        "Online workers.",
+    );
+    let mut hashrate = Family::new(
+        "mining_proxy_hashrate",
+        Gauge,
+        "Hashrate reported by online workers, H/s.",
+    );
+    let mut child_up = Family::new(
+        "mining_proxy_child_up",
+        Gauge,
+        "Whether the proxy process is still running.",
+    );
+    let mut pool_up = Family::new(
+        "mining_proxy_pool_up",
+        Gauge,
+        "Whether the pool passed the health check.",
+    );
+    let mut pool_active = Family::new(
+        "mining_proxy_pool_active",
+        Gauge,
+        "Whether the pool is the one currently used.",
+    );
+    let mut pool_latency = Family::new(
+        "mining_proxy_pool_latency_milliseconds",
+        Gauge,
+        "Latency of the last successful pool connection.",
+    );
+    let mut fee_jobs = Family::new(
+        "mining_proxy_fee_job_queue",
+        Gauge,
+        "Fee jobs waiting in the queue.",
+    );
+    let mut develop_jobs = Family::new(
+        "mining_proxy_develop_job_queue",
+        Gauge,
+        "Develop fee jobs waiting in the queue.",
+    );
+    let mut fee_pool_up = Family::new(
+        "mining_proxy_fee_pool_up",
+        Gauge,
+        "Whether the fee pool is connected.",
+    );
+    let mut fee_pool_accept_ratio = Family::new(
+        "mining_proxy_fee_pool_accept_ratio",
+        Gauge,
+        "Accepted fraction of the recent fee shares answered by the fee pool.",
+    );
+    let mut fee_pool_latency = Family::new(
+        "mining_proxy_fee_pool_latency_milliseconds",
+        Gauge,
+        "Average time for the fee pool to answer a fee share.",
+    );
+    let mut fee_pool_alert = Family::new(
+        "mining_proxy_fee_pool_alert",
+        Gauge,
+        "Whether the fee pool rejects more recent fee shares than allowed.",
+    );
+    let fee_pool_counters = [
+        ("submits", "Fee shares submitted to the fee pool"),
+        ("accepts", "Fee shares accepted by the fee pool"),
+        ("rejects", "Fee shares rejected by the fee pool"),
+        ("lost", "Fee shares that got no answer from the fee pool"),
+    ];
+    let mut fee_pool_shares: Vec<Family> = fee_pool_counters
+        .iter()
+        .map(|(name, help)| {
+            Family::new(
+                format!("mining_proxy_fee_pool_{}_total", name),
+                Counter,
+                format!("{}.", help),
+            )
+        })
+        .collect();
+    let mut bytes_in = Family::new(
+        "mining_proxy_bytes_in_total",
+        Counter,
+        "Bytes received from workers.",
+    );
+    let mut bytes_out = Family::new(
+        "mining_proxy_bytes_out_total",
+        Counter,
+        "Bytes sent to workers.",
+    );
+    let mut worker_hashrate = Family::new(
+        "mining_proxy_worker_hashrate",
+        Gauge,
+        "Hashrate reported by the worker, H/s.",
+    );
+    let mut worker_hashrate_real = Family::new(
+        "mining_proxy_worker_effective_hashrate",
+        Gauge,
+        "5 minute hashrate calculated from accepted shares, H/s.",
+    );
+    // 实际抽水比例按抽水算法标记, 便于比较不同算法
+    let mut proxy_fee_ratio = Family::new(
+        "mining_proxy_fee_ratio",
+        Gauge,
+        "Fee share difficulty as a fraction of online workers' shares.",
+    );
+    let mut fee_target = Family::new(
+        "mining_proxy_fee_target_ratio",
+        Gauge,
+        "Configured fee rate.",
+    );
+    let mut dest_fee_ratio = Family::new(
+        "mining_proxy_fee_destination_ratio",
+        Gauge,
+        "Share difficulty mined for the fee destination as a fraction of all.",
+    );
+    let mut dest_fee_target = Family::new(
+        "mining_proxy_fee_destination_target_ratio",
+        Gauge,
+        "Configured fee rate of the fee destination.",
+    );
+    let mut worker_fee_ratio = Family::new(
+        "mining_proxy_worker_fee_ratio",
+        Gauge,
+        "Difficulty of fee shares as a fraction of all shares of the worker.",
+    );
+
+    // 份额计数同时按中转和矿机输出
+    let share_families = [
+        ("shares", "Shares submitted"),
+        ("accepts", "Shares accepted"),
+        ("rejects", "Shares rejected"),
+        ("stales", "Stale shares, also counted as rejected"),
+        ("fee_shares", "Fee shares submitted"),
+        ("fee_accepts", "Fee shares accepted"),
+        ("fee_rejects", "Fee shares rejected"),
+    ];
+    let mut proxy_shares: Vec<Family> = share_families
+        .iter()
+        .map(|(name, help)| {
+            Family::new(
+                format!("mining_proxy_{}_total", name),
+                Counter,
+                format!("{}.", help),
+            )
+        })
+        .collect();
+    let mut worker_shares: Vec<Family> = share_families
+        .iter()
+        .map(|(name, help)| {
+            Family::new(
+                format!("mining_proxy_worker_{}_total", name),
+                Counter,
+                format!("{} by the worker.", help),
+            )
+        })
+        .collect();
+
+    for proxy in proxies {
+        let name = proxy.name.as_str();
+        let labels = [("proxy", name)];
+        let alg = share_alg_name(proxy.share_alg);
+        let workers: Vec<&Worker> =
+            proxy.workers.iter().filter(|w| w.is_online()).collect();
+
+        online.add(&labels, workers.len() as f64);
+        hashrate.add(&labels, workers.iter().map(|w| w.hash as f64).sum());
+        child_up.add(&labels, proxy.running as u8 as f64);
+        fee_jobs.add(&labels, proxy.status.fee_jobs as f64);
+        develop_jobs.add(&labels, proxy.status.develop_jobs as f64);
+        for fee_pool in &proxy.status.fee_pools {
+            let dest = fee_pool.name.as_str();
+            let labels = [("proxy", name), ("destination", dest)];
+            fee_pool_up.add(&labels, fee_pool.connected as u8 as f64);
+            fee_pool_accept_ratio.add(&labels, fee_pool.accept_rate);
+            fee_pool_latency.add(&labels, fee_pool.latency_ms as f64);
+            fee_pool_alert.add(&labels, fee_pool.alert as u8 as f64);
+            let values = [
+                fee_pool.submits,
+                fee_pool.accepts,
+                fee_pool.rejects,
+                fee_pool.lost,
+            ];
+            for (family, value) in fee_pool_shares.iter_mut().zip(values) {
+                family.add(&labels, value as f64);
+            }
+        }
+        bytes_in.add(&labels, proxy.status.bytes_in as f64);
+        bytes_out.add(&labels, proxy.status.bytes_out as f64);
+
+        for (idx, pool) in proxy.status.pools.iter().enumerate() {
+            let address = if private {
+                pool.address.clone()
+            } else {
+                public_pool(&pool.address, idx)
+            };
+            let labels = [("proxy", name), ("pool", address.as_str())];
+            pool_up.add(&labels, pool.healthy() as u8 as f64);
+            let active = idx == proxy.status.active_pool;
+            pool_active.add(&labels, active as u8 as f64);
+            pool_latency.add(&labels, pool.latency_ms as f64);
+        }
+
+        let mut totals = [0u64; 7];
+        for worker in &workers {
+            for (i, value) in counters(worker).iter().enumerate() {
+                totals[i] += value;
+            }
+            if !private {
+                continue;
+            }
+            let labels = [
+                ("proxy", name),
+                ("worker", worker.worker.as_str()),
+                ("wallet", worker.worker_wallet.as_str()),
+                ("rig", worker.worker_name.as_str()),
+            ];
+            worker_hashrate.add(&labels, worker.hash as f64);
+            worker_hashrate_real.add(&labels, worker.hash_5m as f64);
+            let ratio_labels = [
+                ("proxy", name),
+                ("worker", worker.worker.as_str()),
+                ("share_alg", alg),
+            ];
+            worker_fee_ratio.add(&ratio_labels, worker.fee_ratio());
+            for (i, value) in counters(worker).iter().enumerate() {
+                worker_shares[i].add(&labels, *value as f64);
+            }
+        }
+        for (family, value) in proxy_shares.iter_mut().zip(totals) {
+            family.add(&labels, value as f64);
+        }
+        let ratio = fee_ratio(workers.iter().copied());
+        proxy_fee_ratio.add(&[("proxy", name), ("share_alg", alg)], ratio);
+        fee_target.add(&labels, proxy.share_rate);
+
+        let total: f64 =
+            workers.iter().map(|w| w.share_diff_sum + w.fee_diff_sum).sum();
+        for (dest, rate) in &proxy.fee_destinations {
+            let labels = [("proxy", name), ("destination", dest.as_str())];
+            let served: f64 = workers
+                .iter()
+                .filter_map(|w| w.fee_accounts.get(dest))
+                .map(|a| a.diff_sum)
+                .sum();
+            let ratio = if total > 0.0 { served / total } else { 0.0 };
+            dest_fee_ratio.add(&labels, ratio);
+            dest_fee_target.add(&labels, *rate);
+        }
+    }
+
+    let mut out = String::new();
+    let families = [
+        online,
+        hashrate,
+        child_up,
+        pool_up,
+        pool_active,
+        pool_latency,
+        fee_jobs,
+        develop_jobs,
+        fee_pool_up,
+        fee_pool_accept_ratio,
+        fee_pool_latency,
+        fee_pool_alert,
+        bytes_in,
+        bytes_out,
+        worker_hashrate,
+        worker_hashrate_real,
+        proxy_fee_ratio,
+        fee_target,
+        dest_fee_ratio,
+        dest_fee_target,
+        worker_fee_ratio,
+    ];
+    for family in families
+        .iter()
+        .chain(&fee_pool_shares)
+        .chain(&proxy_shares)
+        .chain(&worker_shares)
+    {
+        family.write(&mut out, openmetrics);
+    }
+    if openmetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+#[test]
+fn test_render_metrics() {
+    let mut worker = Worker::new(
+        "0xabc.rig\"1".into(),
+        "rig\"1".into(),
+        "0xabc".into(),
+        true,
+    );
+    worker.hash = 100;
+    worker.share_index = 10;
+    worker.accept_index = 9;
+    let mut offline = worker.clone();
+    offline.worker = "0xabc.rig2".into();
+    offline.offline();
+
+    let mut pool = crate::client::health::PoolStatus::default();
+    pool.address = "tcp://0xabc.rig:x@pool:4444".into();
+    pool.latency_ms = 30;
+    let proxies = vec![Snapshot {
+        name: "eth".into(),
+        workers: vec![worker, offline],
+        status: ProxyStatus {
+            pools: vec![pool],
+            fee_jobs: 3,
+            bytes_in: 1024,
+            fee_pools: vec![crate::client::fee::FeePoolStatus {
+                name: "partner".into(),
+                connected: true,
+                rejects: 5,
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        running: false,
+        share_alg: crate::util::SHARE_ALG_WINDOW,
+        share_rate: 0.01,
+        fee_destinations: vec![("partner".into(), 0.01)],
+    }];
+
+    let text = render(&proxies, false, true);
+    assert!(text.contains("mining_proxy_online_workers{proxy=\"eth\"} 1\n"));
+    assert!(text.contains("# TYPE mining_proxy_shares_total counter\n"));
+    assert!(text.contains("mining_proxy_shares_total{proxy=\"eth\"} 10\n"));
+    assert!(text.contains("mining_proxy_child_up{proxy=\"eth\"} 0\n"));
+    assert!(text.contains("mining_proxy_fee_job_queue{proxy=\"eth\"} 3\n"));
+    assert!(text.contains("pool=\"tcp://0xabc.rig:x@pool:4444\"} 1\n"));
+    assert!(text.contains("worker=\"0xabc.rig\\\"1\""));
+    assert!(text.contains(
+        "mining_proxy_fee_ratio{proxy=\"eth\",share_alg=\"window\"} 0\n"
+    ));
+    assert!(text.contains("fee_target_ratio{proxy=\"eth\"} 0.01\n"));
+    assert!(text.contains(
+        "mining_proxy_fee_pool_up{proxy=\"eth\",destination=\"partner\"} 1\n"
+    ));
+    assert!(text.contains("fee_pool_rejects_total{proxy=\"eth\",destination"));
+    assert!(text.contains(
+        "destination_target_ratio{proxy=\"eth\",destination=\"partner\"} 0.01"
+    ));
+    assert!(!text.contains("rig2"));
+    assert!(!text.contains("# EOF"));
+
+    // 没有访问令牌时不公开钱包
+    let public = render(&proxies, false, false);
+    assert!(!public.contains("0xabc"));
+    assert!(public.contains("mining_proxy_shares_total{proxy=\"eth\"} 10\n"));
+    assert!(public.contains(
+        "mining_proxy_pool_up{proxy=\"eth\",pool=\"tcp://pool:4444\"} 1\n"
+    ));
+
+    let open = render(&proxies, true, true);
+    assert!(open.contains("# TYPE mining_proxy_shares counter\n"));
+    assert!(open.contains("mining_proxy_bytes_in_total{proxy=\"eth\"} 1024\n"));
+    assert!(open.ends_with("# EOF\n"));
+
+    assert!(authorized(None, ""));
+    assert!(authorized(Some("Bearer secret"), "secret"));
+    assert!(!authorized(Some("Bearer other"), "secret"));
+    assert!(!authorized(None, "secret"));
+}
@@ -1,7 +1,11 @@
-use crate::{state::Worker, util::config::Settings};
+use std::time::Duration;
+
+use crate::{proxy::ProxyStatus, state::Worker, util::config::Settings};
 
 pub mod data;
 pub mod handles;
+pub mod metrics;
+pub mod stats;
 // pub struct AppState {
 //     pub global_count: std::sync::Arc<
 //         std::sync::Mutex<std::collections::HashMap<String, OnlineWorker>>,
@@ -17,4 +21,43 @@ pub struct OnlineWorker {
     pub workers: Vec<Worker>,
     pub online: u32,
     pub config: Settings,
+    // 中转进程定时上报的运行状态
+    pub status: ProxyStatus,
+    // 进程是否还在运行, 退出后不会自动重新启动
+    pub running: bool,
+}
+
+impl OnlineWorker {
+    pub fn new(child: tokio::process::Child, config: Settings) -> Self {
+        Self {
+            child,
+            workers: vec![],
+            online: 0,
+            config,
+            status: ProxyStatus::default(),
+            running: true,
+        }
+    }
+}
+
+// 检查中转进程是否退出的间隔
+const WATCH_SECS: u64 = 10;
+
+// 只记录中转进程的退出, 供 /metrics 输出
+pub async fn watch_children(app: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(WATCH_SECS));
+    loop {
+        interval.tick().await;
+        let mut proxies = app.lock().unwrap();
+        for (name, online) in proxies.iter_mut() {
+            if !online.running {
+                continue;
+            }
+            if let Ok(Some(status)) = online.child.try_wait() {
+                tracing::error!("中转 {} 已退出 {}", name, status);
+                online.running = false;
+                online.status = ProxyStatus::default();
+            }
+        }
+    }
 }
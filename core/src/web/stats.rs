@@ -0,0 +1,573 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryInto,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info};
+
+use tokio::time;
+
+use crate::{state::Worker, web::AppState};
+
+// 矿工统计的持久化存储, 放在主控进程里。
+// 每隔 interval 秒从 AppState 采样一次, 单台矿机、单个中转的合计和
+// 全部中转的合计各记一条。矿机重新登录时份额计数会清零,
+// 所以记录的是两次采样之间的增量, 计数变小时按清零处理。
+// 分钟数据保留 raw_days 天, 每个整点汇总成小时数据, 保留 hour_days 天。
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * HOUR;
+
+const DEFAULT_PATH: &str = "./stats.db";
+const DEFAULT_INTERVAL: u64 = 60;
+const DEFAULT_RAW_DAYS: u64 = 2;
+const DEFAULT_HOUR_DAYS: u64 = 90;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsOptions {
+    pub path: String,
+    // 采样间隔, 秒
+    pub interval: u64,
+    pub raw_days: u64,
+    pub hour_days: u64,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_PATH.into(),
+            interval: DEFAULT_INTERVAL,
+            raw_days: DEFAULT_RAW_DAYS,
+            hour_days: DEFAULT_HOUR_DAYS,
+        }
+    }
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default)
+}
+
+impl StatsOptions {
+    pub fn from_env() -> Self {
+        Self {
+            path: std::env::var("MINING_PROXY_STATS_PATH")
+                .unwrap_or_else(|_| DEFAULT_PATH.into()),
+            interval: env_u64("MINING_PROXY_STATS_INTERVAL", DEFAULT_INTERVAL),
+            raw_days: env_u64("MINING_PROXY_STATS_RAW_DAYS", DEFAULT_RAW_DAYS),
+            hour_days: env_u64(
+                "MINING_PROXY_STATS_HOUR_DAYS",
+                DEFAULT_HOUR_DAYS,
+            ),
+        }
+    }
+}
+
+// 一个时间段的统计, 算力和在线数为平均值, 份额为这段时间内的数量
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Sample {
+    pub time: u64,
+    // 矿机上报的算力
+    pub hash: u64,
+    // 按有效份额计算的 5 分钟算力
+    pub hash_real: u64,
+    pub shares: u64,
+    pub accepts: u64,
+    pub rejects: u64,
+    pub stales: u64,
+    pub fee_shares: u64,
+    pub fee_accepts: u64,
+    pub fee_rejects: u64,
+    pub workers: u64,
+}
+
+const FIELDS: usize = 10;
+
+impl Sample {
+    fn values(&self) -> [u64; FIELDS] {
+        [
+            self.hash,
+            self.hash_real,
+            self.shares,
+            self.accepts,
+            self.rejects,
+            self.stales,
+            self.fee_shares,
+            self.fee_accepts,
+            self.fee_rejects,
+            self.workers,
+        ]
+    }
+
+    fn from_values(time: u64, v: [u64; FIELDS]) -> Self {
+        Self {
+            time,
+            hash: v[0],
+            hash_real: v[1],
+            shares: v[2],
+            accepts: v[3],
+            rejects: v[4],
+            stales: v[5],
+            fee_shares: v[6],
+            fee_accepts: v[7],
+            fee_rejects: v[8],
+            workers: v[9],
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> { encode_u64s(&self.values()) }
+
+    fn decode(time: u64, bytes: &[u8]) -> Option<Self> {
+        Some(Self::from_values(time, decode_u64s(bytes)?))
+    }
+
+    fn add(&mut self, other: &Sample) {
+        let mut v = self.values();
+        for (a, b) in v.iter_mut().zip(other.values()) {
+            *a += b;
+        }
+        *self = Self::from_values(self.time, v);
+    }
+
+    fn has_shares(&self) -> bool {
+        self.shares + self.fee_shares + self.rejects + self.fee_rejects > 0
+    }
+}
+
+// 汇总成一个时间段: 份额相加, 算力和在线数取平均
+fn rollup(time: u64, samples: &[Sample]) -> Sample {
+    let mut total = Sample {
+        time,
+        ..Default::default()
+    };
+    for sample in samples {
+        total.add(sample);
+    }
+    let n = samples.len().max(1) as u64;
+    total.hash /= n;
+    total.hash_real /= n;
+    total.workers = (total.workers + n / 2) / n;
+    total
+}
+
+fn encode_u64s(values: &[u64]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+fn decode_u64s<const N: usize>(bytes: &[u8]) -> Option<[u64; N]> {
+    if bytes.len() != N * 8 {
+        return None;
+    }
+    let mut values = [0u64; N];
+    for (v, chunk) in values.iter_mut().zip(bytes.chunks(8)) {
+        *v = u64::from_be_bytes(chunk.try_into().ok()?);
+    }
+    Some(values)
+}
+
+// 矿机的累计计数: 份额, 接受, 拒绝, 过期, 抽水份额, 抽水接受, 抽水拒绝
+pub(crate) fn counters(worker: &Worker) -> [u64; 7] {
+    [
+        worker.share_index,
+        worker.accept_index,
+        worker.invalid_index,
+        worker.stale_index,
+        worker.fee_share_index,
+        worker.fee_accept_index,
+        worker.fee_invalid_index,
+    ]
+}
+
+fn delta(current: [u64; 7], last: Option<[u64; 7]>) -> [u64; 7] {
+    let mut res = current;
+    if let Some(last) = last {
+        for (v, last) in res.iter_mut().zip(last) {
+            if *v >= last {
+                *v -= last;
+            }
+        }
+    }
+    res
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Series {
+    // 全部中转的合计
+    Farm,
+    Proxy(String),
+    // (中转名称, 矿工名)
+    Worker(String, String),
+}
+
+impl Series {
+    fn prefix(&self) -> Vec<u8> {
+        match self {
+            Series::Farm => b"f\0".to_vec(),
+            Series::Proxy(proxy) => [b"p\0", proxy.as_bytes(), b"\0"].concat(),
+            Series::Worker(proxy, worker) => {
+                [b"w\0", proxy.as_bytes(), b"\0", worker.as_bytes(), b"\0"]
+                    .concat()
+            }
+        }
+    }
+}
+
+fn key(prefix: &[u8], time: u64) -> Vec<u8> {
+    [prefix, &time.to_be_bytes()[..]].concat()
+}
+
+fn time_of(key: &[u8]) -> u64 {
+    match key.len().checked_sub(8).and_then(|i| key[i..].try_into().ok()) {
+        Some(bytes) => u64::from_be_bytes(bytes),
+        None => 0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Minute,
+    Hour,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// 打开失败时为 None, 接口返回未开启
+pub type StatsData = Option<Arc<StatsStore>>;
+
+pub struct StatsStore {
+    options: StatsOptions,
+    // 采样数据
+    raw: sled::Tree,
+    // 小时汇总
+    hour: sled::Tree,
+    // 序列前缀 -> 最后一次采样时间和当时的累计计数
+    series: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl StatsStore {
+    pub fn open(options: StatsOptions) -> Result<Self> {
+        let db = match sled::open(&options.path) {
+            Ok(db) => db,
+            Err(e) => bail!("打开统计数据库 {} 失败 {}", options.path, e),
+        };
+        Ok(Self {
+            raw: db.open_tree("raw")?,
+            hour: db.open_tree("hour")?,
+            series: db.open_tree("series")?,
+            meta: db.open_tree("meta")?,
+            options,
+        })
+    }
+
+    pub fn options(&self) -> &StatsOptions { &self.options }
+
+    fn last(&self, prefix: &[u8]) -> Result<Option<(u64, [u64; 7])>> {
+        Ok(self.series.get(prefix)?.and_then(|v| {
+            let values = decode_u64s::<8>(&v)?;
+            let mut counters = [0u64; 7];
+            counters.copy_from_slice(&values[1..]);
+            Some((values[0], counters))
+        }))
+    }
+
+    // 记录一次采样, proxies 为 (中转名称, 矿机列表)
+    pub fn record(
+        &self, time: u64, proxies: &[(String, Vec<Worker>)],
+    ) -> Result<()> {
+        let mut raw = sled::Batch::default();
+        let mut series = sled::Batch::default();
+        let mut farm = Sample {
+            time,
+            ..Default::default()
+        };
+
+        for (name, workers) in proxies {
+            let mut total = Sample {
+                time,
+                ..Default::default()
+            };
+            for worker in workers {
+                let worker_series =
+                    Series::Worker(name.clone(), worker.worker.clone());
+                let prefix = worker_series.prefix();
+                let current = counters(worker);
+                let last = self.last(&prefix)?.map(|(_, counters)| counters);
+                let d = delta(current, last);
+                let online = worker.is_online();
+                let sample = Sample {
+                    time,
+                    hash: if online { worker.hash } else { 0 },
+                    hash_real: if online { worker.hash_5m } else { 0 },
+                    shares: d[0],
+                    accepts: d[1],
+                    rejects: d[2],
+                    stales: d[3],
+                    fee_shares: d[4],
+                    fee_accepts: d[5],
+                    fee_rejects: d[6],
+                    workers: online as u64,
+                };
+                // 早已下线且没有新份额的矿机不再记录
+                if !online && !sample.has_shares() && last.is_some() {
+                    continue;
+                }
+                let mut value = vec![time];
+                value.extend_from_slice(&current);
+                series.insert(prefix.clone(), encode_u64s(&value));
+                raw.insert(key(&prefix, time), sample.encode());
+                total.add(&sample);
+            }
+
+            let prefix = Series::Proxy(name.clone()).prefix();
+            series.insert(prefix.clone(), encode_u64s(&[time; 8]));
+            raw.insert(key(&prefix, time), total.encode());
+            farm.add(&total);
+        }
+
+        let prefix = Series::Farm.prefix();
+        series.insert(prefix.clone(), encode_u64s(&[time; 8]));
+        raw.insert(key(&prefix, time), farm.encode());
+
+        self.raw.apply_batch(raw)?;
+        self.series.apply_batch(series)?;
+        Ok(())
+    }
+
+    fn scan(
+        tree: &sled::Tree, prefix: &[u8], from: u64, to: u64,
+    ) -> Result<Vec<Sample>> {
+        let mut samples = vec![];
+        for item in tree.range(key(prefix, from)..key(prefix, to)) {
+            let (k, v) = item?;
+            if let Some(sample) = Sample::decode(time_of(&k), &v) {
+                samples.push(sample);
+            }
+        }
+        Ok(samples)
+    }
+
+    fn remove_before(
+        tree: &sled::Tree, prefix: &[u8], before: u64,
+    ) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for item in tree.range(key(prefix, 0)..key(prefix, before)) {
+            batch.remove(item?.0);
+        }
+        tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn rolled(&self) -> Result<u64> {
+        Ok(self
+            .meta
+            .get(b"rolled")?
+            .and_then(|v| decode_u64s::<1>(&v))
+            .map(|v| v[0])
+            .unwrap_or(0))
+    }
+
+    // 把已经结束的小时汇总成小时数据, 删除过期的数据和序列
+    pub fn compact(&self, now: u64) -> Result<()> {
+        let current_hour = now - now % HOUR;
+        let raw_cutoff = now.saturating_sub(self.options.raw_days * DAY);
+        let hour_cutoff = now.saturating_sub(self.options.hour_days * DAY);
+        let rolled = self.rolled()?.max(raw_cutoff - raw_cutoff % HOUR);
+
+        let mut expired = sled::Batch::default();
+        for item in self.series.iter() {
+            let (prefix, value) = item?;
+            let last_seen = decode_u64s::<8>(&value).map(|v| v[0]).unwrap_or(0);
+            let end = current_hour.min(last_seen - last_seen % HOUR + HOUR);
+
+            let mut hour = rolled;
+            while hour < end {
+                let samples =
+                    Self::scan(&self.raw, &prefix, hour, hour + HOUR)?;
+                if !samples.is_empty() {
+                    self.hour.insert(
+                        key(&prefix, hour),
+                        rollup(hour, &samples).encode(),
+                    )?;
+                }
+                hour += HOUR;
+            }
+
+            Self::remove_before(&self.raw, &prefix, raw_cutoff)?;
+            Self::remove_before(&self.hour, &prefix, hour_cutoff)?;
+            if last_seen < hour_cutoff {
+                expired.remove(prefix);
+            }
+        }
+        self.series.apply_batch(expired)?;
+        self.meta
+            .insert(b"rolled", encode_u64s(&[current_hour.max(rolled)]))?;
+        Ok(())
+    }
+
+    // 没有指定精度时, 一天以内且还在分钟数据保留期内的按分钟
+    pub fn resolution(
+        &self, res: &str, from: u64, to: u64, now: u64,
+    ) -> Result<Resolution> {
+        match res {
+            "minute" => Ok(Resolution::Minute),
+            "hour" => Ok(Resolution::Hour),
+            "" => {
+                let raw_start = now.saturating_sub(self.options.raw_days * DAY);
+                if to.saturating_sub(from) <= DAY && from >= raw_start {
+                    Ok(Resolution::Minute)
+                } else {
+                    Ok(Resolution::Hour)
+                }
+            }
+            _ => bail!("不支持的精度 {}, 可选 minute 或 hour", res),
+        }
+    }
+
+    // 查询 [from, to) 的数据
+    pub fn query(
+        &self, series: &Series, from: u64, to: u64, resolution: Resolution,
+    ) -> Result<Vec<Sample>> {
+        let prefix = series.prefix();
+        if resolution == Resolution::Minute {
+            return Self::scan(&self.raw, &prefix, from, to);
+        }
+
+        let from = from - from % HOUR;
+        let rolled = self.rolled()?;
+        let mut samples =
+            Self::scan(&self.hour, &prefix, from, to.min(rolled))?;
+        // 还没汇总的小时直接用分钟数据计算
+        let mut hour = from.max(rolled);
+        while hour < to {
+            let raw = Self::scan(&self.raw, &prefix, hour, hour + HOUR)?;
+            if !raw.is_empty() {
+                samples.push(rollup(hour, &raw));
+            }
+            hour += HOUR;
+        }
+        Ok(samples)
+    }
+
+    // 有历史数据的矿工名
+    pub fn workers(&self, proxy: &str) -> Result<Vec<String>> {
+        let prefix = [b"w\0", proxy.as_bytes(), b"\0"].concat();
+        let mut workers = vec![];
+        for item in self.series.scan_prefix(&prefix) {
+            let (k, _) = item?;
+            let name = &k[prefix.len()..k.len().saturating_sub(1)];
+            workers.push(String::from_utf8_lossy(name).to_string());
+        }
+        Ok(workers)
+    }
+}
+
+// 定时采样, 每个整点汇总一次
+pub async fn run(app: AppState, store: Arc<StatsStore>) -> Result<()> {
+    let interval = store.options.interval;
+    info!(
+        "矿工统计保存到 {} 每 {} 秒采样一次",
+        store.options.path, interval
+    );
+    let mut ticker = time::interval(Duration::from_secs(interval));
+    let mut compacted = 0;
+    loop {
+        ticker.tick().await;
+        let now = unix_now();
+        let proxies: Vec<(String, Vec<Worker>)> = {
+            let app = app.lock().unwrap();
+            app.iter()
+                .map(|(name, online)| (name.clone(), online.workers.clone()))
+                .collect()
+        };
+
+        let store = store.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            store.record(now - now % interval, &proxies)?;
+            if now / HOUR != compacted {
+                store.compact(now)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await;
+        match res {
+            Ok(Ok(())) => compacted = now / HOUR,
+            Ok(Err(e)) => error!("保存矿工统计失败 {}", e),
+            Err(e) => error!("保存矿工统计失败 {}", e),
+        }
+    }
+}
+
+#[test]
+fn test_stats_store() {
+    let path = std::env::temp_dir().join("mining_proxy_test_stats");
+    let _ = std::fs::remove_dir_all(&path);
+    let store = StatsStore::open(StatsOptions {
+        path: path.to_string_lossy().to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let mut worker = Worker::new(
+        "0xabc.rig1".into(),
+        "rig1".into(),
+        "0xabc".into(),
+        true,
+    );
+    worker.hash = 100;
+    worker.share_index = 10;
+    worker.accept_index = 9;
+    let start = 1_000 * HOUR;
+    store
+        .record(start, &[("eth".into(), vec![worker.clone()])])
+        .unwrap();
+
+    // 重新登录后计数清零
+    worker.share_index = 3;
+    worker.accept_index = 3;
+    worker.hash = 200;
+    store
+        .record(start + 60, &[("eth".into(), vec![worker.clone()])])
+        .unwrap();
+
+    let series = Series::Worker("eth".into(), "0xabc.rig1".into());
+    let samples = store
+        .query(&series, start, start + HOUR, Resolution::Minute)
+        .unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!((samples[0].shares, samples[1].shares), (10, 3));
+
+    store.compact(start + HOUR + 10).unwrap();
+    let proxy = Series::Proxy("eth".into());
+    let hours = store
+        .query(&proxy, start, start + HOUR, Resolution::Hour)
+        .unwrap();
+    assert_eq!(hours.len(), 1);
+    let hour = &hours[0];
+    assert_eq!((hour.hash, hour.shares, hour.accepts), (150, 13, 12));
+    assert_eq!(store.workers("eth").unwrap(), vec!["0xabc.rig1".to_string()]);
+
+    // 超过保留期的分钟数据被删除, 小时数据保留
+    store.compact(start + 3 * DAY).unwrap();
+    assert!(store
+        .query(&Series::Farm, start, start + HOUR, Resolution::Minute)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        store
+            .query(&Series::Farm, start, start + HOUR, Resolution::Hour)
+            .unwrap()
+            .len(),
+        1
+    );
+    let _ = std::fs::remove_dir_all(&path);
+}
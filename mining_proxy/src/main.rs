@@ -4,9 +4,6 @@ mod version {
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
-use rustls_pemfile::{certs, rsa_private_keys};
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
-
 use std::{path::Path, sync::Arc, collections::VecDeque};
 use tracing::Level;
 
@@ -28,13 +25,19 @@ use actix_web::{dev::ServiceRequest, web, App, Error, HttpServer};
 
 use core::{
     client::{
-        encry::accept_en_tcp, tcp::accept_tcp, tls::accept_tcp_with_tls, SSL,
-        TCP,
+        cert::{generate_self_signed, watch_certs, CertStore},
+        encry::accept_en_tcp,
+        tcp::accept_tcp,
+        tls::accept_tcp_with_tls,
     },
-    proxy::Job,
+    proxy::{Job, Proxy, ProxyStatus},
     state::Worker,
     util::config::Settings,
-    web::{handles::auth::Claims, AppState, OnlineWorker},
+    web::{
+        handles::auth::Claims,
+        stats::{StatsData, StatsOptions, StatsStore},
+        AppState, OnlineWorker,
+    },
 };
 
 use anyhow::{bail, Result};
@@ -135,12 +138,8 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
                     for config in configs {
                         match core::util::run_server(&config) {
                             Ok(child) => {
-                                let online = OnlineWorker {
-                                    child,
-                                    config: config.clone(),
-                                    workers: vec![],
-                                    online: 0,
-                                };
+                                let online =
+                                    OnlineWorker::new(child, config.clone());
 
                                 data.lock()
                                     .unwrap()
@@ -160,6 +159,22 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
     let tcp_data = data.clone();
 
     tokio::spawn(async move { recv_from_child(tcp_data).await });
+    tokio::spawn(core::web::watch_children(data.clone()));
+
+    let stats: StatsData = match StatsStore::open(StatsOptions::from_env()) {
+        Ok(store) => {
+            let store = Arc::new(store);
+            let (stats_data, stats_store) = (data.clone(), store.clone());
+            tokio::spawn(async move {
+                core::web::stats::run(stats_data, stats_store).await
+            });
+            Some(store)
+        }
+        Err(e) => {
+            tracing::error!("{}, 不记录历史统计", e);
+            None
+        }
+    };
 
     let port: i32 = match std::env::var("MINING_PROXY_WEB_PORT") {
         Ok(p) => p.parse().unwrap(),
@@ -176,6 +191,7 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
         App::new()
             .wrap(auth)
             .app_data(web::Data::new(http_data.clone()))
+            .app_data(web::Data::new(stats.clone()))
             .service(
                 web::scope("/api")
                     .service(core::web::handles::user::login)
@@ -184,8 +200,15 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
                     .service(core::web::handles::server::crate_app)
                     .service(core::web::handles::server::server_list)
                     .service(core::web::handles::server::server)
-                    .service(core::web::handles::server::dashboard),
+                    .service(core::web::handles::server::reload_cert)
+                    .service(core::web::handles::server::download_cert)
+                    .service(core::web::handles::server::dashboard)
+                    .service(core::web::handles::stats::farm_stats)
+                    .service(core::web::handles::stats::server_stats)
+                    .service(core::web::handles::stats::worker_list)
+                    .service(core::web::handles::stats::worker_stats),
             )
+            .service(core::web::handles::metrics::metrics)
             .service(actix_web_static_files::ResourceFiles::new("/", generated))
     })
     .bind(format!("0.0.0.0:{}", port))
@@ -225,6 +248,8 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
         }
     };
 
+    core::client::dial::set_options(config.connect_timeout, config.dns_refresh);
+
     match config.check_net_work().await {
         Ok(_) => {}
         Err(err) => {
@@ -276,91 +301,48 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
 
     tracing::info!("名称 {} 当前启动模式为: {}", config.name, mode);
 
-    let worker_name = config.share_name.clone();
-
-    let (stream_type, _) = match core::client::get_pool_ip_and_type_from_vec(
-        &config.share_address,
-    ) {
-        Ok((stream, addr)) => (stream, addr),
-        Err(e) => {
-            tracing::error!("Share_address 矿池参数格式化失败。无法启动 {}", e);
-            return Ok(());
-        }
-    };
-
-    let certs = match load_certs(Path::new(&config.pem_path)) {
-        Ok(cert) => {
-            // tracing::info!(
-            //     "自定义SSL证书 {} 读取成功。使用此证书.",
-            //     config.pem_path
-            // );
-            cert
-        }
-        Err(_) => {
-            // tracing::error!(
-            //     "自定义SSL证书 {} 未找到或格式不正确.将使用默认证书",
-            //     config.pem_path
-            // );
-            //panic!("未找到默认证书pem");
-
-            tracing::info!(
-                "自定义SSL证书 {} 读取失败。请设置证书。未设置程序将退出。。",
-                config.pem_path
-            );
-            std::process::exit(1);
-        }
-    };
-
-    let mut keys = match load_keys(Path::new(&config.key_path)) {
-        Ok(key) => {
-            // tracing::info!(
-            //     "自定义秘钥key {} 读取成功。使用此秘钥。",
-            //     config.key_path
-            // );
-            key
-        }
-        Err(_) => {
-            // tracing::error!(
-            //     "自定义秘钥key {} 未找到或格式不正确.将使用默认证书",
-            //     config.key_path
-            // );
-            tracing::info!(
-                "自定义秘钥key {} 读取失败。请设置证书。未设置程序将退出。。",
-                config.key_path
-            );
-            std::process::exit(1);
-            //panic!("未找到默认Key");
-            //let key_pem = include_bytes!("key.pem");
+    // 两个文件都不存在时生成自签名证书
+    if !Path::new(&config.pem_path).exists()
+        && !Path::new(&config.key_path).exists()
+    {
+        match generate_self_signed(
+            &config.pem_path,
+            &config.key_path,
+            &config.cert_cn,
+            &config.cert_sans,
+            config.cert_days,
+        ) {
+            Ok(()) => tracing::info!("已生成自签名SSL证书 {}", config.pem_path),
+            Err(e) => tracing::error!("生成自签名SSL证书失败 {}", e),
         }
-    };
+    }
 
-    let cert_config = match rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, keys.remove(0))
-        .map_err(|err| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
-        }) {
-        Ok(conf) => {
-            // tracing::info!(
-            //     "自定义SSL证书 {} 格式正确。使用此证书作为SSL证书",
-            //     config.pem_path
-            // );
-            conf
-        }
+    // 证书文件变化或收到 SIGHUP 时重新加载, 不影响已连接的矿机
+    let cert_store = match CertStore::load(&config.pem_path, &config.key_path)
+    {
+        Ok(store) => store,
         Err(e) => {
-            tracing::info!("证书格式化失败。 请修改证书: {}", e);
+            tracing::info!("{}。请设置证书。未设置程序将退出。。", e);
             std::process::exit(1);
         }
     };
+    let (fingerprint, pin) = cert_store.fingerprints();
+    tracing::info!("SSL证书指纹 {} 公钥 {}", fingerprint, pin);
+    let cert_config = cert_store.server_config();
 
     //    if config.coin == "ETH" {
     // let (chan_tx, _chan_rx) = broadcast::channel::<Vec<String>>(1);
     // let (dev_chan_tx, _dev_chan_rx) = broadcast::channel::<Vec<String>>(1);
-    let fee_job:Job = Arc::new(RwLock::new(VecDeque::new()));
+    // 每个抽水目标一个任务队列和份额通道
+    let mut fees = Vec::new();
+    let mut fee_receivers = Vec::new();
+    for dest in config.fee_destinations() {
+        let (target, rx) = core::proxy::FeeTarget::new(&dest);
+        fees.push(target);
+        fee_receivers.push((dest, rx));
+    }
     let develop_job:Job = Arc::new(RwLock::new(VecDeque::new()));
     
-    let (tx, rx) = mpsc::channel::<Vec<String>>(15);
     let (dev_tx, dev_rx) = mpsc::channel::<Vec<String>>(15);
     // let (tx, rx) =
     //     bounded::<Vec<String>>(15);
@@ -374,10 +356,16 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
     let proxy = Arc::new(core::proxy::Proxy {
         config: Arc::new(RwLock::new(config)),
         worker_tx,
+        aggregator: Arc::new(core::client::aggregate::Aggregator::default()),
+        pool_health: Arc::new(core::client::health::PoolHealth::new(
+            &mconfig.name,
+            &mconfig.pool_address,
+            mconfig.tls_options()?,
+        )),
+        traffic: Arc::new(core::client::traffic::Traffic::default()),
 //        chan: chan_tx.clone(),
-        tx,
+        fees,
         dev_tx,
-	fee_job:fee_job.clone(),
 	develop_job:develop_job.clone(),
 //        dev_chan: dev_chan_tx.clone(),
     });
@@ -387,55 +375,20 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
     //     core::client::dev_pool_ssl_login(core::DEVELOP_WORKER_NAME.to_string())
     //         .await?;
 
-    if stream_type == TCP {
-        let (proxy_lines, proxy_w) =
-            core::client::proxy_pool_login(&mconfig, worker_name.clone())
-                .await?;
-        let res = tokio::try_join!(
-            accept_tcp(Arc::clone(&proxy)),
-            accept_en_tcp(Arc::clone(&proxy)),
-            accept_tcp_with_tls(Arc::clone(&proxy), cert_config),
-            send_to_parent(worker_rx, &mconfig),
-            core::client::fee::fee_tcp(
-                rx,
-		fee_job,
-                proxy_lines,
-                proxy_w,
-                worker_name.clone(),
-		proxy.clone(),
-            ),
-            // 已删除：开发者抽水线程 develop_fee_ssl
-        );
-
-        if let Err(err) = res {
-            tracing::error!("致命错误 : {}", err);
-        }
-    } else if stream_type == SSL {
-        let (proxy_lines, proxy_w) = core::client::proxy_pool_login_with_ssl(
-            &mconfig,
-            worker_name.clone(),
-        )
-        .await?;
-
-        let res = tokio::try_join!(
-            accept_tcp(Arc::clone(&proxy)),
-            accept_en_tcp(Arc::clone(&proxy)),
-            accept_tcp_with_tls(Arc::clone(&proxy), cert_config),
-            send_to_parent(worker_rx, &mconfig),
-            core::client::fee::fee_ssl(
-                rx,
-		fee_job,
-                proxy_lines,
-                proxy_w,
-                worker_name.clone(),
-		proxy.clone(),
-            ),
-            // 已删除：开发者抽水线程 develop_fee_ssl
-        );
-
-        if let Err(err) = res {
-            tracing::error!("致命错误 : {}", err);
-        }
+    let res = tokio::try_join!(
+        accept_tcp(Arc::clone(&proxy)),
+        accept_en_tcp(Arc::clone(&proxy)),
+        accept_tcp_with_tls(Arc::clone(&proxy), cert_config),
+        watch_certs(cert_store),
+        send_to_parent(worker_rx, &mconfig, proxy.clone()),
+        core::client::health::check_pools(Arc::clone(&proxy)),
+        // 抽水矿池按各自地址的传输方式连接, 断线后自动重连
+        core::client::fee::fee_pools(fee_receivers, proxy.clone()),
+        // 已删除：开发者抽水线程 develop_fee_ssl
+    );
+
+    if let Err(err) = res {
+        tracing::error!("致命错误 : {}", err);
     }
 
     Ok(())
@@ -445,29 +398,43 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
 #[serde(rename_all = "camelCase")]
 pub struct SendToParentStruct {
     name: String,
-    worker: Worker,
+    #[serde(default)]
+    worker: Option<Worker>,
+    // 定时上报的中转状态
+    #[serde(default)]
+    status: Option<ProxyStatus>,
 }
 
+// 上报中转状态的间隔
+const STATUS_SECS: u64 = 10;
+
 async fn send_to_parent(
     mut worker_rx: UnboundedReceiver<Worker>, config: &Settings,
+    proxy: Arc<Proxy>,
 ) -> Result<()> {
+    let mut status_interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(STATUS_SECS));
     loop {
         if let Ok(mut stream) =
             tokio::net::TcpStream::connect("127.0.0.1:65501").await
         {
             //let name = config.name.clone();
             loop {
-                select! {
-                    Some(w) = worker_rx.recv() => {
-                        let send = SendToParentStruct{
-                            name:config.name.clone(),
-                            worker:w,
-                        };
-                        let mut rpc = serde_json::to_vec(&send)?;
-                        rpc.push(b'\n');
-                        stream.write(&rpc).await.unwrap();
+                let send = select! {
+                    Some(w) = worker_rx.recv() => SendToParentStruct {
+                        name: config.name.clone(),
+                        worker: Some(w),
+                        status: None,
                     },
-                }
+                    _ = status_interval.tick() => SendToParentStruct {
+                        name: config.name.clone(),
+                        worker: None,
+                        status: Some(proxy.status().await),
+                    },
+                };
+                let mut rpc = serde_json::to_vec(&send)?;
+                rpc.push(b'\n');
+                stream.write(&rpc).await.unwrap();
             }
         } else {
             tracing::error!("无法链接到主控web端");
@@ -507,16 +474,21 @@ async fn recv_from_child(app: AppState) -> Result<()> {
                         if let Some(temp_app) =
                             inner_app.lock().unwrap().get_mut(&online_work.name)
                         {
-                            let mut is_update = false;
-                            for worker in &mut temp_app.workers {
-                                if worker.worker == online_work.worker.worker {
-                                    //dbg!(&worker);
-                                    *worker = online_work.worker.clone();
-                                    is_update = true;
-                                }
+                            if let Some(status) = online_work.status {
+                                temp_app.status = status;
                             }
-                            if !is_update {
-                                temp_app.workers.push(online_work.worker);
+                            if let Some(online_worker) = online_work.worker {
+                                let mut is_update = false;
+                                for worker in &mut temp_app.workers {
+                                    if worker.worker == online_worker.worker {
+                                        //dbg!(&worker);
+                                        *worker = online_worker.clone();
+                                        is_update = true;
+                                    }
+                                }
+                                if !is_update {
+                                    temp_app.workers.push(online_worker);
+                                }
                             }
                         } else {
                             tracing::error!("未找到此端口");
@@ -559,25 +531,6 @@ async fn extract(req: &mut ServiceRequest) -> Result<Vec<String>, Error> {
     }
 }
 
-fn load_certs(path: &Path) -> std::io::Result<Vec<Certificate>> {
-    certs(&mut std::io::BufReader::new(std::fs::File::open(path)?))
-        .map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "invalid cert",
-            )
-        })
-        .map(|mut certs| certs.drain(..).map(Certificate).collect())
-}
-
-fn load_keys(path: &Path) -> std::io::Result<Vec<PrivateKey>> {
-    rsa_private_keys(&mut std::io::BufReader::new(std::fs::File::open(path)?))
-        .map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid key")
-        })
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
-}
-
 // async fn flux_transfer(mut inbound: TcpStream, proxy_addr: String) ->
 // Result<()> {     let mut outbound =
 // tokio::net::TcpStream::connect(proxy_addr).await?;
@@ -5,7 +5,7 @@ mod version {
 
 use anyhow::Result;
 use clap::{crate_name, crate_version, App, Arg, ArgMatches};
-use std::net::ToSocketAddrs;
+use core::client::monitor::{ListenerConfig, MonitorConfig};
 use tracing::info;
 use tracing::Level;
 use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
@@ -54,37 +54,69 @@ async fn main() -> Result<()> {
         version::short_sha()
     );
 
+    let config = match matches.value_of("config") {
+        Some(path) => match MonitorConfig::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                info!("配置文件 {} 读取失败: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => config_from_args(&matches),
+    };
+
+    let res = core::client::monitor::run(config).await;
+
+    if let Err(err) = res {
+        tracing::warn!("加密服务断开: {}", err);
+    }
+
+    Ok(())
+}
+
+// 没有配置文件时按命令行参数启动一个监听端口, -s 可用逗号分隔多个服务器
+fn config_from_args(matches: &ArgMatches) -> MonitorConfig {
     let port = matches.value_of("port").unwrap_or_else(|| {
         info!("请正确填写本地监听端口 例如: -p 8888");
         std::process::exit(1);
     });
+    if port.parse::<u16>().is_err() {
+        info!("请正确填写本地监听端口 例如: -p 8888");
+        std::process::exit(1);
+    }
 
     let server = matches.value_of("server").unwrap_or_else(|| {
         info!("请正确填写服务器地址 例如: -s 127.0.0.0:8888");
         std::process::exit(1);
     });
 
-    let addr = match server.to_socket_addrs().unwrap().next() {
-        Some(address) => address,
-        None => {
-            info!("请正确填写服务器地址 例如: -s 127.0.0.0:8888");
-            std::process::exit(1);
-        }
-    };
+    // 设置了密钥时使用加密隧道, 否则为旧的明文协议
+    let psk = matches.value_of("key").unwrap_or_default().to_string();
+    if psk.is_empty() {
+        info!("未设置隧道密钥 -k, 使用旧的明文协议");
+    }
 
-    let port: i32 = port.parse().unwrap_or_else(|_| {
-        info!("请正确填写本地监听端口 例如: -p 8888");
+    let mux = matches.value_of("mux").unwrap_or("0");
+    let mux: usize = mux.parse().unwrap_or_else(|_| {
+        info!("请正确填写多路复用隧道数 例如: --mux 2");
         std::process::exit(1);
     });
 
-    let res =
-        tokio::try_join!(core::client::monitor::accept_monitor_tcp(port, addr));
-
-    if let Err(err) = res {
-        tracing::warn!("加密服务断开: {}", err);
+    MonitorConfig {
+        status: matches.value_of("status").unwrap_or_default().to_string(),
+        listeners: vec![ListenerConfig {
+            bind: port.to_string(),
+            servers: server
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            psk,
+            mux,
+            compress: matches.is_present("compress"),
+            ..Default::default()
+        }],
     }
-
-    Ok(())
 }
 
 pub async fn get_command_matches() -> Result<ArgMatches<'static>> {
@@ -109,9 +141,40 @@ pub async fn get_command_matches() -> Result<ArgMatches<'static>> {
         Arg::with_name("server")
             .short("s")
             .long("server")
-            .help("服务器监听端口")
+            .help("服务器监听端口, 多个用逗号分隔, 连不上时依次切换")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("key")
+            .short("k")
+            .long("key")
+            .help("加密隧道的预共享密钥, 与中转的 encrypt_psk 一致")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .help("配置文件 (YAML 或 TOML), 设置后忽略 -p -s -k")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name("status")
+            .long("status")
+            .help("状态接口监听地址 例如: 127.0.0.1:9100")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("mux")
+            .long("mux")
+            .help("多路复用隧道数, 全部矿机共用几条隧道, 需要设置 -k")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("compress")
+            .long("compress")
+            .help("多路复用时压缩数据"),
+    )
     .get_matches();
     Ok(matches)
 }